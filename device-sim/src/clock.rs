@@ -0,0 +1,20 @@
+//! The tokio clock, for the code shared with the firmware.
+
+use rpc_definition::clock::Clock;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// The tokio clock in place of `Systick`.
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    type Instant = Instant;
+
+    fn after(instant: Instant, micros: u64) -> Instant {
+        instant + Duration::from_micros(micros)
+    }
+
+    fn micros_between(earlier: Instant, later: Instant) -> u64 {
+        later.saturating_duration_since(earlier).as_micros() as u64
+    }
+}
@@ -0,0 +1,288 @@
+use crate::{
+    deferred::{DeferredCommand, DeferredCounters, DeferredResponse, COMMANDS_FULL_RETRY_MS},
+    ethernet::{Keepalive, MAX_MESSAGE_SIZE},
+    firmware_update::Updater,
+    stream::StreamCommand,
+    DeviceConfig,
+};
+use log::*;
+use postcard::experimental::schema::Schema;
+use rpc_definition::{
+    cancel::{Cancel, TopicCancel},
+    endpoints::{
+        deferred::DeferredStatsEndpoint,
+        device_info::DeviceInfoEndpoint,
+        firmware_update::{
+            FinishUpdateEndpoint, FirmwareVersionEndpoint, StartUpdate, StartUpdateEndpoint,
+            WriteChunk, WriteChunkEndpoint,
+        },
+        pingpong::{PingPongEndpoint, Pong},
+        sleep::{Sleep, SleepDone, SleepEndpoint},
+        stream::{
+            SetStreamRate, SetStreamRateEndpoint, StartStream, StartStreamEndpoint, StopStream,
+            StopStreamEndpoint,
+        },
+    },
+    postcard_rpc::{self, headered::extract_header_from_bytes, Endpoint, Key, Topic},
+    topics::{
+        close::TopicClose,
+        heartbeat::{HeartbeatAck, TopicHeartbeatAck},
+        time::{Time, TopicTime},
+    },
+    wire_error::{FatalError, ERROR_KEY},
+};
+use serde::Serialize;
+use std::time::Duration;
+use tokio::{sync::mpsc::Sender, time::Instant};
+
+/// Main command dispatch helper, this is called on all incoming packets.
+#[allow(clippy::too_many_arguments)]
+pub async fn dispatch(
+    name: &str,
+    buf: &[u8],
+    ethernet_tx: &Sender<Vec<u8>>,
+    deferred_command_sender: &Sender<DeferredCommand>,
+    deferred: &DeferredCounters,
+    stream_command_sender: &Sender<(u32, StreamCommand)>,
+    updater: &Updater,
+    keepalive: &Keepalive,
+    config: &DeviceConfig,
+    started: Instant,
+) {
+    let Ok((hdr, body)) = extract_header_from_bytes(buf) else {
+        error!("{name}: Failed to do dispatch: malformed header");
+        return;
+    };
+
+    match hdr.key {
+        SleepEndpoint::REQ_KEY => {
+            let Ok(sleeping_req) = postcard::from_bytes::<Sleep>(body) else {
+                error!("{name}: Failed to do dispatch: malformed Sleep");
+                return;
+            };
+
+            trace!("{name}: Got Sleep request {sleeping_req:?}");
+            let due = Instant::now()
+                + Duration::from_secs(sleeping_req.seconds as u64)
+                + Duration::from_micros(sleeping_req.micros as u64);
+            let Ok(frame) = postcard_rpc::headered::to_stdvec_keyed(
+                hdr.seq_no,
+                SleepEndpoint::RESP_KEY,
+                &SleepDone {
+                    slept_for: sleeping_req,
+                },
+            ) else {
+                return;
+            };
+
+            let done = DeferredResponse {
+                seq_no: hdr.seq_no,
+                due,
+                frame,
+            };
+            if deferred_command_sender
+                .try_send(DeferredCommand::Schedule(done))
+                .is_err()
+            {
+                // If all queues are full, tell the backend to retry in a moment.
+                response(
+                    hdr.seq_no,
+                    ERROR_KEY,
+                    &FatalError::Busy(COMMANDS_FULL_RETRY_MS),
+                    ethernet_tx,
+                )
+                .await;
+            }
+        }
+        DeferredStatsEndpoint::REQ_KEY => {
+            trace!("{name}: Got DeferredStats request");
+            response(
+                hdr.seq_no,
+                DeferredStatsEndpoint::RESP_KEY,
+                &deferred.stats(),
+                ethernet_tx,
+            )
+            .await;
+        }
+        PingPongEndpoint::REQ_KEY => {
+            trace!("{name}: Got Ping request");
+            response(
+                hdr.seq_no,
+                PingPongEndpoint::RESP_KEY,
+                &Pong {},
+                ethernet_tx,
+            )
+            .await;
+        }
+        FirmwareVersionEndpoint::REQ_KEY => {
+            trace!("{name}: Got FirmwareVersion request");
+            response(
+                hdr.seq_no,
+                FirmwareVersionEndpoint::RESP_KEY,
+                &config.firmware_version,
+                ethernet_tx,
+            )
+            .await;
+        }
+        StartUpdateEndpoint::REQ_KEY => {
+            let Ok(start_req) = postcard::from_bytes::<StartUpdate>(body) else {
+                error!("{name}: Failed to do dispatch: malformed StartUpdate");
+                return;
+            };
+
+            trace!("{name}: Got StartUpdate request {start_req:?}");
+            let update = updater.start(start_req);
+            response(
+                hdr.seq_no,
+                StartUpdateEndpoint::RESP_KEY,
+                &update,
+                ethernet_tx,
+            )
+            .await;
+        }
+        WriteChunkEndpoint::REQ_KEY => {
+            let Ok(chunk_req) = postcard::from_bytes::<WriteChunk>(body) else {
+                error!("{name}: Failed to do dispatch: malformed WriteChunk");
+                return;
+            };
+
+            trace!("{name}: Got WriteChunk request at {}", chunk_req.offset);
+            let update = updater.write_chunk(chunk_req);
+            response(
+                hdr.seq_no,
+                WriteChunkEndpoint::RESP_KEY,
+                &update,
+                ethernet_tx,
+            )
+            .await;
+        }
+        FinishUpdateEndpoint::REQ_KEY => {
+            trace!("{name}: Got FinishUpdate request");
+            let update = updater.finish();
+            response(
+                hdr.seq_no,
+                FinishUpdateEndpoint::RESP_KEY,
+                &update,
+                ethernet_tx,
+            )
+            .await;
+        }
+        DeviceInfoEndpoint::REQ_KEY => {
+            trace!("{name}: Got DeviceInfo request");
+            response(
+                hdr.seq_no,
+                DeviceInfoEndpoint::RESP_KEY,
+                &config.info(started),
+                ethernet_tx,
+            )
+            .await;
+        }
+        StartStreamEndpoint::REQ_KEY
+        | StopStreamEndpoint::REQ_KEY
+        | SetStreamRateEndpoint::REQ_KEY => {
+            let command = match hdr.key {
+                StartStreamEndpoint::REQ_KEY => postcard::from_bytes::<StartStream>(body)
+                    .map(|req| StreamCommand::Start(req.topic)),
+                StopStreamEndpoint::REQ_KEY => postcard::from_bytes::<StopStream>(body)
+                    .map(|req| StreamCommand::Stop(req.topic)),
+                _ => postcard::from_bytes::<SetStreamRate>(body)
+                    .map(|req| StreamCommand::SetRate(req.topic, req.hz)),
+            };
+            let Ok(command) = command else {
+                error!("{name}: Failed to do dispatch: malformed stream request");
+                return;
+            };
+
+            trace!("{name}: Got stream request");
+            if stream_command_sender
+                .try_send((hdr.seq_no, command))
+                .is_err()
+            {
+                response(
+                    hdr.seq_no,
+                    ERROR_KEY,
+                    &FatalError::NotEnoughSenders,
+                    ethernet_tx,
+                )
+                .await;
+            }
+        }
+        TopicHeartbeatAck::TOPIC_KEY => {
+            let Ok(ack) = postcard::from_bytes::<HeartbeatAck>(body) else {
+                error!("{name}: Failed to do dispatch: malformed HeartbeatAck");
+                return;
+            };
+
+            trace!("{name}: Heartbeat {} acknowledged", ack.sequence_number);
+            keepalive.acked();
+        }
+        TopicClose::TOPIC_KEY => {
+            debug!("{name}: The backend is closing the session");
+            keepalive.close();
+        }
+        TopicCancel::TOPIC_KEY => {
+            let Ok(cancel) = postcard::from_bytes::<Cancel>(body) else {
+                error!("{name}: Failed to do dispatch: malformed Cancel");
+                return;
+            };
+
+            trace!("{name}: Got Cancel for {}", cancel.seq_no);
+            // Only deferred responses wait long enough to be worth cancelling.
+            if deferred_command_sender
+                .try_send(DeferredCommand::Cancel(cancel.seq_no))
+                .is_err()
+            {
+                warn!(
+                    "{name}: Too many cancellations, {} runs to the end",
+                    cancel.seq_no
+                );
+            }
+        }
+        TopicTime::TOPIC_KEY => {
+            let Ok(time) = postcard::from_bytes::<Time>(body) else {
+                error!("{name}: Failed to do dispatch: malformed Time");
+                return;
+            };
+
+            // The host clock is the same, there is nothing to set.
+            debug!(
+                "{name}: Backend time is {} ms since the epoch",
+                time.unix_time_ms
+            );
+        }
+        _ => {
+            error!(
+                "{name}: Got unhandled endpoint/topic with key = {:x?}",
+                hdr.key
+            );
+            response(
+                hdr.seq_no,
+                ERROR_KEY,
+                &FatalError::UnknownEndpoint,
+                ethernet_tx,
+            )
+            .await;
+        }
+    }
+}
+
+/// Helper to generate a response, or `FatalError::MessageTooLarge` if it doesn't fit.
+async fn response<T: Serialize + Schema>(
+    seq_no: u32,
+    key: Key,
+    response: &T,
+    ethernet_tx: &Sender<Vec<u8>>,
+) {
+    let packet = match postcard_rpc::headered::to_stdvec_keyed(seq_no, key, response) {
+        Ok(packet) if packet.len() <= MAX_MESSAGE_SIZE => Ok(packet),
+        Ok(_) => {
+            error!("The response to {seq_no} is too large");
+            postcard_rpc::headered::to_stdvec_keyed(seq_no, ERROR_KEY, &FatalError::MessageTooLarge)
+        }
+        Err(e) => Err(e),
+    };
+
+    if let Ok(packet) = packet {
+        ethernet_tx.send(packet).await.ok();
+    }
+}
@@ -0,0 +1,83 @@
+//! Deferred responses, with the queue of the firmware and the tokio clock in place of `Systick`.
+
+use crate::clock::TokioClock;
+use log::*;
+use rpc_definition::{
+    endpoints::deferred::{self, Deferred},
+    postcard_rpc,
+    wire_error::{FatalError, ERROR_KEY},
+};
+use std::convert::Infallible;
+use tokio::{
+    sync::mpsc::{Receiver, Sender},
+    time::{timeout_at, Instant},
+};
+
+pub use deferred::{DeferredCounters, COMMANDS_FULL_RETRY_MS, DEFERRED_CAPACITY};
+
+/// A serialized response, sent once it is due.
+pub type DeferredResponse = deferred::DeferredResponse<TokioClock, Vec<u8>>;
+
+/// Requests to `handle_deferred`, forwarded from the dispatcher.
+pub type DeferredCommand = deferred::DeferredCommand<TokioClock, Vec<u8>>;
+
+/// Task sending the deferred responses once they are due. The counters are kept per device, the
+/// firmware keeps them in a static.
+pub async fn handle_deferred(
+    mut deferred_command_receiver: Receiver<DeferredCommand>,
+    ethernet_tx: Sender<Vec<u8>>,
+    counters: &DeferredCounters,
+) -> anyhow::Result<Infallible> {
+    let mut deferred = Deferred::<TokioClock, Vec<u8>, DEFERRED_CAPACITY>::new();
+
+    loop {
+        // Check if the time has come to send a response.
+        if let Some(response) = deferred.pop_due(Instant::now()) {
+            debug!("Deferred response to {} is due", response.seq_no);
+            ethernet_tx.send(response.frame).await?;
+            counters.completed();
+            counters.queued(deferred.len());
+
+            continue;
+        }
+
+        // Always keep taking commands, a full queue refuses new responses instead of leaving them
+        // in the channel.
+        let command = match deferred.next_due() {
+            Some(next) => match timeout_at(next, deferred_command_receiver.recv()).await {
+                Ok(command) => command,
+                Err(_timeout) => continue,
+            },
+            None => deferred_command_receiver.recv().await,
+        };
+
+        let Some(command) = command else {
+            anyhow::bail!("The deferred command channel closed");
+        };
+
+        match command {
+            DeferredCommand::Schedule(response) => {
+                let seq_no = response.seq_no;
+                if let Err(retry_after_ms) = deferred.schedule(response, Instant::now()) {
+                    warn!("No room to defer {seq_no}, retry in {retry_after_ms} ms");
+                    counters.rejected();
+
+                    if let Ok(packet) = postcard_rpc::headered::to_stdvec_keyed(
+                        seq_no,
+                        ERROR_KEY,
+                        &FatalError::Busy(retry_after_ms),
+                    ) {
+                        ethernet_tx.send(packet).await?;
+                    }
+                }
+            }
+            DeferredCommand::Cancel(seq_no) => {
+                if deferred.cancel(seq_no) {
+                    debug!("Deferred response to {seq_no} cancelled");
+                    counters.cancelled();
+                }
+            }
+        }
+        counters.queued(deferred.len());
+    }
+}
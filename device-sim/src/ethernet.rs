@@ -0,0 +1,326 @@
+use crate::{
+    command_handling,
+    deferred::{self, DeferredCounters},
+    firmware_update::Updater,
+    send_heartbeat, stream, DeviceConfig,
+};
+use embedded_dtls::{
+    cipher_suites::{ChaCha20Poly1305Cipher, DtlsEcdhePskWithChacha20Poly1305Sha256},
+    client::{
+        config::{ClientConfig, Psk},
+        open_client,
+    },
+    queue_helpers::framed_queue,
+    ApplicationDataReceiver, ApplicationDataSender,
+};
+use log::*;
+pub use rpc_definition::connection::MAX_MESSAGE_SIZE;
+use rpc_definition::{
+    connection::{Backoff, MISSED_ACKS},
+    fragment::{Fragmenter, Reassembler, ReassemblyError, MAX_RECORD_SIZE},
+    postcard_rpc,
+    wire_error::{FatalError, ERROR_KEY},
+};
+use std::{
+    convert::Infallible,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc::channel, Notify},
+    time::{sleep, sleep_until, timeout, Instant},
+};
+
+/// Depth of the queues between the DTLS connection and the command handling.
+const QUEUE_DEPTH: usize = 8;
+
+/// Main UDP RX/TX data pump. Also sets up the UDP socket.
+///
+/// The socket and the command handling outlive the DTLS connection, which is reopened whenever it
+/// drops.
+pub async fn run_comms(config: DeviceConfig) -> anyhow::Result<Infallible> {
+    let name = config.name();
+    let started = Instant::now();
+
+    let bind: SocketAddr = match (config.bind, config.backend) {
+        (Some(bind), _) => bind,
+        (None, SocketAddr::V4(_)) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        (None, SocketAddr::V6(_)) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = Arc::new(UdpSocket::bind(bind).await?);
+    socket.connect(config.backend).await?;
+
+    info!("{name}: Connecting from {}", socket.local_addr()?);
+
+    let (mut rx_sender, mut rx_receiver) = framed_queue(QUEUE_DEPTH);
+    let (mut tx_sender, mut tx_receiver) = framed_queue(QUEUE_DEPTH);
+
+    let (ethernet_tx_sender, mut ethernet_tx_receiver) = channel::<Vec<u8>>(1);
+    let (deferred_command_sender, deferred_command_receiver) = channel(deferred::DEFERRED_CAPACITY);
+    let (stream_command_sender, stream_command_receiver) = channel(2);
+
+    let client_config = ClientConfig {
+        psk: Psk {
+            identity: &config.identity,
+            key: &config.key,
+        },
+    };
+
+    let mut buf = vec![0; 1024];
+    let keepalive = Keepalive::new(config.heartbeat_interval * MISSED_ACKS);
+    // Reported in the heartbeats.
+    let dtls_errors = AtomicU32::new(0);
+    let deferred = DeferredCounters::new();
+    let updater = Updater::new(config.update_slot.clone());
+
+    tokio::select! {
+        _ = async {
+            let mut backoff = Backoff::new();
+
+            loop {
+                let rx = DtlsSocket(socket.clone());
+                let tx = DtlsSocket(socket.clone());
+                let cipher = ChaCha20Poly1305Cipher::default();
+                let client_connection = match timeout(
+                    Duration::from_secs(5),
+                    open_client::<_, _, _, DtlsEcdhePskWithChacha20Poly1305Sha256>(
+                        &mut rand::rngs::OsRng,
+                        &mut buf,
+                        rx,
+                        tx,
+                        cipher,
+                        &client_config,
+                    ),
+                )
+                .await
+                {
+                    Ok(Ok(c)) => c,
+                    Ok(Err(e)) => {
+                        error!("{name}: Failed to open a DTLS client connection: {e:?}");
+                        dtls_errors.fetch_add(1, Ordering::Relaxed);
+                        back_off(&mut backoff).await;
+                        continue;
+                    }
+                    Err(_timeout) => {
+                        error!("{name}: Attempt to open a DTLS connection timed out");
+                        dtls_errors.fetch_add(1, Ordering::Relaxed);
+                        back_off(&mut backoff).await;
+                        continue;
+                    }
+                };
+
+                debug!("{name}: Connected");
+                backoff.reset();
+                keepalive.opened();
+
+                let mut rx_buf = vec![0; 1536];
+                let mut tx_buf = vec![0; 1536];
+                let mut delay = Delay;
+
+                tokio::select! {
+                    result = client_connection.run(
+                        &mut rx_buf,
+                        &mut tx_buf,
+                        &mut rx_sender,
+                        &mut tx_receiver,
+                        &mut delay,
+                    ) => {
+                        let Err(e) = result;
+                        error!("{name}: Client connection closed with {e:?}");
+                        dtls_errors.fetch_add(1, Ordering::Relaxed);
+                    }
+                    _ = keepalive.expired() => {
+                        warn!("{name}: The backend stopped acknowledging heartbeats, reconnecting");
+                    }
+                    _ = keepalive.closed() => {
+                        info!("{name}: The backend closed the session, reconnecting");
+                        back_off(&mut backoff).await;
+                    }
+                }
+            }
+        } => unreachable!(),
+        e = async {
+            // Messages larger than a record are sent in fragments.
+            let mut buf = [0; MAX_RECORD_SIZE];
+            while let Some(packet) = ethernet_tx_receiver.recv().await {
+                let mut fragmenter = Fragmenter::new(&packet);
+                while let Some(record) = fragmenter.next_record(&mut buf) {
+                    if let Err(e) = tx_sender.send(record).await {
+                        error!("{name}: Could not fit data in the tx_sender: {e:?}");
+                    }
+                }
+            }
+
+            anyhow::anyhow!("{name}: All ethernet TX senders closed")
+        } => Err(e),
+        e = async {
+            // Receive worker, fragmented messages are put back together before the dispatch.
+            let mut storage = [0; MAX_MESSAGE_SIZE];
+            let mut reassembler = Reassembler::new(&mut storage);
+            loop {
+                {
+                    let Ok(packet) = rx_receiver.peek().await else {
+                        return anyhow::anyhow!("{name}: The RX queue closed");
+                    };
+
+                    match reassembler.push(packet.as_ref()) {
+                        Ok(Some(frame)) => {
+                            command_handling::dispatch(
+                                &name,
+                                frame,
+                                &ethernet_tx_sender,
+                                &deferred_command_sender,
+                                &deferred,
+                                &stream_command_sender,
+                                &updater,
+                                &keepalive,
+                                &config,
+                                started,
+                            )
+                            .await;
+                        }
+                        Ok(None) => {}
+                        Err(ReassemblyError::TooLarge { seq_no }) => {
+                            error!("{name}: Message {seq_no} is too large, it is dropped");
+                            if let Ok(packet) = postcard_rpc::headered::to_stdvec_keyed(
+                                seq_no,
+                                ERROR_KEY,
+                                &FatalError::MessageTooLarge,
+                            ) {
+                                ethernet_tx_sender.send(packet).await.ok();
+                            }
+                        }
+                        Err(ReassemblyError::Missing { seq_no }) => {
+                            warn!("{name}: Message {seq_no} lost a fragment, it is dropped");
+                            if let Ok(packet) = postcard_rpc::headered::to_stdvec_keyed(
+                                seq_no,
+                                ERROR_KEY,
+                                &FatalError::WireFailure,
+                            ) {
+                                ethernet_tx_sender.send(packet).await.ok();
+                            }
+                        }
+                        Err(e) => warn!("{name}: Dropped a received record: {e:?}"),
+                    }
+                }
+                rx_receiver.pop().ok();
+            }
+        } => Err(e),
+        e = deferred::handle_deferred(
+            deferred_command_receiver,
+            ethernet_tx_sender.clone(),
+            &deferred,
+        ) => e,
+        e = stream::stream_topics(stream_command_receiver, ethernet_tx_sender.clone()) => e,
+        e = send_heartbeat::send_heartbeat(
+            config.heartbeat_interval,
+            started,
+            ethernet_tx_sender.clone(),
+            &deferred,
+            &dtls_errors,
+        ) => e,
+    }
+}
+
+/// Application level keepalive. A backend that restarted has lost the session
+/// and drops everything the device sends, without the DTLS connection noticing.
+pub struct Keepalive {
+    timeout: Duration,
+    last_ack: Mutex<Instant>,
+    closed: AtomicBool,
+    close: Notify,
+}
+
+impl Keepalive {
+    fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            last_ack: Mutex::new(Instant::now()),
+            closed: AtomicBool::new(false),
+            close: Notify::new(),
+        }
+    }
+
+    /// A new session started.
+    fn opened(&self) {
+        self.closed.store(false, Ordering::Relaxed);
+        self.acked();
+    }
+
+    /// The backend closed the session.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.close.notify_waiters();
+    }
+
+    /// Resolves when the backend closed the session.
+    async fn closed(&self) {
+        loop {
+            let notified = self.close.notified();
+            if self.closed.load(Ordering::Relaxed) {
+                return;
+            }
+
+            notified.await;
+        }
+    }
+
+    /// The backend acknowledged a heartbeat.
+    pub fn acked(&self) {
+        *self.last_ack.lock().unwrap() = Instant::now();
+    }
+
+    /// Resolves when no heartbeat has been acknowledged for the timeout.
+    async fn expired(&self) {
+        loop {
+            let deadline = *self.last_ack.lock().unwrap() + self.timeout;
+            if Instant::now() >= deadline {
+                return;
+            }
+
+            sleep_until(deadline).await;
+        }
+    }
+}
+
+/// Wait out the backoff before the next connection attempt.
+async fn back_off(backoff: &mut Backoff) {
+    let ms = backoff.next_ms(rand::random());
+    debug!("Reconnecting in {ms} ms");
+    sleep(Duration::from_millis(ms.into())).await;
+}
+
+/// A UDP socket connected to the backend, as a DTLS endpoint.
+struct DtlsSocket(Arc<UdpSocket>);
+
+impl embedded_dtls::RxEndpoint for DtlsSocket {
+    type ReceiveError = std::io::Error;
+
+    async fn recv<'a>(&mut self, buf: &'a mut [u8]) -> Result<&'a mut [u8], Self::ReceiveError> {
+        // The socket is connected, datagrams from anyone but the backend are filtered out.
+        let n = self.0.recv(buf).await?;
+        Ok(&mut buf[..n])
+    }
+}
+
+impl embedded_dtls::TxEndpoint for DtlsSocket {
+    type SendError = std::io::Error;
+
+    async fn send(&mut self, buf: &[u8]) -> Result<(), Self::SendError> {
+        self.0.send(buf).await?;
+        Ok(())
+    }
+}
+
+struct Delay;
+
+impl embedded_dtls::DelayNs for Delay {
+    async fn delay_ns(&mut self, ns: u32) {
+        tokio::time::sleep(Duration::from_nanos(ns as _)).await;
+    }
+}
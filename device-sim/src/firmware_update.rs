@@ -0,0 +1,143 @@
+//! The firmware update, into an update slot in memory instead of flash.
+//!
+//! As in the firmware there is no bootloader, a verified image is staged in the slot and the
+//! device keeps running its firmware.
+
+use log::*;
+use rpc_definition::{
+    crc::crc32,
+    endpoints::firmware_update::{
+        StartUpdate, UpdateError, UpdateProgress, UpdateResponse, WriteChunk,
+    },
+};
+use std::sync::{Arc, Mutex};
+
+/// Largest image the update slot of the firmware holds, next to its marker.
+const MAX_IMAGE_SIZE: u32 = 384 * 1024 - 16;
+
+/// The update slot of a simulated board.
+///
+/// Clones share the slot, so a device spawned again from a clone of its `DeviceConfig` finds the
+/// image it staged before, like the firmware does in flash.
+#[derive(Clone, Debug, Default)]
+pub struct UpdateSlot(Arc<Mutex<Slot>>);
+
+#[derive(Debug, Default)]
+struct Slot {
+    data: Vec<u8>,
+    /// The verified image in `data`, the marker in the firmware.
+    staged: Option<StartUpdate>,
+    chunks_written: usize,
+}
+
+impl UpdateSlot {
+    /// The staged image and its data, if any.
+    pub fn staged(&self) -> Option<(StartUpdate, Vec<u8>)> {
+        let slot = self.0.lock().unwrap();
+        slot.staged.map(|image| (image, slot.data.clone()))
+    }
+
+    /// Number of chunks written to the slot, over all updates.
+    pub fn chunks_written(&self) -> usize {
+        self.0.lock().unwrap().chunks_written
+    }
+
+    fn is_staged(&self, image: &StartUpdate) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .staged
+            .is_some_and(|staged| staged.size == image.size && staged.crc == image.crc)
+    }
+}
+
+/// Executes the firmware update requests. The progress is lost when the device is powered off,
+/// the slot is not.
+pub struct Updater {
+    slot: UpdateSlot,
+    update: Mutex<Option<UpdateProgress>>,
+}
+
+impl Updater {
+    pub fn new(slot: UpdateSlot) -> Self {
+        Self {
+            slot,
+            update: Mutex::new(None),
+        }
+    }
+
+    /// Start a new update, or resume the current one if it's for the same image.
+    pub fn start(&self, image: StartUpdate) -> UpdateResponse {
+        if self.slot.is_staged(&image) {
+            info!("Firmware {:?} is already staged", image.version);
+            return UpdateResponse::Staged;
+        }
+
+        let mut update = self.update.lock().unwrap();
+        if let Some(current) = update.as_ref().filter(|current| current.resumes(&image)) {
+            info!("Resuming firmware update at {}", current.next_offset);
+            return UpdateResponse::Progress(current.next_offset);
+        }
+
+        if image.size > MAX_IMAGE_SIZE {
+            return UpdateResponse::Error(UpdateError::TooLarge);
+        }
+
+        info!("Starting firmware update to {:?}", image.version);
+        let mut slot = self.slot.0.lock().unwrap();
+        slot.data.clear();
+        slot.staged = None;
+        *update = Some(UpdateProgress::new(image));
+
+        UpdateResponse::Progress(0)
+    }
+
+    /// Write a chunk of the image, if it's the one we expect next.
+    pub fn write_chunk(&self, chunk: WriteChunk) -> UpdateResponse {
+        let mut update = self.update.lock().unwrap();
+        let Some(current) = update.as_mut() else {
+            return UpdateResponse::Error(UpdateError::NotStarted);
+        };
+
+        if let Err(response) = current.check(&chunk) {
+            return response;
+        }
+
+        let mut slot = self.slot.0.lock().unwrap();
+        slot.data.extend_from_slice(&chunk.data);
+        slot.chunks_written += 1;
+
+        current.written(chunk.data.len())
+    }
+
+    /// Verify the full image and mark it as staged.
+    pub fn finish(&self) -> UpdateResponse {
+        let mut update = self.update.lock().unwrap();
+        let Some(current) = update.as_ref() else {
+            return UpdateResponse::Error(UpdateError::NotStarted);
+        };
+
+        if self.slot.is_staged(&current.image) {
+            return UpdateResponse::Staged;
+        }
+
+        if !current.is_complete() {
+            return UpdateResponse::Progress(current.next_offset);
+        }
+
+        let mut slot = self.slot.0.lock().unwrap();
+        if crc32(&slot.data) != current.image.crc {
+            error!("Firmware update image CRC mismatch, starting over");
+            *update = None;
+            return UpdateResponse::Error(UpdateError::ImageCrc);
+        }
+
+        warn!(
+            "Firmware {:?} is staged, there is no bootloader to install it",
+            current.image.version
+        );
+        slot.staged = Some(current.image);
+
+        UpdateResponse::Staged
+    }
+}
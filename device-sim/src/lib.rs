@@ -0,0 +1,129 @@
+//! A host-runnable simulator of the device firmware.
+//!
+//! A simulated device connects to the ingress over UDP as a DTLS client, and speaks the same
+//! protocol as the firmware: `Sleep` with out-of-order completion and cancellation, the deferred
+//! response statistics, `PingPong`, the firmware version and update, the device information, the
+//! wire errors, the heartbeat topic, the streamed `SomeData` topic, the time published by the backend
+//! and fragmented messages. This makes it possible to run the ingress and its tests without an
+//! STM32F407 board.
+
+pub use firmware_update::UpdateSlot;
+use rpc_definition::{
+    endpoints::{
+        device_info::{DeviceInfo, ResetReason},
+        firmware_update::FirmwareVersion,
+    },
+    heapless, psk, PROTOCOL_VERSION,
+};
+use std::{convert::Infallible, net::SocketAddr, time::Duration};
+use tokio::{task::JoinHandle, time::Instant};
+
+mod clock;
+mod command_handling;
+mod deferred;
+mod ethernet;
+mod firmware_update;
+mod send_heartbeat;
+mod stream;
+
+/// Configuration of a simulated device.
+#[derive(Clone, Debug)]
+pub struct DeviceConfig {
+    /// Address of the ingress.
+    pub backend: SocketAddr,
+    /// Local address to send from, an ephemeral port by default. Reusing the address of a
+    /// device that was powered off simulates a reboot with the same address.
+    pub bind: Option<SocketAddr>,
+    /// The DTLS PSK identity of the device.
+    pub identity: Vec<u8>,
+    /// The pre-shared key of the device.
+    pub key: Vec<u8>,
+    /// Time between heartbeats.
+    pub heartbeat_interval: Duration,
+    /// The firmware version the device reports.
+    pub firmware_version: FirmwareVersion,
+    /// The MCU UID the device reports, the MAC address and hostname are derived from it.
+    pub uid: [u8; psk::UID_LEN],
+    /// Where firmware updates are staged, kept over power cycles.
+    pub update_slot: UpdateSlot,
+}
+
+impl DeviceConfig {
+    /// A device with the same heartbeat interval and version as the firmware.
+    pub fn new(
+        backend: impl Into<SocketAddr>,
+        identity: impl Into<Vec<u8>>,
+        key: impl Into<Vec<u8>>,
+    ) -> Self {
+        Self {
+            backend: backend.into(),
+            bind: None,
+            identity: identity.into(),
+            key: key.into(),
+            heartbeat_interval: Duration::from_secs(2),
+            firmware_version: FirmwareVersion {
+                major: 0,
+                minor: 1,
+                patch: 0,
+            },
+            uid: [0; psk::UID_LEN],
+            update_slot: UpdateSlot::default(),
+        }
+    }
+
+    /// Printable name of the device for logs.
+    fn name(&self) -> String {
+        String::from_utf8_lossy(&self.identity).into_owned()
+    }
+
+    /// The device information, for a device that started at `started`.
+    fn info(&self, started: Instant) -> DeviceInfo {
+        let uid = self.uid;
+        // A locally administered address, the firmware hashes the UID instead.
+        let mac = [0x02, uid[0], uid[1], uid[2], uid[3], uid[4]];
+        // The PSK identity, `rpc-<UID in hex>`.
+        let hostname = psk::identity(&uid);
+
+        DeviceInfo {
+            uid,
+            mac,
+            hostname: heapless::String::from(std::str::from_utf8(&hostname).unwrap()),
+            firmware_version: self.firmware_version,
+            git_hash: heapless::String::from("simulated"),
+            protocol_version: PROTOCOL_VERSION,
+            uptime_ms: started.elapsed().as_millis() as u64,
+            reset_reason: ResetReason::PowerOn,
+        }
+    }
+}
+
+/// Run a simulated device. It reconnects whenever the connection drops, so this only returns if
+/// the socket fails.
+pub async fn run(config: DeviceConfig) -> anyhow::Result<Infallible> {
+    ethernet::run_comms(config).await
+}
+
+/// Spawn a simulated device, abort the handle to power it off.
+pub fn spawn(config: DeviceConfig) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let name = config.name();
+
+        let Err(e) = run(config).await;
+        log::error!("{name}: Simulated device stopped: {e:?}");
+    })
+}
+
+/// Spawn `count` simulated devices with the identities `{prefix}-0`, `{prefix}-1`, ..., all
+/// using the same key.
+pub fn spawn_many(
+    backend: impl Into<SocketAddr>,
+    prefix: &str,
+    key: &[u8],
+    count: usize,
+) -> Vec<JoinHandle<()>> {
+    let backend = backend.into();
+
+    (0..count)
+        .map(|i| spawn(DeviceConfig::new(backend, format!("{prefix}-{i}"), key)))
+        .collect()
+}
@@ -0,0 +1,45 @@
+//! Run simulated devices against an ingress.
+//!
+//! ```text
+//! device-sim [backend address] [device count] [identity prefix] [key as hex]
+//! ```
+//!
+//! The devices are called `{prefix}-0`, `{prefix}-1`, ..., and must be listed in the ingress'
+//! credentials file with the same key.
+
+use std::net::SocketAddr;
+
+/// The development key, same as in `pc-app/psk.toml`.
+const DEFAULT_KEY: &str =
+    "313131313132333435363738393071776572747975696f706173646667686a6b6c7a7863";
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    pretty_env_logger::init();
+
+    let mut args = std::env::args().skip(1);
+    let backend: SocketAddr = args.next().as_deref().unwrap_or("127.0.0.1:8321").parse()?;
+    let count: usize = args.next().as_deref().unwrap_or("1").parse()?;
+    let prefix = args.next().unwrap_or_else(|| "sim".into());
+    let key = args.next().unwrap_or_else(|| DEFAULT_KEY.into());
+    let key = decode_hex(&key).ok_or_else(|| anyhow::anyhow!("the key is not valid hex"))?;
+
+    log::info!("Starting {count} simulated devices against {backend}");
+
+    for device in device_sim::spawn_many(backend, &prefix, &key, count) {
+        device.await?;
+    }
+
+    Ok(())
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
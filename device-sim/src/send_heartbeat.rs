@@ -0,0 +1,58 @@
+use crate::deferred::DeferredCounters;
+use log::*;
+use rpc_definition::{
+    postcard_rpc::{self, Topic},
+    topics::heartbeat::{Health, Heartbeat, HeartbeatWithHealth, TopicHeartbeat, HEALTH_VERSION},
+};
+use std::{
+    convert::Infallible,
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+use tokio::{sync::mpsc::Sender, time::Instant};
+
+/// Send a heartbeat with the health of the device every `interval`.
+///
+/// There is no Ethernet MAC, so its counters stay at zero and the link is always up.
+pub async fn send_heartbeat(
+    interval: Duration,
+    started: Instant,
+    ethernet_tx_sender: Sender<Vec<u8>>,
+    deferred: &DeferredCounters,
+    dtls_errors: &AtomicU32,
+) -> anyhow::Result<Infallible> {
+    let mut sequence_number = 0;
+
+    loop {
+        tokio::time::sleep(interval).await;
+
+        let deferred = deferred.stats();
+        let hb = HeartbeatWithHealth {
+            heartbeat: Heartbeat {
+                value: 1.,
+                sequence_number,
+            },
+            health: Health {
+                version: HEALTH_VERSION,
+                uptime_ms: started.elapsed().as_millis() as u64,
+                ethernet_tx_free: ethernet_tx_sender.capacity() as u8,
+                sleep_free: deferred.capacity.saturating_sub(deferred.queued) as u8,
+                eth_rx_frames: 0,
+                eth_tx_frames: 0,
+                eth_rx_errors: 0,
+                eth_rx_missed: 0,
+                net_dropped: 0,
+                dtls_errors: dtls_errors.load(Ordering::Relaxed),
+                link_up: true,
+            },
+        };
+        if let Ok(packet) =
+            postcard_rpc::headered::to_stdvec_keyed(sequence_number, TopicHeartbeat::TOPIC_KEY, &hb)
+        {
+            trace!("Sending heartbeat {sequence_number}");
+
+            ethernet_tx_sender.send(packet).await?;
+        }
+        sequence_number += 1;
+    }
+}
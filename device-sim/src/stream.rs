@@ -0,0 +1,57 @@
+//! Topics streamed to the backend on request, with the stream state of the firmware and the tokio
+//! clock in place of `Systick`.
+
+use crate::clock::TokioClock;
+use log::*;
+use rpc_definition::{
+    endpoints::stream::Stream,
+    postcard_rpc::{self, Topic},
+    topics::some_data::{SomeData, TopicSomeData},
+};
+use std::convert::Infallible;
+use tokio::{
+    sync::mpsc::{Receiver, Sender},
+    time::{timeout_at, Instant},
+};
+
+pub use rpc_definition::endpoints::stream::StreamCommand;
+
+/// Task publishing the streamed topics, at the rate and for as long as the backend asks for.
+pub async fn stream_topics(
+    mut stream_command_receiver: Receiver<(u32, StreamCommand)>,
+    ethernet_tx: Sender<Vec<u8>>,
+) -> anyhow::Result<Infallible> {
+    let mut some_data = Stream::<TokioClock>::new(Instant::now());
+    let mut counter: u64 = 0;
+
+    loop {
+        let received = match some_data.next_due() {
+            Some(next) => timeout_at(next, stream_command_receiver.recv()).await.ok(),
+            None => Some(stream_command_receiver.recv().await),
+        };
+
+        let Some(received) = received else {
+            if let Ok(packet) = postcard_rpc::headered::to_stdvec_keyed(
+                counter as u32,
+                TopicSomeData::TOPIC_KEY,
+                &SomeData { data: counter },
+            ) {
+                ethernet_tx.send(packet).await?;
+            }
+            counter += 1;
+            some_data.published(Instant::now());
+            continue;
+        };
+
+        let Some((seq_no, command)) = received else {
+            anyhow::bail!("The stream command channel closed");
+        };
+
+        let (key, response) = some_data.handle(command, Instant::now());
+        debug!("SomeData stream after {command:?}: {response:?}");
+
+        if let Ok(packet) = postcard_rpc::headered::to_stdvec_keyed(seq_no, key, &response) {
+            ethernet_tx.send(packet).await?;
+        }
+    }
+}
@@ -0,0 +1,29 @@
+use std::process::Command;
+
+fn main() {
+    // Embed the commit the firmware is built from, reported in the device information.
+    let hash = git(&["rev-parse", "--short=8", "HEAD"]);
+    let dirty =
+        git(&["status", "--porcelain", "--untracked-files=no"]).is_some_and(|s| !s.is_empty());
+
+    let hash = match hash {
+        Some(hash) if dirty => format!("{hash}-dirty"),
+        Some(hash) => hash,
+        None => "unknown".into(),
+    };
+
+    println!("cargo:rustc-env=GIT_HASH={hash}");
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/index");
+    println!("cargo:rerun-if-changed=src");
+}
+
+/// Output of a git command, `None` if git is not available or it failed.
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
@@ -1,5 +1,10 @@
+/* Flash layout of the STM32F407 (1 MiB):
+ *   0x08000000 - 0x0805FFFF, sectors 0 to 6 (384K): Application.
+ *   0x08060000 - 0x080BFFFF, sectors 7 to 9 (384K): Firmware update slot.
+ *   0x080C0000 - 0x080FFFFF, sectors 10 and 11 (256K): Configuration store.
+ */
 MEMORY
 {
-  FLASH       : ORIGIN = 0x08000000, LENGTH = 1024K
+  FLASH       : ORIGIN = 0x08000000, LENGTH = 384K
   RAM         : ORIGIN = 0x20000000, LENGTH = 128K
 }
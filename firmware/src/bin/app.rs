@@ -2,44 +2,53 @@
 #![no_std]
 #![allow(incomplete_features)]
 
-use rtic_monotonics::{
-    systick::{fugit::MicrosDurationU64, Systick},
-    Monotonic,
-};
-
+pub mod clock;
 pub mod command_handling;
+pub mod config;
+pub mod deferred;
+pub mod discovery;
 pub mod ethernet;
+pub mod firmware_update;
+pub mod health;
+pub mod message;
 pub mod send_heartbeat;
+pub mod stream;
 
-defmt::timestamp!("{=u64:us}", {
-    let time_us: MicrosDurationU64 = Systick::now().duration_since_epoch().convert();
-
-    time_us.ticks()
-});
+// Wall clock time once the backend has published it, time since the start until then.
+defmt::timestamp!("{=u64:us}", crate::clock::now_us());
 
 #[rtic::app(device = embassy_stm32::pac, dispatchers = [I2C1_EV, I2C1_ER, I2C2_EV, I2C2_ER], peripherals = false)]
 mod app {
     use crate::{
-        command_handling::handle_sleep_command,
+        config::{handle_config_command, ConfigCommand},
+        deferred::{handle_deferred, DeferredCommand, DEFERRED_CAPACITY},
         ethernet::{handle_stack, run_comms},
+        firmware_update::{handle_firmware_update, UpdateCommand},
+        message::Message,
         send_heartbeat::send_heartbeat,
+        stream::{stream_topics, StreamCommand},
     };
-    use heapless::Vec;
-    use rpc_definition::endpoints::sleep::Sleep;
-    use rpc_testing::bsp::{self, NetworkStack, Rng};
+    use rpc_definition::endpoints::config::DeviceConfig;
+    use rpc_testing::bsp::{self, config_store::ConfigStore, BoardInfo, Flash, NetworkStack, Rng};
     use rtic_sync::{
+        arbiter::Arbiter,
         channel::{Receiver, Sender},
         make_channel,
     };
+    use static_cell::StaticCell;
 
     #[shared]
     struct Shared {
         network_stack: NetworkStack,
+        config: &'static DeviceConfig,
+        flash: &'static Arbiter<Flash>,
+        board: &'static BoardInfo,
     }
 
     #[local]
     struct Local {
         rng: Rng,
+        config_store: ConfigStore,
     }
 
     #[init]
@@ -47,23 +56,52 @@ mod app {
         defmt::info!("pre init");
 
         // Initialize the underlying HW.
-        let (network_stack, rng) = bsp::init(cx.core);
+        let (network_stack, rng, flash, config_store, config, board) = bsp::init(cx.core);
+
+        // These are used by several tasks for the rest of the program.
+        static CONFIG: StaticCell<DeviceConfig> = StaticCell::new();
+        static FLASH: StaticCell<Arbiter<Flash>> = StaticCell::new();
+        static BOARD: StaticCell<BoardInfo> = StaticCell::new();
+        let config = &*CONFIG.init(config);
+        let flash = &*FLASH.init(Arbiter::new(flash));
+        let board = &*BOARD.init(board);
 
         // Create channels for communication.
-        let (ethernet_tx_sender, ethernet_tx_receiver) = make_channel!(Vec<u8, 128>, 1);
-        let (sleep_request_sender, sleep_request_receiver) = make_channel!((u32, Sleep), 8);
+        let (ethernet_tx_sender, ethernet_tx_receiver) = make_channel!(Message, 1);
+        let (deferred_command_sender, deferred_command_receiver) =
+            make_channel!(DeferredCommand, DEFERRED_CAPACITY);
+        let (update_command_sender, update_command_receiver) =
+            make_channel!((u32, UpdateCommand), 2);
+        let (config_command_sender, config_command_receiver) =
+            make_channel!((u32, ConfigCommand), 2);
+        let (stream_command_sender, stream_command_receiver) =
+            make_channel!((u32, StreamCommand), 2);
 
         handle_stack::spawn().ok();
         run_comms::spawn(
             ethernet_tx_receiver,
             ethernet_tx_sender.clone(),
-            sleep_request_sender,
+            deferred_command_sender,
+            update_command_sender,
+            config_command_sender,
+            stream_command_sender,
         )
         .ok();
-        handle_sleep_command::spawn(sleep_request_receiver, ethernet_tx_sender.clone()).ok();
+        handle_deferred::spawn(deferred_command_receiver, ethernet_tx_sender.clone()).ok();
+        handle_firmware_update::spawn(update_command_receiver, ethernet_tx_sender.clone()).ok();
+        handle_config_command::spawn(config_command_receiver, ethernet_tx_sender.clone()).ok();
+        stream_topics::spawn(stream_command_receiver, ethernet_tx_sender.clone()).ok();
         send_heartbeat::spawn(ethernet_tx_sender).ok();
 
-        (Shared { network_stack }, Local { rng })
+        (
+            Shared {
+                network_stack,
+                config,
+                flash,
+                board,
+            },
+            Local { rng, config_store },
+        )
     }
 
     extern "Rust" {
@@ -72,23 +110,50 @@ mod app {
         async fn handle_stack(_: handle_stack::Context);
 
         // Main RX/TX data pump for Ethernet.
-        #[task(shared = [&network_stack], local = [rng])]
+        #[task(shared = [&network_stack, &config, &board], local = [rng])]
         async fn run_comms(
             _: run_comms::Context,
-            _: Receiver<'static, Vec<u8, 128>, 1>,
-            _: Sender<'static, Vec<u8, 128>, 1>,
-            _: Sender<'static, (u32, Sleep), 8>,
+            _: Receiver<'static, Message, 1>,
+            _: Sender<'static, Message, 1>,
+            _: Sender<'static, DeferredCommand, DEFERRED_CAPACITY>,
+            _: Sender<'static, (u32, UpdateCommand), 2>,
+            _: Sender<'static, (u32, ConfigCommand), 2>,
+            _: Sender<'static, (u32, StreamCommand), 2>,
         );
 
-        // The `sleep` command handling will run at elevated priority.
+        // Deferred responses, such as those of `Sleep`, will be sent at elevated priority.
         #[task(priority = 1)]
-        async fn handle_sleep_command(
-            _: handle_sleep_command::Context,
-            _: Receiver<'static, (u32, Sleep), 8>,
-            _: Sender<'static, Vec<u8, 128>, 1>,
+        async fn handle_deferred(
+            _: handle_deferred::Context,
+            _: Receiver<'static, DeferredCommand, DEFERRED_CAPACITY>,
+            _: Sender<'static, Message, 1>,
+        );
+
+        // Firmware updates, writes the new image into the update slot in flash.
+        #[task(shared = [&flash])]
+        async fn handle_firmware_update(
+            _: handle_firmware_update::Context,
+            _: Receiver<'static, (u32, UpdateCommand), 2>,
+            _: Sender<'static, Message, 1>,
+        );
+
+        // Provisioning, stages and stores the device configuration.
+        #[task(shared = [&config, &flash], local = [config_store])]
+        async fn handle_config_command(
+            _: handle_config_command::Context,
+            _: Receiver<'static, (u32, ConfigCommand), 2>,
+            _: Sender<'static, Message, 1>,
         );
 
+        // Streamed topics, started and stopped by the backend.
         #[task]
-        async fn send_heartbeat(_: send_heartbeat::Context, _: Sender<'static, Vec<u8, 128>, 1>);
+        async fn stream_topics(
+            _: stream_topics::Context,
+            _: Receiver<'static, (u32, StreamCommand), 2>,
+            _: Sender<'static, Message, 1>,
+        );
+
+        #[task(shared = [&config, &network_stack])]
+        async fn send_heartbeat(_: send_heartbeat::Context, _: Sender<'static, Message, 1>);
     }
 }
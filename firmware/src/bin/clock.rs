@@ -0,0 +1,46 @@
+//! Wall clock time, set from the time the backend publishes, and `Systick` for the code shared
+//! with the simulator.
+
+use core::cell::Cell;
+use cortex_m::interrupt::{self, Mutex};
+use rpc_definition::clock::Clock;
+use rtic_monotonics::{
+    systick::{ExtU64, Systick},
+    Monotonic,
+};
+
+/// Microseconds between the Unix epoch and the start of `Systick`, zero until the backend has
+/// sent its time.
+static UNIX_OFFSET_US: Mutex<Cell<u64>> = Mutex::new(Cell::new(0));
+
+/// Set the wall clock to `unix_time_ms`.
+pub fn set(unix_time_ms: u64) {
+    let offset = (unix_time_ms * 1000).saturating_sub(uptime_us());
+    interrupt::free(|cs| UNIX_OFFSET_US.borrow(cs).set(offset));
+}
+
+/// Microseconds since the Unix epoch, or since the start until the backend has sent its time.
+pub fn now_us() -> u64 {
+    uptime_us() + interrupt::free(|cs| UNIX_OFFSET_US.borrow(cs).get())
+}
+
+fn uptime_us() -> u64 {
+    Systick::now().duration_since_epoch().to_micros()
+}
+
+/// `Systick` as the clock of the code shared with the simulator.
+pub struct SystickClock;
+
+impl Clock for SystickClock {
+    type Instant = <Systick as Monotonic>::Instant;
+
+    fn after(instant: Self::Instant, micros: u64) -> Self::Instant {
+        instant + micros.micros()
+    }
+
+    fn micros_between(earlier: Self::Instant, later: Self::Instant) -> u64 {
+        later
+            .checked_duration_since(earlier)
+            .map_or(0, |duration| duration.to_micros())
+    }
+}
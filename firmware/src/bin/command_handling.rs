@@ -1,181 +1,190 @@
-use crate::app;
-use heapless::{binary_heap::Min, BinaryHeap, Vec};
+use crate::{
+    config::ConfigCommand,
+    deferred::{self, DeferredCommand, DeferredResponse, DEFERRED_CAPACITY},
+    ethernet::Keepalive,
+    firmware_update::{UpdateCommand, FIRMWARE_VERSION},
+    message::{self, Message},
+    stream::StreamCommand,
+};
 use rpc_definition::{
+    cancel::TopicCancel,
     endpoints::{
+        config::{CommitConfigEndpoint, GetConfigEndpoint, SetConfigEndpoint},
+        deferred::DeferredStatsEndpoint,
+        device_info::{DeviceInfo, DeviceInfoEndpoint},
+        firmware_update::{
+            FinishUpdateEndpoint, FirmwareVersionEndpoint, StartUpdateEndpoint, WriteChunkEndpoint,
+        },
         pingpong::{PingPongEndpoint, Pong},
-        sleep::{Sleep, SleepDone, SleepEndpoint},
+        sleep::{SleepDone, SleepEndpoint},
+        stream::{SetStreamRateEndpoint, StartStreamEndpoint, StopStreamEndpoint},
     },
-    postcard_rpc::{self, Endpoint},
-    wire_error::{FatalError, ERROR_KEY},
+    heapless as rpc_heapless,
+    postcard_rpc::Endpoint,
+    topics::{close::TopicClose, heartbeat::TopicHeartbeatAck, time::TopicTime},
+    wire_error::FatalError,
+    PROTOCOL_VERSION,
 };
+use rpc_testing::bsp::BoardInfo;
 use rtic_monotonics::{
     systick::{fugit::ExtU64, Systick},
     Monotonic,
 };
-use rtic_sync::channel::{Receiver, Sender};
+use rtic_sync::channel::Sender;
 
 /// Main command dispatch helper, this is called on all incoming packets.
+#[allow(clippy::too_many_arguments)]
 pub async fn dispatch(
     buf: &[u8],
-    ethernet_tx: &mut Sender<'static, Vec<u8, 128>, 1>,
-    sleep_command_sender: &mut Sender<'static, (u32, Sleep), 8>,
+    ethernet_tx: &mut Sender<'static, Message, 1>,
+    deferred_command_sender: &mut Sender<'static, DeferredCommand, DEFERRED_CAPACITY>,
+    update_command_sender: &mut Sender<'static, (u32, UpdateCommand), 2>,
+    config_command_sender: &mut Sender<'static, (u32, ConfigCommand), 2>,
+    stream_command_sender: &mut Sender<'static, (u32, StreamCommand), 2>,
+    keepalive: &Keepalive,
+    board: &BoardInfo,
 ) {
     // Do handling of each command, some synchronously and some asynchronously.
     if let Err(e) = crate::dispatch!(
         buf,
         (hdr, _buf) = _ => {
             defmt::error!("Got unhandled endpoint/topic with key = {:x}", hdr.key.to_bytes());
-            unhandled_error(hdr.seq_no, ethernet_tx, FatalError::UnknownEndpoint).await;
+            message::send_error(hdr.seq_no, FatalError::UnknownEndpoint, ethernet_tx).await;
         },
         EP: (hdr, sleeping_req) = SleepEndpoint => {
             defmt::trace!("Got Sleep request {}", sleeping_req);
-            if sleep_command_sender.try_send((hdr.seq_no, sleeping_req)).is_err() {
-                // If all queues are full, tell the backend that we are over capacity.
-                unhandled_error(hdr.seq_no, ethernet_tx, FatalError::NotEnoughSenders).await;
+            let due = Systick::now()
+                + (sleeping_req.seconds as u64).secs()
+                + (sleeping_req.micros as u64).micros();
+            let done = SleepDone { slept_for: sleeping_req };
+            if let Some(frame) = message::frame(hdr.seq_no, SleepEndpoint::RESP_KEY, &done) {
+                let response = DeferredResponse { seq_no: hdr.seq_no, due, frame };
+                if deferred_command_sender.try_send(DeferredCommand::Schedule(response)).is_err() {
+                    // If all queues are full, tell the backend to retry in a moment.
+                    let busy = FatalError::Busy(deferred::COMMANDS_FULL_RETRY_MS);
+                    message::send_error(hdr.seq_no, busy, ethernet_tx).await;
+                }
             }
         },
+        EP: (hdr, _stats_req) = DeferredStatsEndpoint => {
+            defmt::trace!("Got DeferredStats request");
+            message::send_response(hdr.seq_no, DeferredStatsEndpoint::RESP_KEY, &deferred::stats(), ethernet_tx).await;
+        },
         EP: (hdr, _pingpong_req) = PingPongEndpoint => {
             defmt::trace!("Got Ping request");
-            ping_response(hdr.seq_no, ethernet_tx).await;
+            message::send_response(hdr.seq_no, PingPongEndpoint::RESP_KEY, &Pong {}, ethernet_tx).await;
+        },
+        EP: (hdr, _version_req) = FirmwareVersionEndpoint => {
+            defmt::trace!("Got FirmwareVersion request");
+            message::send_response(hdr.seq_no, FirmwareVersionEndpoint::RESP_KEY, &FIRMWARE_VERSION, ethernet_tx).await;
+        },
+        EP: (hdr, _info_req) = DeviceInfoEndpoint => {
+            defmt::trace!("Got DeviceInfo request");
+            device_info_response(hdr.seq_no, board, ethernet_tx).await;
+        },
+        EP: (hdr, start_req) = StartUpdateEndpoint => {
+            defmt::trace!("Got StartUpdate request {}", start_req);
+            if update_command_sender.try_send((hdr.seq_no, UpdateCommand::Start(start_req))).is_err() {
+                message::send_error(hdr.seq_no, FatalError::NotEnoughSenders, ethernet_tx).await;
+            }
+        },
+        EP: (hdr, chunk_req) = WriteChunkEndpoint => {
+            defmt::trace!("Got WriteChunk request at {}", chunk_req.offset);
+            if update_command_sender.try_send((hdr.seq_no, UpdateCommand::Chunk(chunk_req))).is_err() {
+                message::send_error(hdr.seq_no, FatalError::NotEnoughSenders, ethernet_tx).await;
+            }
+        },
+        EP: (hdr, _finish_req) = FinishUpdateEndpoint => {
+            defmt::trace!("Got FinishUpdate request");
+            if update_command_sender.try_send((hdr.seq_no, UpdateCommand::Finish)).is_err() {
+                message::send_error(hdr.seq_no, FatalError::NotEnoughSenders, ethernet_tx).await;
+            }
+        },
+        EP: (hdr, _get_req) = GetConfigEndpoint => {
+            defmt::trace!("Got GetConfig request");
+            if config_command_sender.try_send((hdr.seq_no, ConfigCommand::Get)).is_err() {
+                message::send_error(hdr.seq_no, FatalError::NotEnoughSenders, ethernet_tx).await;
+            }
+        },
+        EP: (hdr, set_req) = SetConfigEndpoint => {
+            defmt::trace!("Got SetConfig request");
+            if config_command_sender.try_send((hdr.seq_no, ConfigCommand::Set(set_req.config))).is_err() {
+                message::send_error(hdr.seq_no, FatalError::NotEnoughSenders, ethernet_tx).await;
+            }
+        },
+        EP: (hdr, _commit_req) = CommitConfigEndpoint => {
+            defmt::trace!("Got CommitConfig request");
+            if config_command_sender.try_send((hdr.seq_no, ConfigCommand::Commit)).is_err() {
+                message::send_error(hdr.seq_no, FatalError::NotEnoughSenders, ethernet_tx).await;
+            }
+        },
+        EP: (hdr, start_req) = StartStreamEndpoint => {
+            defmt::trace!("Got StartStream request {}", start_req);
+            if stream_command_sender.try_send((hdr.seq_no, StreamCommand::Start(start_req.topic))).is_err() {
+                message::send_error(hdr.seq_no, FatalError::NotEnoughSenders, ethernet_tx).await;
+            }
+        },
+        EP: (hdr, stop_req) = StopStreamEndpoint => {
+            defmt::trace!("Got StopStream request {}", stop_req);
+            if stream_command_sender.try_send((hdr.seq_no, StreamCommand::Stop(stop_req.topic))).is_err() {
+                message::send_error(hdr.seq_no, FatalError::NotEnoughSenders, ethernet_tx).await;
+            }
+        },
+        EP: (hdr, rate_req) = SetStreamRateEndpoint => {
+            defmt::trace!("Got SetStreamRate request {}", rate_req);
+            if stream_command_sender.try_send((hdr.seq_no, StreamCommand::SetRate(rate_req.topic, rate_req.hz))).is_err() {
+                message::send_error(hdr.seq_no, FatalError::NotEnoughSenders, ethernet_tx).await;
+            }
+        },
+        TP: (_hdr, ack) = TopicHeartbeatAck => {
+            defmt::trace!("Heartbeat {} acknowledged", ack.sequence_number);
+            keepalive.acked();
+        },
+        TP: (_hdr, _close) = TopicClose => {
+            defmt::debug!("The backend is closing the session");
+            keepalive.close();
+        },
+        TP: (_hdr, cancel) = TopicCancel => {
+            defmt::trace!("Got Cancel for {}", cancel.seq_no);
+            // Only deferred responses wait long enough to be worth cancelling.
+            if deferred_command_sender.try_send(DeferredCommand::Cancel(cancel.seq_no)).is_err() {
+                defmt::warn!("Too many cancellations, {} runs to the end", cancel.seq_no);
+            }
+        },
+        TP: (_hdr, time) = TopicTime => {
+            defmt::info!("Backend time is {} ms since the epoch", time.unix_time_ms);
+            crate::clock::set(time.unix_time_ms);
         }
     ) {
-        // Note: Should we send unhandled_error if we failed to deserialize?
+        // Note: Should we send an error if we failed to deserialize?
         // Dispatch deserialization failure
         defmt::error!("Failed to do dispatch: {}", e);
     }
 }
 
-/// Helper to generate an unhandled packet error.
-async fn unhandled_error(
-    seq_no: u32,
-    ethernet_tx: &mut Sender<'static, Vec<u8, 128>, 1>,
-    error: FatalError,
-) {
-    let mut buf = [0; 128];
-    if let Ok(used) = postcard_rpc::headered::to_slice_keyed(seq_no, ERROR_KEY, &error, &mut buf) {
-        ethernet_tx.send(Vec::from_slice(used).unwrap()).await.ok();
-    }
-}
-
-/// Helper to generate a response to a `Ping` call.
-async fn ping_response(seq_no: u32, ethernet_tx: &mut Sender<'static, Vec<u8, 128>, 1>) {
-    let mut buf = [0; 128];
-    if let Ok(used) = postcard_rpc::headered::to_slice_keyed(
-        seq_no,
-        PingPongEndpoint::RESP_KEY,
-        &Pong {},
-        &mut buf,
-    ) {
-        ethernet_tx.send(Vec::from_slice(used).unwrap()).await.ok();
-    }
-}
-
-/// Helper to generate a response to a `Sleep` call.
-async fn sleep_response(
+/// Helper to generate a response to a `DeviceInfo` call.
+async fn device_info_response(
     seq_no: u32,
-    sleep: Sleep,
-    ethernet_tx: &mut Sender<'static, Vec<u8, 128>, 1>,
+    board: &BoardInfo,
+    ethernet_tx: &mut Sender<'static, Message, 1>,
 ) {
-    let mut buf = [0; 128];
-    if let Ok(used) = postcard_rpc::headered::to_slice_keyed(
-        seq_no,
-        SleepEndpoint::RESP_KEY,
-        &SleepDone { slept_for: sleep },
-        &mut buf,
-    ) {
-        ethernet_tx.send(Vec::from_slice(used).unwrap()).await.ok();
-    }
-}
+    let mut hostname = rpc_heapless::String::new();
+    hostname.push_str(&board.hostname).ok();
+    let mut git_hash = rpc_heapless::String::new();
+    git_hash.push_str(env!("GIT_HASH")).ok();
 
-/// Task to executing `Sleep` commands.
-///
-/// It looks a bit complex, but basically it:
-/// 1. Takes `Sleep` commands from a queue and calculate the time at which a
-///    response should be sent.
-/// 2. Puts this in a sorted heap, with the next to execute at the top.
-/// 3. Wait for the next one to dequeue, and generate a response over Ethernet.
-pub async fn handle_sleep_command(
-    _: app::handle_sleep_command::Context<'_>,
-    mut sleep_command_receiver: Receiver<'static, (u32, Sleep), 8>,
-    mut ethernet_tx_sender: Sender<'static, Vec<u8, 128>, 1>,
-) {
-    let mut queue = BinaryHeap::<SortedSleepHandler, Min, 8>::new();
-
-    loop {
-        // Always get the head of the queue in case last iteration replaced it.
-        let next_wakeup = queue.peek().map(|next| next.sleep_until);
-
-        // Check if the time has come to send a response.
-        if let Some(next_wakeup) = next_wakeup {
-            if Systick::now() >= next_wakeup {
-                let next = queue.pop().unwrap();
-
-                defmt::debug!("Sleep {} finished", next.seq_no);
-                sleep_response(next.seq_no, next.sleep, &mut ethernet_tx_sender).await;
+    let info = DeviceInfo {
+        uid: board.uid,
+        mac: board.mac,
+        hostname,
+        firmware_version: FIRMWARE_VERSION,
+        git_hash,
+        protocol_version: PROTOCOL_VERSION,
+        uptime_ms: Systick::now().duration_since_epoch().to_millis(),
+        reset_reason: board.reset_reason,
+    };
 
-                continue;
-            }
-        }
-
-        // Check if there is a new command to add to the queue.
-        let (seq_no, sleep_command) = match next_wakeup {
-            Some(next) => match Systick::timeout_at(next, async {
-                if queue.len() == queue.capacity() {
-                    // The queue is full, wait for timeout.
-                    core::future::pending().await
-                } else {
-                    // The queue is not full, we can add more data into the queue.
-                    sleep_command_receiver.recv().await.unwrap()
-                }
-            })
-            .await
-            {
-                Ok(o) => o,
-                Err(_timeout) => continue,
-            },
-            None => sleep_command_receiver.recv().await.unwrap(),
-        };
-
-        defmt::debug!("Sleep {} requested", seq_no);
-        queue
-            .push(SortedSleepHandler {
-                sleep_until: Systick::now()
-                    + (sleep_command.seconds as u64).secs()
-                    + (sleep_command.micros as u64).micros(),
-                sleep: sleep_command,
-                seq_no,
-            })
-            .ok();
-    }
-}
-
-/// Boiler-plate to make a `Sleep` command sortable on when it should run in a
-/// `heapless::BinaryHeap`.
-#[derive(Clone)]
-struct SortedSleepHandler {
-    sleep_until: <Systick as Monotonic>::Instant,
-    sleep: Sleep,
-    seq_no: u32,
-}
-
-impl core::cmp::PartialEq for SortedSleepHandler {
-    fn eq(&self, other: &Self) -> bool {
-        self.sleep_until.eq(&other.sleep_until)
-    }
-}
-
-impl core::cmp::Eq for SortedSleepHandler {}
-
-impl core::cmp::PartialOrd for SortedSleepHandler {
-    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
-        self.sleep_until.partial_cmp(&other.sleep_until)
-    }
-}
-
-impl core::cmp::Ord for SortedSleepHandler {
-    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
-        self.sleep_until.cmp(&other.sleep_until)
-    }
+    message::send_response(seq_no, DeviceInfoEndpoint::RESP_KEY, &info, ethernet_tx).await;
 }
 
 #[derive(defmt::Format, Debug, PartialEq, Eq, Clone)]
@@ -237,6 +246,7 @@ macro_rules! dispatch {
         $buf:ident,
         $unhandled:pat = _ => $unhandled_body:tt,
         $(EP: $ep_request:pat = $endpoint:path => $ep_body:tt),*
+        $(,)?
         $(TP: $topic_pl:pat = $topic:path => $topic_body:tt),*
     ) => {
     {
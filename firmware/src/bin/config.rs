@@ -0,0 +1,103 @@
+use crate::{
+    app,
+    message::{self, Message},
+};
+use rpc_definition::{
+    endpoints::config::{
+        CommitConfigEndpoint, ConfigError, ConfigResponse, DeviceConfig, GetConfigEndpoint,
+        SetConfigEndpoint,
+    },
+    postcard_rpc::{Endpoint, Key},
+};
+use rtic_monotonics::systick::{ExtU64, Systick};
+use rtic_sync::channel::{Receiver, Sender};
+
+/// Configuration requests, forwarded from the dispatcher.
+pub enum ConfigCommand {
+    Get,
+    Set(DeviceConfig),
+    Commit,
+}
+
+/// Task executing configuration commands.
+///
+/// A new configuration is staged in RAM until it is committed, it is then written to flash and
+/// the device restarts, as the PSK and the backend are only used when connecting.
+pub async fn handle_config_command(
+    cx: app::handle_config_command::Context<'_>,
+    mut config_command_receiver: Receiver<'static, (u32, ConfigCommand), 2>,
+    mut ethernet_tx_sender: Sender<'static, Message, 1>,
+) -> ! {
+    let config = *cx.shared.config;
+    let flash = *cx.shared.flash;
+    let store = cx.local.config_store;
+    let mut staged: Option<DeviceConfig> = None;
+
+    loop {
+        let (seq_no, command) = config_command_receiver.recv().await.unwrap();
+
+        let (key, response) = match command {
+            ConfigCommand::Get => {
+                current_config_response(seq_no, config, &mut ethernet_tx_sender).await;
+                continue;
+            }
+            ConfigCommand::Set(new) => (
+                SetConfigEndpoint::RESP_KEY,
+                match new.validate() {
+                    Ok(()) => {
+                        defmt::info!("Staged a new configuration");
+                        staged = Some(new);
+                        ConfigResponse::Staged
+                    }
+                    Err(e) => ConfigResponse::Error(e),
+                },
+            ),
+            ConfigCommand::Commit => (
+                CommitConfigEndpoint::RESP_KEY,
+                match &staged {
+                    Some(new) => match store.store(&mut flash.access().await, new).await {
+                        Ok(()) => ConfigResponse::Committed,
+                        Err(e) => {
+                            defmt::error!("Failed to store the configuration: {}", e);
+                            ConfigResponse::Error(ConfigError::Flash)
+                        }
+                    },
+                    None => ConfigResponse::Error(ConfigError::NothingStaged),
+                },
+            ),
+        };
+
+        config_response(seq_no, key, &response, &mut ethernet_tx_sender).await;
+
+        if response == ConfigResponse::Committed {
+            defmt::info!("Configuration committed, restarting");
+
+            // Give the response time to leave before restarting with the new configuration.
+            Systick::delay(500.millis()).await;
+            cortex_m::peripheral::SCB::sys_reset();
+        }
+    }
+}
+
+/// Helper to generate a response to a `GetConfig` call.
+async fn current_config_response(
+    seq_no: u32,
+    config: &DeviceConfig,
+    ethernet_tx: &mut Sender<'static, Message, 1>,
+) {
+    // The key stays on the device.
+    let mut current = config.clone();
+    current.psk_key.clear();
+
+    message::send_response(seq_no, GetConfigEndpoint::RESP_KEY, &current, ethernet_tx).await;
+}
+
+/// Helper to generate a response to a `SetConfig` or `CommitConfig` call.
+async fn config_response(
+    seq_no: u32,
+    key: Key,
+    response: &ConfigResponse,
+    ethernet_tx: &mut Sender<'static, Message, 1>,
+) {
+    message::send_response(seq_no, key, response, ethernet_tx).await;
+}
@@ -0,0 +1,89 @@
+//! Deferred responses, sent at a later point in time than the call that asked for them.
+//!
+//! An endpoint that answers later, like `Sleep`, serializes its response up front and schedules it
+//! with the time it's due. `handle_deferred` holds the responses sorted on when they are due and
+//! sends them in that order. A response that doesn't fit is not queued, the call is refused with
+//! `FatalError::Busy` and the time until a slot frees up as the hint when to retry.
+//!
+//! The queue is shared with the simulator, only the timer and the channels are the firmware's.
+
+use crate::{
+    app,
+    clock::SystickClock,
+    message::{self, Message},
+};
+use rpc_definition::{
+    endpoints::deferred::{self, Deferred, DeferredCounters, DeferredStats},
+    wire_error::FatalError,
+};
+use rtic_monotonics::{systick::Systick, Monotonic};
+use rtic_sync::channel::{Receiver, Sender};
+
+pub use deferred::{COMMANDS_FULL_RETRY_MS, DEFERRED_CAPACITY};
+
+/// A serialized response, sent once it is due.
+pub type DeferredResponse = deferred::DeferredResponse<SystickClock, Message>;
+
+/// Requests to `handle_deferred`, forwarded from the dispatcher.
+pub type DeferredCommand = deferred::DeferredCommand<SystickClock, Message>;
+
+static COUNTERS: DeferredCounters = DeferredCounters::new();
+
+/// Statistics of `handle_deferred`.
+pub fn stats() -> DeferredStats {
+    COUNTERS.stats()
+}
+
+/// Task sending the deferred responses once they are due.
+pub async fn handle_deferred(
+    _: app::handle_deferred::Context<'_>,
+    mut deferred_command_receiver: Receiver<'static, DeferredCommand, DEFERRED_CAPACITY>,
+    mut ethernet_tx_sender: Sender<'static, Message, 1>,
+) -> ! {
+    let mut deferred = Deferred::<SystickClock, Message, DEFERRED_CAPACITY>::new();
+
+    loop {
+        // Check if the time has come to send a response.
+        if let Some(response) = deferred.pop_due(Systick::now()) {
+            defmt::debug!("Deferred response to {} is due", response.seq_no);
+            ethernet_tx_sender.send(response.frame).await.ok();
+            COUNTERS.completed();
+            COUNTERS.queued(deferred.len());
+
+            continue;
+        }
+
+        // Always keep taking commands, a full queue refuses new responses instead of leaving them
+        // in the channel.
+        let command = match deferred.next_due() {
+            Some(next) => match Systick::timeout_at(next, deferred_command_receiver.recv()).await {
+                Ok(command) => command.unwrap(),
+                Err(_timeout) => continue,
+            },
+            None => deferred_command_receiver.recv().await.unwrap(),
+        };
+
+        match command {
+            DeferredCommand::Schedule(response) => {
+                let seq_no = response.seq_no;
+                if let Err(retry_after_ms) = deferred.schedule(response, Systick::now()) {
+                    defmt::warn!(
+                        "No room to defer {}, retry in {} ms",
+                        seq_no,
+                        retry_after_ms
+                    );
+                    COUNTERS.rejected();
+                    let busy = FatalError::Busy(retry_after_ms);
+                    message::send_error(seq_no, busy, &mut ethernet_tx_sender).await;
+                }
+            }
+            DeferredCommand::Cancel(seq_no) => {
+                if deferred.cancel(seq_no) {
+                    defmt::debug!("Deferred response to {} cancelled", seq_no);
+                    COUNTERS.cancelled();
+                }
+            }
+        }
+        COUNTERS.queued(deferred.len());
+    }
+}
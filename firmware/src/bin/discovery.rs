@@ -0,0 +1,69 @@
+//! Finding the backend, so a device on a new network works without being reflashed.
+//!
+//! The backend is looked up by name with the DNS server from DHCP first, then by listening for an
+//! announcement broadcast by the ingress. If neither works the fallback from the configuration is
+//! used, without one the device keeps looking.
+
+use embassy_net::{dns::DnsQueryType, udp::UdpSocket, IpEndpoint};
+use embassy_time::{with_timeout, Duration};
+use rpc_definition::discovery::Announcement;
+use rpc_testing::bsp::NetworkStack;
+
+/// Hostname of the backend.
+const BACKEND_HOSTNAME: &str = "rpc-ingress";
+
+/// Port of the backend, when found by name.
+const BACKEND_PORT: u16 = 8321;
+
+/// How long to listen for an announcement, the ingress sends one every few seconds.
+const ANNOUNCEMENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Find the address of the backend.
+///
+/// `announcements` must be bound to `DISCOVERY_PORT`.
+pub async fn find_backend(
+    stack: NetworkStack,
+    announcements: &UdpSocket<'_>,
+    fallback: Option<IpEndpoint>,
+) -> IpEndpoint {
+    loop {
+        match stack.dns_query(BACKEND_HOSTNAME, DnsQueryType::A).await {
+            Ok(addresses) if !addresses.is_empty() => {
+                let backend = IpEndpoint::new(addresses[0], BACKEND_PORT);
+                defmt::info!("Resolved {} to {}", BACKEND_HOSTNAME, backend);
+                return backend;
+            }
+            Ok(_) => defmt::warn!("{} has no address", BACKEND_HOSTNAME),
+            Err(e) => defmt::warn!("Unable to resolve {}: {}", BACKEND_HOSTNAME, e),
+        }
+
+        if let Ok(backend) = with_timeout(ANNOUNCEMENT_TIMEOUT, announcement(announcements)).await {
+            defmt::info!("Backend announced at {}", backend);
+            return backend;
+        }
+
+        match fallback {
+            Some(backend) => {
+                defmt::warn!("No backend found, falling back to {}", backend);
+                return backend;
+            }
+            None => defmt::warn!("No backend found, looking again"),
+        }
+    }
+}
+
+/// Wait for an announcement from the backend.
+async fn announcement(socket: &UdpSocket<'_>) -> IpEndpoint {
+    let mut buf = [0; 32];
+
+    loop {
+        match socket.recv_from(&mut buf).await {
+            Ok((n, sender)) => {
+                if let Some(announcement) = Announcement::parse(&buf[..n]) {
+                    return IpEndpoint::new(sender.addr, announcement.port);
+                }
+            }
+            Err(e) => defmt::error!("Unable to receive announcements: {}", e),
+        }
+    }
+}
@@ -1,8 +1,19 @@
 use crate::app;
-use embassy_futures::join::join3;
+use crate::config::ConfigCommand;
+use crate::deferred::{DeferredCommand, DEFERRED_CAPACITY};
+use crate::discovery::find_backend;
+use crate::firmware_update::UpdateCommand;
+use crate::health::{self, DTLS_ERRORS};
+use crate::message::{self, Message};
+use crate::stream::StreamCommand;
+use core::{cell::Cell, future::poll_fn, task::Poll};
+use embassy_futures::{
+    join::join3,
+    select::{select3, Either3},
+};
 use embassy_net::{
     udp::{PacketMetadata, UdpSocket},
-    Ipv4Address,
+    IpEndpoint, Ipv4Address,
 };
 use embassy_time::{with_timeout, Duration, TimeoutError};
 use embedded_dtls::{
@@ -14,22 +25,40 @@ use embedded_dtls::{
     queue_helpers::FramedQueue,
     ApplicationDataReceiver, ApplicationDataSender,
 };
-use heapless::Vec;
-use rpc_definition::endpoints::sleep::Sleep;
-use rtic_monotonics::systick::Systick;
+use rand_core::RngCore;
+use rpc_definition::{
+    connection::{Backoff, MAX_MESSAGE_SIZE, MISSED_ACKS},
+    discovery::DISCOVERY_PORT,
+    endpoints::config::Backend,
+    fragment::{Fragmenter, Reassembler, ReassemblyError, MAX_RECORD_SIZE},
+    wire_error::FatalError,
+};
+use rtic_common::waker_registration::CriticalSectionWakerRegistration;
+use rtic_monotonics::{
+    systick::{ExtU64, Systick},
+    Monotonic,
+};
 use rtic_sync::channel::{Receiver, Sender};
 
-// Backend IP.
-const BACKEND_ENDPOINT: (Ipv4Address, u16) = (Ipv4Address::new(192, 168, 0, 220), 8321);
+/// Failed connection attempts in a row before the backend is looked up again.
+const RESOLVE_AFTER_FAILURES: u32 = 3;
+
+/// Size of the record queues between the DTLS connection and the application.
+const RECORD_QUEUE_SIZE: usize = 2 * MAX_RECORD_SIZE;
 
 /// Main UDP RX/TX data pump. Also sets up the UDP socket.
 pub async fn run_comms(
     cx: app::run_comms::Context<'_>,
-    mut ethernet_tx_receiver: Receiver<'static, Vec<u8, 128>, 1>,
-    mut ethernet_tx_sender: Sender<'static, Vec<u8, 128>, 1>,
-    mut sleep_command_sender: Sender<'static, (u32, Sleep), 8>,
+    mut ethernet_tx_receiver: Receiver<'static, Message, 1>,
+    mut ethernet_tx_sender: Sender<'static, Message, 1>,
+    mut deferred_command_sender: Sender<'static, DeferredCommand, DEFERRED_CAPACITY>,
+    mut update_command_sender: Sender<'static, (u32, UpdateCommand), 2>,
+    mut config_command_sender: Sender<'static, (u32, ConfigCommand), 2>,
+    mut stream_command_sender: Sender<'static, (u32, StreamCommand), 2>,
 ) -> ! {
     let stack = *cx.shared.network_stack;
+    let config = *cx.shared.config;
+    let board = *cx.shared.board;
     let rng = cx.local.rng;
 
     // Ensure DHCP configuration is up before trying connect
@@ -54,22 +83,57 @@ pub async fn run_comms(
     );
     socket.bind(8321).unwrap();
 
-    let mut fq = FramedQueue::<128>::new();
+    // Announcements of the backend are received on a socket of their own.
+    let mut announce_rx_buffer = [0; 64];
+    let mut announce_tx_buffer = [0; 64];
+    let mut announce_rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut announce_tx_meta = [PacketMetadata::EMPTY; 2];
+
+    let mut announcements = UdpSocket::new(
+        stack,
+        &mut announce_rx_meta,
+        &mut announce_rx_buffer,
+        &mut announce_tx_meta,
+        &mut announce_tx_buffer,
+    );
+    announcements.bind(DISCOVERY_PORT).unwrap();
+
+    let mut fq = FramedQueue::<RECORD_QUEUE_SIZE>::new();
     let (mut rx_sender, mut rx_receiver) = fq.split().unwrap();
-    let mut fq = FramedQueue::<128>::new();
+    let mut fq = FramedQueue::<RECORD_QUEUE_SIZE>::new();
     let (mut tx_sender, mut tx_receiver) = fq.split().unwrap();
     let client_config = ClientConfig {
         psk: Psk {
-            identity: b"hello world",
-            key: b"11111234567890qwertyuiopasdfghjklzxc",
+            identity: &config.psk_identity,
+            key: &config.psk_key,
         },
     };
 
+    // A provisioned backend address is used as is, otherwise the backend is looked for.
+    let endpoint = |backend: Backend| IpEndpoint::new(Ipv4Address(backend.ip).into(), backend.port);
+    let fixed_backend = config.backend.map(endpoint);
+    let fallback_backend = config.fallback_backend.map(endpoint);
+
+    let keepalive = Keepalive::new(config.heartbeat_interval_secs as u64);
+
     join3(
-        async move {
+        async {
+            let mut backoff = Backoff::new();
+            let mut backend = match fixed_backend {
+                Some(backend) => backend,
+                None => find_backend(stack, &announcements, fallback_backend).await,
+            };
+            let mut failures = 0;
+
             loop {
-                let rx = edtls::DtlsSocket::new(&socket, BACKEND_ENDPOINT);
-                let tx = edtls::DtlsSocket::new(&socket, BACKEND_ENDPOINT);
+                // The backend may have moved, e.g. to another address from DHCP.
+                if failures >= RESOLVE_AFTER_FAILURES && fixed_backend.is_none() {
+                    backend = find_backend(stack, &announcements, fallback_backend).await;
+                    failures = 0;
+                }
+
+                let rx = edtls::DtlsSocket::new(&socket, backend);
+                let tx = edtls::DtlsSocket::new(&socket, backend);
                 let cipher = ChaCha20Poly1305Cipher::default();
                 let client_connection = match with_timeout(
                     Duration::from_secs(5),
@@ -87,50 +151,102 @@ pub async fn run_comms(
                     Ok(Ok(c)) => c,
                     Ok(Err(e)) => {
                         defmt::error!("Failed to open a DTLS client connection: {}", e);
+                        health::count(&DTLS_ERRORS);
+                        failures += 1;
+                        back_off(&mut backoff, rng).await;
                         continue;
                     }
                     Err(TimeoutError) => {
                         defmt::error!("Attempt to open a DTLS connection timed out");
+                        health::count(&DTLS_ERRORS);
+                        failures += 1;
+                        back_off(&mut backoff, rng).await;
                         continue;
                     }
                 };
 
+                backoff.reset();
+                keepalive.opened();
+                failures = 0;
+
                 let mut rx_buf = [0; 1536];
                 let mut tx_buf = [0; 1536];
 
-                if let Err(e) = client_connection
-                    .run(
+                match select3(
+                    client_connection.run(
                         &mut rx_buf,
                         &mut tx_buf,
                         &mut rx_sender,
                         &mut tx_receiver,
                         &mut Systick,
-                    )
-                    .await
+                    ),
+                    keepalive.expired(),
+                    keepalive.closed(),
+                )
+                .await
                 {
-                    defmt::error!("Client connection closed with {:?}", e);
+                    Either3::First(result) => {
+                        if let Err(e) = result {
+                            defmt::error!("Client connection closed with {:?}", e);
+                            health::count(&DTLS_ERRORS);
+                        }
+                    }
+                    Either3::Second(()) => {
+                        defmt::warn!("The backend stopped acknowledging heartbeats, reconnecting");
+                        failures += 1;
+                    }
+                    Either3::Third(()) => {
+                        defmt::info!("The backend closed the session, reconnecting");
+                        back_off(&mut backoff, rng).await;
+                    }
                 }
             }
         },
         async {
+            // Transmit worker, messages larger than a record are sent in fragments.
+            let mut buf = [0; MAX_RECORD_SIZE];
             loop {
-                if let Err(e) = tx_sender
-                    .send(ethernet_tx_receiver.recv().await.unwrap())
-                    .await
-                {
-                    defmt::error!("Could not fit data in the tx_sender: {}", e);
+                let message = ethernet_tx_receiver.recv().await.unwrap();
+                let mut fragmenter = Fragmenter::new(&message);
+                while let Some(record) = fragmenter.next_record(&mut buf) {
+                    if let Err(e) = tx_sender.send(record).await {
+                        defmt::error!("Could not fit data in the tx_sender: {}", e);
+                    }
                 }
             }
         },
         async {
-            // Receive worker.
+            // Receive worker, fragmented messages are put back together before the dispatch.
+            let mut storage = [0; MAX_MESSAGE_SIZE];
+            let mut reassembler = Reassembler::new(&mut storage);
             loop {
-                crate::command_handling::dispatch(
-                    rx_receiver.peek().await.unwrap().as_ref(),
-                    &mut ethernet_tx_sender,
-                    &mut sleep_command_sender,
-                )
-                .await;
+                match reassembler.push(rx_receiver.peek().await.unwrap().as_ref()) {
+                    Ok(Some(frame)) => {
+                        crate::command_handling::dispatch(
+                            frame,
+                            &mut ethernet_tx_sender,
+                            &mut deferred_command_sender,
+                            &mut update_command_sender,
+                            &mut config_command_sender,
+                            &mut stream_command_sender,
+                            &keepalive,
+                            board,
+                        )
+                        .await;
+                    }
+                    Ok(None) => {}
+                    Err(ReassemblyError::TooLarge { seq_no }) => {
+                        defmt::error!("Message {} is too large, it is dropped", seq_no);
+                        let too_large = FatalError::MessageTooLarge;
+                        message::send_error(seq_no, too_large, &mut ethernet_tx_sender).await;
+                    }
+                    Err(ReassemblyError::Missing { seq_no }) => {
+                        defmt::warn!("Message {} lost a fragment, it is dropped", seq_no);
+                        let lost = FatalError::WireFailure;
+                        message::send_error(seq_no, lost, &mut ethernet_tx_sender).await;
+                    }
+                    Err(e) => defmt::warn!("Dropped a received record: {}", e),
+                }
                 rx_receiver.pop().unwrap();
             }
         },
@@ -139,12 +255,87 @@ pub async fn run_comms(
     .0
 }
 
+/// Application level keepalive.
+///
+/// A backend that restarted has lost the session and drops everything the device sends, without
+/// the DTLS connection noticing. The backend acknowledges every heartbeat, so when it stops doing
+/// that the session is dropped and a new one opened. A backend that shuts down closes the
+/// session instead, with `TopicClose`.
+pub struct Keepalive {
+    heartbeat_interval_secs: u64,
+    last_ack: Cell<<Systick as Monotonic>::Instant>,
+    closed: Cell<bool>,
+    close_waker: CriticalSectionWakerRegistration,
+}
+
+impl Keepalive {
+    fn new(heartbeat_interval_secs: u64) -> Self {
+        Self {
+            heartbeat_interval_secs,
+            last_ack: Cell::new(Systick::now()),
+            closed: Cell::new(false),
+            close_waker: CriticalSectionWakerRegistration::new(),
+        }
+    }
+
+    /// A new session started.
+    fn opened(&self) {
+        self.closed.set(false);
+        self.acked();
+    }
+
+    /// The backend closed the session.
+    pub fn close(&self) {
+        self.closed.set(true);
+        self.close_waker.wake();
+    }
+
+    /// Resolves when the backend closed the session.
+    async fn closed(&self) {
+        poll_fn(|cx| {
+            self.close_waker.register(cx.waker());
+            if self.closed.get() {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// The backend acknowledged a heartbeat.
+    pub fn acked(&self) {
+        self.last_ack.set(Systick::now());
+    }
+
+    /// Resolves when no heartbeat has been acknowledged for `MISSED_ACKS` heartbeats.
+    async fn expired(&self) {
+        loop {
+            let deadline =
+                self.last_ack.get() + (self.heartbeat_interval_secs * MISSED_ACKS as u64).secs();
+            if Systick::now() >= deadline {
+                return;
+            }
+
+            Systick::delay_until(deadline).await;
+        }
+    }
+}
+
+/// Wait out the backoff before the next connection attempt.
+async fn back_off(backoff: &mut Backoff, rng: &mut impl RngCore) {
+    let ms = backoff.next_ms(rng.next_u32());
+    defmt::debug!("Reconnecting in {} ms", ms);
+    Systick::delay((ms as u64).millis()).await;
+}
+
 /// `embassy-net` stack poller.
 pub async fn handle_stack(cx: app::handle_stack::Context<'_>) -> ! {
     cx.shared.network_stack.run().await
 }
 
 pub mod edtls {
+    use crate::health::{self, NET_DROPPED};
     use embassy_net::{udp::UdpSocket, IpEndpoint};
 
     pub struct DtlsSocket<'stack, 'socket> {
@@ -184,10 +375,15 @@ pub mod edtls {
             &mut self,
             buf: &'a mut [u8],
         ) -> Result<&'a mut [u8], Self::ReceiveError> {
-            // Problem: If "backend" restarts, client continues with the old keypair and thus just bounces off.
-            // I guess only a heartbeat is a solution and a timeout on a receiving side to restart the connection altogether?
-            let (n, sender_ep) = self.inner.recv_from(buf).await?;
+            // If the backend restarts the client continues with the old keys and just bounces off,
+            // `Keepalive` notices that and reconnects.
+            let (n, sender_ep) = self
+                .inner
+                .recv_from(buf)
+                .await
+                .inspect_err(|_| health::count(&NET_DROPPED))?;
             if self.endpoint != sender_ep {
+                health::count(&NET_DROPPED);
                 return Err(RecvError::UnexpectedSender(sender_ep));
             }
             Ok(&mut buf[..n])
@@ -198,7 +394,10 @@ pub mod edtls {
         type SendError = embassy_net::udp::SendError;
 
         async fn send(&mut self, buf: &[u8]) -> Result<(), Self::SendError> {
-            self.inner.send_to(buf, self.endpoint).await
+            self.inner
+                .send_to(buf, self.endpoint)
+                .await
+                .inspect_err(|_| health::count(&NET_DROPPED))
         }
     }
 }
@@ -0,0 +1,245 @@
+use crate::{
+    app,
+    message::{self, Message},
+};
+use rpc_definition::{
+    crc::Crc32,
+    endpoints::firmware_update::{
+        FinishUpdateEndpoint, FirmwareVersion, StartUpdate, StartUpdateEndpoint, UpdateError,
+        UpdateProgress, UpdateResponse, WriteChunk, WriteChunkEndpoint, CHUNK_SIZE,
+    },
+    postcard_rpc::{Endpoint, Key},
+};
+use rpc_testing::bsp::Flash;
+use rtic_sync::channel::{Receiver, Sender};
+
+/// Start of the update slot, as an offset from the start of flash. See `memory.x`.
+const SLOT_OFFSET: u32 = 0x0006_0000;
+/// Size of the update slot, sectors 7 to 9.
+const SLOT_SIZE: u32 = 384 * 1024;
+/// Size of the marker at the end of the slot.
+const MARKER_SIZE: u32 = 16;
+/// Magic number of the marker of a verified image in the slot.
+const UPDATE_MAGIC: u32 = 0x5550_4454;
+
+/// Version of this firmware, from `Cargo.toml`.
+pub const FIRMWARE_VERSION: FirmwareVersion = FirmwareVersion {
+    major: parse_u16(env!("CARGO_PKG_VERSION_MAJOR")),
+    minor: parse_u16(env!("CARGO_PKG_VERSION_MINOR")),
+    patch: parse_u16(env!("CARGO_PKG_VERSION_PATCH")),
+};
+
+/// Firmware update requests, forwarded from the dispatcher.
+pub enum UpdateCommand {
+    Start(StartUpdate),
+    Chunk(WriteChunk),
+    Finish,
+}
+
+/// Task executing firmware update commands.
+///
+/// Flash operations are slow, erasing the slot takes seconds, so they are kept out of the
+/// dispatcher. The progress is kept in RAM, so a connection that drops during an update resumes
+/// where it stopped, while a reboot starts the update over.
+///
+/// There is no bootloader to install the image, the application is linked at the start of flash.
+/// A verified image is only staged in the slot, marked so that starting the same update again,
+/// also after a reboot, answers `Staged` without transferring it again.
+pub async fn handle_firmware_update(
+    cx: app::handle_firmware_update::Context<'_>,
+    mut update_command_receiver: Receiver<'static, (u32, UpdateCommand), 2>,
+    mut ethernet_tx_sender: Sender<'static, Message, 1>,
+) -> ! {
+    let flash = *cx.shared.flash;
+    let mut update: Option<UpdateProgress> = None;
+
+    loop {
+        let (seq_no, command) = update_command_receiver.recv().await.unwrap();
+        // The flash is shared with the configuration store.
+        let mut flash = flash.access().await;
+
+        let (key, response) = match command {
+            UpdateCommand::Start(image) => (
+                StartUpdateEndpoint::RESP_KEY,
+                start(&mut flash, &mut update, image).await,
+            ),
+            UpdateCommand::Chunk(chunk) => (
+                WriteChunkEndpoint::RESP_KEY,
+                write_chunk(&mut flash, &mut update, chunk).await,
+            ),
+            UpdateCommand::Finish => (
+                FinishUpdateEndpoint::RESP_KEY,
+                finish(&mut flash, &mut update).await,
+            ),
+        };
+        drop(flash);
+
+        update_response(seq_no, key, &response, &mut ethernet_tx_sender).await;
+    }
+}
+
+/// Start a new update, or resume the current one if it's for the same image.
+async fn start(
+    flash: &mut Flash,
+    update: &mut Option<UpdateProgress>,
+    image: StartUpdate,
+) -> UpdateResponse {
+    if staged(flash, &image) {
+        defmt::info!("Firmware {} is already staged", image.version);
+        return UpdateResponse::Staged;
+    }
+
+    if let Some(current) = update {
+        if current.resumes(&image) {
+            defmt::info!("Resuming firmware update at {}", current.next_offset);
+            return UpdateResponse::Progress(current.next_offset);
+        }
+    }
+
+    if image.size > SLOT_SIZE - MARKER_SIZE {
+        return UpdateResponse::Error(UpdateError::TooLarge);
+    }
+
+    defmt::info!("Starting firmware update to {}", image.version);
+    *update = None;
+
+    if let Err(e) = flash.erase(SLOT_OFFSET, SLOT_OFFSET + SLOT_SIZE).await {
+        defmt::error!("Failed to erase the update slot: {}", e);
+        return UpdateResponse::Error(UpdateError::Flash);
+    }
+
+    *update = Some(UpdateProgress::new(image));
+
+    UpdateResponse::Progress(0)
+}
+
+/// Write a chunk of the image, if it's the one we expect next.
+async fn write_chunk(
+    flash: &mut Flash,
+    update: &mut Option<UpdateProgress>,
+    chunk: WriteChunk,
+) -> UpdateResponse {
+    let Some(current) = update else {
+        return UpdateResponse::Error(UpdateError::NotStarted);
+    };
+
+    if let Err(response) = current.check(&chunk) {
+        return response;
+    }
+
+    // Writes must be a multiple of the flash write size, pad the last chunk.
+    let mut data = [0xff; CHUNK_SIZE];
+    data[..chunk.data.len()].copy_from_slice(&chunk.data);
+    let padded_len = chunk
+        .data
+        .len()
+        .next_multiple_of(embassy_stm32::flash::WRITE_SIZE);
+
+    if let Err(e) = flash
+        .write(SLOT_OFFSET + chunk.offset, &data[..padded_len])
+        .await
+    {
+        defmt::error!("Failed to write the update slot: {}", e);
+        return UpdateResponse::Error(UpdateError::Flash);
+    }
+
+    current.written(chunk.data.len())
+}
+
+/// Verify the full image and mark it as staged.
+async fn finish(flash: &mut Flash, update: &mut Option<UpdateProgress>) -> UpdateResponse {
+    let Some(current) = update else {
+        return UpdateResponse::Error(UpdateError::NotStarted);
+    };
+
+    if staged(flash, &current.image) {
+        return UpdateResponse::Staged;
+    }
+
+    if !current.is_complete() {
+        return UpdateResponse::Progress(current.next_offset);
+    }
+
+    let mut crc = Crc32::new();
+    let mut buf = [0; CHUNK_SIZE];
+    let mut offset = 0;
+
+    while offset < current.image.size {
+        let n = (current.image.size - offset).min(CHUNK_SIZE as u32) as usize;
+        if flash
+            .blocking_read(SLOT_OFFSET + offset, &mut buf[..n])
+            .is_err()
+        {
+            return UpdateResponse::Error(UpdateError::Flash);
+        }
+        crc = crc.update(&buf[..n]);
+        offset += n as u32;
+    }
+
+    if crc.finish() != current.image.crc {
+        defmt::error!("Firmware update image CRC mismatch, starting over");
+        *update = None;
+        return UpdateResponse::Error(UpdateError::ImageCrc);
+    }
+
+    if let Err(e) = flash
+        .write(
+            SLOT_OFFSET + SLOT_SIZE - MARKER_SIZE,
+            &marker(&current.image),
+        )
+        .await
+    {
+        defmt::error!("Failed to write the update marker: {}", e);
+        return UpdateResponse::Error(UpdateError::Flash);
+    }
+
+    defmt::warn!(
+        "Firmware {} is staged, there is no bootloader to install it",
+        current.image.version
+    );
+
+    UpdateResponse::Staged
+}
+
+/// The marker of a verified `image` in the slot.
+fn marker(image: &StartUpdate) -> [u8; MARKER_SIZE as usize] {
+    let mut marker = [0xff; MARKER_SIZE as usize];
+    marker[0..4].copy_from_slice(&UPDATE_MAGIC.to_le_bytes());
+    marker[4..8].copy_from_slice(&image.size.to_le_bytes());
+    marker[8..12].copy_from_slice(&image.crc.to_le_bytes());
+    marker
+}
+
+/// If `image` is verified and staged in the slot.
+fn staged(flash: &mut Flash, image: &StartUpdate) -> bool {
+    let mut current = [0; MARKER_SIZE as usize];
+
+    flash
+        .blocking_read(SLOT_OFFSET + SLOT_SIZE - MARKER_SIZE, &mut current)
+        .is_ok()
+        && current == marker(image)
+}
+
+/// Helper to generate a response to a firmware update call.
+async fn update_response(
+    seq_no: u32,
+    key: Key,
+    response: &UpdateResponse,
+    ethernet_tx: &mut Sender<'static, Message, 1>,
+) {
+    message::send_response(seq_no, key, response, ethernet_tx).await;
+}
+
+/// `const` parser for the version numbers from Cargo.
+const fn parse_u16(s: &str) -> u16 {
+    let bytes = s.as_bytes();
+    let mut value = 0;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        value = value * 10 + (bytes[i] - b'0') as u16;
+        i += 1;
+    }
+
+    value
+}
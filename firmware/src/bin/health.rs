@@ -0,0 +1,17 @@
+//! Counters reported in the health report of every heartbeat.
+//!
+//! The channels and the network stack don't keep count themselves, so the tasks using them do it
+//! here.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Datagrams dropped by the network stack, see `Health::net_dropped`.
+pub static NET_DROPPED: AtomicU32 = AtomicU32::new(0);
+
+/// Failed DTLS handshakes and connections closed by an error.
+pub static DTLS_ERRORS: AtomicU32 = AtomicU32::new(0);
+
+/// Count one more of `counter`.
+pub fn count(counter: &AtomicU32) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
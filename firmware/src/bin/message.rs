@@ -0,0 +1,75 @@
+//! Messages to the backend, up to `MAX_MESSAGE_SIZE` each.
+//!
+//! Every task hands its serialized messages to the Ethernet task through a channel of [`Message`],
+//! which splits the ones larger than a DTLS record in fragments. A response that doesn't fit is
+//! replaced with `FatalError::MessageTooLarge`, so the backend isn't left waiting for it.
+
+use heapless::Vec;
+use postcard::experimental::schema::Schema;
+use rpc_definition::{
+    postcard_rpc::{self, Key},
+    wire_error::{FatalError, ERROR_KEY},
+};
+use rtic_sync::channel::Sender;
+use serde::Serialize;
+
+pub use rpc_definition::connection::MAX_MESSAGE_SIZE;
+
+/// A serialized message, header included.
+pub type Message = Vec<u8, MAX_MESSAGE_SIZE>;
+
+/// Serialize a message, `None` if it is larger than `MAX_MESSAGE_SIZE`.
+pub fn frame<T: Serialize + ?Sized + Schema>(seq_no: u32, key: Key, msg: &T) -> Option<Message> {
+    let mut frame = Message::new();
+    frame.resize_default(MAX_MESSAGE_SIZE).ok()?;
+    let used = postcard_rpc::headered::to_slice_keyed(seq_no, key, msg, &mut frame)
+        .ok()?
+        .len();
+    frame.truncate(used);
+
+    Some(frame)
+}
+
+/// Helper to send the response to a call, or `FatalError::MessageTooLarge` if it doesn't fit.
+pub async fn send_response<T: Serialize + ?Sized + Schema>(
+    seq_no: u32,
+    key: Key,
+    response: &T,
+    ethernet_tx: &mut Sender<'static, Message, 1>,
+) {
+    match frame(seq_no, key, response) {
+        Some(frame) => {
+            ethernet_tx.send(frame).await.ok();
+        }
+        None => {
+            defmt::error!("The response to {} is too large", seq_no);
+            send_error(seq_no, FatalError::MessageTooLarge, ethernet_tx).await;
+        }
+    }
+}
+
+/// Helper to send an error as the response to a call.
+pub async fn send_error(
+    seq_no: u32,
+    error: FatalError,
+    ethernet_tx: &mut Sender<'static, Message, 1>,
+) {
+    if let Some(frame) = frame(seq_no, ERROR_KEY, &error) {
+        ethernet_tx.send(frame).await.ok();
+    }
+}
+
+/// Helper to publish a topic message, there is no one to tell if it doesn't fit.
+pub async fn publish<T: Serialize + ?Sized + Schema>(
+    seq_no: u32,
+    key: Key,
+    msg: &T,
+    ethernet_tx: &mut Sender<'static, Message, 1>,
+) {
+    match frame(seq_no, key, msg) {
+        Some(frame) => {
+            ethernet_tx.send(frame).await.ok();
+        }
+        None => defmt::error!("Message {} to publish is too large", seq_no),
+    }
+}
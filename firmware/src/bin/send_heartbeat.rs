@@ -1,40 +1,61 @@
-use crate::app;
-use heapless::Vec;
+use crate::{
+    app, deferred,
+    health::{DTLS_ERRORS, NET_DROPPED},
+    message::{self, Message},
+};
+use core::sync::atomic::Ordering;
 use rpc_definition::{
-    postcard_rpc::{self, Topic},
-    topics::heartbeat::{Heartbeat, TopicHeartbeat},
+    postcard_rpc::Topic,
+    topics::heartbeat::{Health, Heartbeat, HeartbeatWithHealth, TopicHeartbeat, HEALTH_VERSION},
+};
+use rpc_testing::bsp;
+use rtic_monotonics::{
+    systick::{ExtU64, Systick},
+    Monotonic,
 };
-use rtic_monotonics::systick::{ExtU64, Systick};
 use rtic_sync::channel::Sender;
 
-/// Main UDP RX/TX data pump. Also sets up the UDP socket.
+/// Send a heartbeat with the health of the device every configured interval.
 pub async fn send_heartbeat(
-    _: app::send_heartbeat::Context<'_>,
-    mut ethernet_tx_sender: Sender<'static, Vec<u8, 128>, 1>,
+    cx: app::send_heartbeat::Context<'_>,
+    mut ethernet_tx_sender: Sender<'static, Message, 1>,
 ) -> ! {
-    let mut buf = [0; 128];
+    let interval = (cx.shared.config.heartbeat_interval_secs as u64).secs();
+    let stack = *cx.shared.network_stack;
     let mut sequence_number = 0;
 
     loop {
-        Systick::delay(2.secs()).await;
+        Systick::delay(interval).await;
 
-        let hb = Heartbeat {
-            value: 1.,
-            sequence_number,
+        let eth = bsp::ethernet_counters();
+        let deferred = deferred::stats();
+        let hb = HeartbeatWithHealth {
+            heartbeat: Heartbeat {
+                value: 1.,
+                sequence_number,
+            },
+            health: Health {
+                version: HEALTH_VERSION,
+                uptime_ms: Systick::now().duration_since_epoch().to_millis(),
+                ethernet_tx_free: u8::from(!ethernet_tx_sender.is_full()),
+                sleep_free: deferred.capacity.saturating_sub(deferred.queued) as u8,
+                eth_rx_frames: eth.rx_frames,
+                eth_tx_frames: eth.tx_frames,
+                eth_rx_errors: eth.rx_errors,
+                eth_rx_missed: eth.rx_missed,
+                net_dropped: NET_DROPPED.load(Ordering::Relaxed),
+                dtls_errors: DTLS_ERRORS.load(Ordering::Relaxed),
+                link_up: stack.is_link_up(),
+            },
         };
-        sequence_number += 1;
-        if let Ok(used) = postcard_rpc::headered::to_slice_keyed(
+        defmt::info!("Sending heartbeat {}", sequence_number);
+        message::publish(
             sequence_number,
             TopicHeartbeat::TOPIC_KEY,
             &hb,
-            &mut buf,
-        ) {
-            defmt::info!("Sending heartbeat {}", hb.sequence_number);
-
-            ethernet_tx_sender
-                .send(Vec::from_slice(used).unwrap())
-                .await
-                .ok();
-        }
+            &mut ethernet_tx_sender,
+        )
+        .await;
+        sequence_number += 1;
     }
 }
@@ -0,0 +1,72 @@
+//! Topics streamed to the backend on request.
+//!
+//! The state of the streams is shared with the simulator, only the timer and the channels are the
+//! firmware's.
+
+use crate::{
+    app,
+    clock::SystickClock,
+    message::{self, Message},
+};
+use rpc_definition::{
+    endpoints::stream::{Stream, StreamResponse},
+    postcard_rpc::{Key, Topic},
+    topics::some_data::{SomeData, TopicSomeData},
+};
+use rtic_monotonics::{systick::Systick, Monotonic};
+use rtic_sync::channel::{Receiver, Sender};
+
+pub use rpc_definition::endpoints::stream::StreamCommand;
+
+/// Task publishing the streamed topics, at the rate and for as long as the backend asks for.
+pub async fn stream_topics(
+    _: app::stream_topics::Context<'_>,
+    mut stream_command_receiver: Receiver<'static, (u32, StreamCommand), 2>,
+    mut ethernet_tx_sender: Sender<'static, Message, 1>,
+) -> ! {
+    let mut some_data = Stream::<SystickClock>::new(Systick::now());
+    let mut counter: u64 = 0;
+
+    loop {
+        let command = match some_data.next_due() {
+            Some(next) => match Systick::timeout_at(next, stream_command_receiver.recv()).await {
+                Ok(command) => Some(command.unwrap()),
+                Err(_timeout) => None,
+            },
+            None => Some(stream_command_receiver.recv().await.unwrap()),
+        };
+
+        let Some((seq_no, command)) = command else {
+            publish_some_data(counter, &mut ethernet_tx_sender).await;
+            counter += 1;
+            some_data.published(Systick::now());
+            continue;
+        };
+
+        let (key, response) = some_data.handle(command, Systick::now());
+        defmt::info!("SomeData stream after {}: {}", command, response);
+
+        stream_response(seq_no, key, &response, &mut ethernet_tx_sender).await;
+    }
+}
+
+/// Helper to publish a `SomeData` message.
+async fn publish_some_data(counter: u64, ethernet_tx: &mut Sender<'static, Message, 1>) {
+    message::publish(
+        counter as u32,
+        TopicSomeData::TOPIC_KEY,
+        &SomeData { data: counter },
+        ethernet_tx,
+    )
+    .await;
+}
+
+/// Helper to generate a response to a stream call.
+async fn stream_response(
+    seq_no: u32,
+    key: Key,
+    response: &StreamResponse,
+    ethernet_tx: &mut Sender<'static, Message, 1>,
+) {
+    message::send_response(seq_no, key, response, ethernet_tx).await;
+}
@@ -1,28 +1,44 @@
 use core::ptr::addr_of_mut;
 use core::str::FromStr;
+use core::sync::atomic::{AtomicU32, Ordering};
 use embassy_net::{DhcpConfig, Stack, StackResources};
 use embassy_stm32::eth::{Ethernet, PacketQueue};
+use embassy_stm32::flash::{self, Async};
 use embassy_stm32::peripherals::ETH;
 use embassy_stm32::rng::Rng as EmbassyRng;
 use embassy_stm32::time::Hertz;
 use embassy_stm32::{bind_interrupts, eth, peripherals, rng, Config};
 use heapless::String;
 use rand_core::RngCore;
+use rpc_definition::endpoints::{config::DeviceConfig, device_info::ResetReason};
 use rtic_monotonics::systick::Systick;
 use static_cell::StaticCell;
 
+use crate::bsp::config_store::{default_config, ConfigStore};
 use crate::bsp::ksz8863::KSZ8863SMI;
 
+pub mod config_store;
 pub mod ksz8863;
 
 bind_interrupts!(struct Irqs {
     ETH => eth::InterruptHandler;
     RNG => rng::InterruptHandler<peripherals::RNG>;
+    FLASH => flash::InterruptHandler;
 });
 
 type Device = Ethernet<'static, ETH, KSZ8863SMI>;
 pub type NetworkStack = &'static Stack<Device>;
 pub type Rng = EmbassyRng<'static, embassy_stm32::peripherals::RNG>;
+pub type Flash = flash::Flash<'static, Async>;
+
+/// What the board knows about itself, reported in the device information.
+pub struct BoardInfo {
+    pub uid: [u8; 12],
+    pub mac: [u8; 6],
+    /// The DHCP hostname.
+    pub hostname: String<32>,
+    pub reset_reason: ResetReason,
+}
 
 #[inline(never)]
 pub fn ascon_mac(id: &[u8; 12]) -> [u8; 6] {
@@ -36,8 +52,84 @@ pub fn ascon_mac(id: &[u8; 12]) -> [u8; 6] {
     dst
 }
 
+/// Read and clear the cause of the last reset.
+fn reset_reason() -> ResetReason {
+    let csr = embassy_stm32::pac::RCC.csr().read();
+
+    // The pin flag is set by every reset and a power-on reset sets the brown-out flag, so the
+    // most specific cause is checked first.
+    let reason = if csr.lpwrrstf() {
+        ResetReason::LowPower
+    } else if csr.wwdgrstf() {
+        ResetReason::WindowWatchdog
+    } else if csr.iwdgrstf() {
+        ResetReason::IndependentWatchdog
+    } else if csr.sftrstf() {
+        ResetReason::Software
+    } else if csr.porrstf() {
+        ResetReason::PowerOn
+    } else if csr.borrstf() {
+        ResetReason::BrownOut
+    } else if csr.pinrstf() {
+        ResetReason::Pin
+    } else {
+        ResetReason::Unknown
+    };
+
+    embassy_stm32::pac::RCC.csr().modify(|w| w.set_rmvf(true));
+
+    reason
+}
+
+/// Frame counters of the Ethernet MAC, they wrap around.
+pub struct EthernetCounters {
+    /// Unicast frames received without errors.
+    pub rx_frames: u32,
+    /// Frames sent without errors.
+    pub tx_frames: u32,
+    /// Frames received with CRC or alignment errors.
+    pub rx_errors: u32,
+    /// Frames dropped as no receive descriptor was free.
+    pub rx_missed: u32,
+}
+
+/// Read the frame counters of the Ethernet MAC.
+pub fn ethernet_counters() -> EthernetCounters {
+    // The missed frame counter clears on read, so it's accumulated here.
+    static RX_MISSED: AtomicU32 = AtomicU32::new(0);
+
+    let eth = embassy_stm32::pac::ETH;
+    let mmc = eth.ethernet_mmc();
+    let missed = eth.ethernet_dma().dmamfbocr().read().mfc() as u32;
+
+    EthernetCounters {
+        rx_frames: mmc.mmcrgufcr().read().rgufc(),
+        tx_frames: mmc.mmctgfcr().read().tgfc(),
+        rx_errors: mmc
+            .mmcrfcecr()
+            .read()
+            .rfcfc()
+            .wrapping_add(mmc.mmcrfaecr().read().rfaec()),
+        rx_missed: RX_MISSED
+            .fetch_add(missed, Ordering::Relaxed)
+            .wrapping_add(missed),
+    }
+}
+
 #[inline(always)]
-pub fn init(c: cortex_m::Peripherals) -> (NetworkStack, Rng) {
+pub fn init(
+    c: cortex_m::Peripherals,
+) -> (
+    NetworkStack,
+    Rng,
+    Flash,
+    ConfigStore,
+    DeviceConfig,
+    BoardInfo,
+) {
+    // Read first, the flags are cleared after reading them.
+    let reset_reason = reset_reason();
+
     // Update this for clock setup.
     let mut config = Config::default();
     {
@@ -96,11 +188,12 @@ pub fn init(c: cortex_m::Peripherals) -> (NetworkStack, Rng) {
     );
 
     // Set the hostname of the board to `rpc-<UID in hex>`.
+    let mut hostname: String<32> = String::from_str("rpc-").unwrap();
+    hostname.push_str(embassy_stm32::uid::uid_hex()).unwrap();
+
     let config = {
         let mut c = DhcpConfig::default();
-        let mut hostname = String::from_str("rpc-").unwrap();
-        hostname.push_str(embassy_stm32::uid::uid_hex()).unwrap();
-        c.hostname = Some(hostname);
+        c.hostname = Some(hostname.clone());
         embassy_net::Config::dhcpv4(c)
     };
 
@@ -121,10 +214,32 @@ pub fn init(c: cortex_m::Peripherals) -> (NetworkStack, Rng) {
         seed,
     ));
 
+    // Flash access for firmware updates and the stored configuration.
+    let mut flash = Flash::new(p.FLASH, Irqs);
+
+    let (config_store, config) = ConfigStore::load(&mut flash);
+    let config = match config {
+        Some(config) => {
+            defmt::info!("Loaded the stored configuration");
+            config
+        }
+        None => {
+            defmt::info!("No stored configuration, using defaults");
+            default_config(embassy_stm32::uid::uid())
+        }
+    };
+
     // Start the Systick monotonic.
     let systick_token = rtic_monotonics::create_systick_token!();
     Systick::start(c.SYST, 168_000_000, systick_token);
     defmt::info!("init done");
 
-    (stack, rng)
+    let board = BoardInfo {
+        uid: *embassy_stm32::uid::uid(),
+        mac: mac_addr,
+        hostname,
+        reset_reason,
+    };
+
+    (stack, rng, flash, config_store, config, board)
 }
@@ -0,0 +1,240 @@
+//! Persistent device configuration, kept in sectors 10 and 11 of the flash. See `memory.x`.
+//!
+//! Each write appends a record with a sequence number and a CRC to the active sector, the record
+//! with the highest sequence number and a valid CRC is the current configuration. When the active
+//! sector is full the other sector is erased and becomes the active one. A sector is erased once
+//! per 1024 writes this way, and a write that is cut short by a reset leaves the previous record
+//! in place.
+
+use embassy_stm32::flash::Error;
+use rpc_definition::{
+    crc::Crc32,
+    endpoints::config::DeviceConfig,
+    heapless,
+    psk::{self, UID_LEN},
+};
+
+use super::Flash;
+
+/// Start of the two sectors, as offsets from the start of flash.
+const SECTORS: [u32; 2] = [0x000C_0000, 0x000E_0000];
+/// Size of each sector.
+const SECTOR_SIZE: u32 = 128 * 1024;
+/// Every record takes up this many bytes, whatever the size of the configuration.
+const RECORD_SIZE: usize = 128;
+/// Size of the record header: magic, sequence number, length and CRC.
+const HEADER_SIZE: usize = 16;
+/// Magic number at the start of every record.
+const RECORD_MAGIC: u32 = 0x4346_4731;
+
+/// Factory master secret the default key is derived from, hex encoded. The build fails without
+/// `PSK_MASTER_SECRET`, unless the `dev` feature allows the development secret from
+/// `pc-app/psk.toml`, which is public.
+#[cfg(not(feature = "dev"))]
+const PSK_MASTER_SECRET: &str = env!(
+    "PSK_MASTER_SECRET",
+    "set PSK_MASTER_SECRET to the factory master secret, or enable the `dev` feature"
+);
+#[cfg(feature = "dev")]
+const PSK_MASTER_SECRET: &str = match option_env!("PSK_MASTER_SECRET") {
+    Some(secret) => secret,
+    None => "646576656c6f706d656e74206d617374657220736563726574",
+};
+/// Time between heartbeats until a device has been provisioned, in seconds.
+const DEFAULT_HEARTBEAT_INTERVAL_SECS: u32 = 2;
+
+/// The configuration of a device that has not been provisioned, with the PSK derived from the
+/// UID of the MCU.
+pub fn default_config(uid: &[u8; UID_LEN]) -> DeviceConfig {
+    const MASTER_SECRET: ([u8; 64], usize) = decode_hex(PSK_MASTER_SECRET);
+
+    let identity = psk::identity(uid);
+    let key = psk::derive_key(&MASTER_SECRET.0[..MASTER_SECRET.1], &identity);
+
+    DeviceConfig {
+        psk_identity: heapless::Vec::from_slice(&identity).unwrap(),
+        psk_key: heapless::Vec::from_slice(&key).unwrap(),
+        backend: None,
+        fallback_backend: None,
+        heartbeat_interval_secs: DEFAULT_HEARTBEAT_INTERVAL_SECS,
+    }
+}
+
+/// Why a configuration could not be stored.
+#[derive(defmt::Format, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum StoreError {
+    /// The configuration does not fit in a record.
+    TooLarge,
+    /// Erasing or writing the flash failed.
+    Flash(Error),
+    /// The record read back did not match its CRC.
+    Verify,
+}
+
+impl From<Error> for StoreError {
+    fn from(value: Error) -> Self {
+        Self::Flash(value)
+    }
+}
+
+/// Keeps track of where the next record goes.
+pub struct ConfigStore {
+    /// Index into `SECTORS` of the sector records are appended to.
+    active: usize,
+    /// Offset of the next free record in the active sector.
+    next: u32,
+    /// Sequence number of the latest record.
+    sequence: u32,
+}
+
+impl ConfigStore {
+    /// Find the latest configuration, `None` if the store is empty or damaged.
+    pub fn load(flash: &mut Flash) -> (Self, Option<DeviceConfig>) {
+        let mut latest: Option<(u32, usize, u32)> = None;
+        let mut ends = [0; 2];
+        let mut record = [0; RECORD_SIZE];
+
+        for (index, sector) in SECTORS.into_iter().enumerate() {
+            for offset in (0..SECTOR_SIZE).step_by(RECORD_SIZE) {
+                if flash.blocking_read(sector + offset, &mut record).is_err() {
+                    break;
+                }
+
+                // Records are appended, so the rest of the sector is free.
+                if record[..HEADER_SIZE].iter().all(|b| *b == 0xff) {
+                    break;
+                }
+
+                // Anything else is in use, even a record that was not completely written.
+                ends[index] = offset + RECORD_SIZE as u32;
+
+                if let Some((sequence, _)) = parse(&record) {
+                    if latest.map_or(true, |(latest, _, _)| sequence > latest) {
+                        latest = Some((sequence, index, offset));
+                    }
+                }
+            }
+        }
+
+        let Some((sequence, active, offset)) = latest else {
+            // Nothing stored, start over in the first sector.
+            let store = Self {
+                active: 1,
+                next: SECTOR_SIZE,
+                sequence: 0,
+            };
+            return (store, None);
+        };
+
+        let config = flash
+            .blocking_read(SECTORS[active] + offset, &mut record)
+            .ok()
+            .and_then(|_| parse(&record))
+            .and_then(|(_, payload)| postcard::from_bytes(payload).ok());
+
+        let store = Self {
+            active,
+            next: ends[active],
+            sequence,
+        };
+
+        (store, config)
+    }
+
+    /// Append a record with `config`, and read it back to check it.
+    pub async fn store(
+        &mut self,
+        flash: &mut Flash,
+        config: &DeviceConfig,
+    ) -> Result<(), StoreError> {
+        let mut record = [0xff; RECORD_SIZE];
+        let len = postcard::to_slice(config, &mut record[HEADER_SIZE..])
+            .map_err(|_| StoreError::TooLarge)?
+            .len();
+        let sequence = self.sequence + 1;
+
+        record[0..4].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+        record[4..8].copy_from_slice(&sequence.to_le_bytes());
+        record[8..12].copy_from_slice(&(len as u32).to_le_bytes());
+        let crc = record_crc(&record, len);
+        record[12..16].copy_from_slice(&crc.to_le_bytes());
+
+        if self.next + RECORD_SIZE as u32 > SECTOR_SIZE {
+            // The active sector is full, move to the other one. The latest record stays in the
+            // full sector until the new one is written.
+            let other = 1 - self.active;
+            flash
+                .erase(SECTORS[other], SECTORS[other] + SECTOR_SIZE)
+                .await?;
+            self.active = other;
+            self.next = 0;
+        }
+
+        let offset = SECTORS[self.active] + self.next;
+        // The slot is used whatever happens next.
+        self.next += RECORD_SIZE as u32;
+
+        flash.write(offset, &record).await?;
+
+        let mut read_back = [0; RECORD_SIZE];
+        flash.blocking_read(offset, &mut read_back)?;
+        if parse(&read_back).map(|(sequence, _)| sequence) != Some(sequence) {
+            return Err(StoreError::Verify);
+        }
+
+        self.sequence = sequence;
+
+        Ok(())
+    }
+}
+
+/// The sequence number and payload of a record, if it is complete and matches its CRC.
+fn parse(record: &[u8; RECORD_SIZE]) -> Option<(u32, &[u8])> {
+    let word = |i: usize| u32::from_le_bytes(record[i..i + 4].try_into().unwrap());
+
+    let len = word(8) as usize;
+    if word(0) != RECORD_MAGIC
+        || len > RECORD_SIZE - HEADER_SIZE
+        || word(12) != record_crc(record, len)
+    {
+        return None;
+    }
+
+    Some((word(4), &record[HEADER_SIZE..HEADER_SIZE + len]))
+}
+
+/// CRC of the sequence number, length and payload of a record.
+fn record_crc(record: &[u8; RECORD_SIZE], len: usize) -> u32 {
+    Crc32::new()
+        .update(&record[4..12])
+        .update(&record[HEADER_SIZE..HEADER_SIZE + len])
+        .finish()
+}
+
+/// `const` hex decoder for the master secret, an invalid secret fails the build.
+const fn decode_hex(s: &str) -> ([u8; 64], usize) {
+    const fn nibble(c: u8) -> u8 {
+        match c {
+            b'0'..=b'9' => c - b'0',
+            b'a'..=b'f' => c - b'a' + 10,
+            b'A'..=b'F' => c - b'A' + 10,
+            _ => panic!("PSK_MASTER_SECRET is not valid hex"),
+        }
+    }
+
+    let s = s.as_bytes();
+    assert!(
+        s.len() % 2 == 0 && s.len() <= 128,
+        "PSK_MASTER_SECRET must be at most 64 bytes of hex"
+    );
+
+    let mut bytes = [0; 64];
+    let mut i = 0;
+
+    while i < s.len() / 2 {
+        bytes[i] = (nibble(s[2 * i]) << 4) | nibble(s[2 * i + 1]);
+        i += 1;
+    }
+
+    (bytes, s.len() / 2)
+}
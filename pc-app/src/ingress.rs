@@ -1,12 +1,18 @@
 //! Embedded device ingress.
 //!
-//! Here the public APIs of the ingress are exposed.
-//!
-//! TODO: Is it worth trying to remove the `static` variables in this lib?
+//! Here the public APIs of the ingress are exposed. An [`Ingress`] is created with
+//! [`Ingress::builder`], and any number of them can run side by side in the same process.
 
+use credentials::PskStore;
 use rpc_definition::{postcard_rpc::host_client::HostClient, wire_error::FatalError};
-use std::net::IpAddr;
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{atomic::AtomicU32, Arc},
+    time::Duration,
+};
 use tokio::net::UdpSocket;
+use tokio_util::{sync::CancellationToken, task::task_tracker::TaskTrackerToken};
 
 // Private internals that run the communication.
 mod engine;
@@ -17,25 +23,311 @@ pub mod api;
 /// Public subscriptions to data are handled here.
 pub mod subscriptions;
 
-/// Run the device ingress.
-pub async fn run_ingress() {
-    let socket = UdpSocket::bind("0.0.0.0:8321")
-        .await
-        .expect("Unable to bind socket");
+/// Device credentials are handled here.
+pub mod credentials;
+
+pub use engine::{DeviceId, FirmwareImage, HandshakeError};
+
+/// Handle to a running device ingress.
+///
+/// The handle is cheap to clone, all clones refer to the same ingress. The RPC APIs are in
+/// [`api`] and the subscriptions in [`subscriptions`].
+#[derive(Clone)]
+pub struct Ingress {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    engine: Arc<engine::State>,
+    topics: subscriptions::Topics,
+    local_addr: SocketAddr,
+    /// Sequence number of the next message published to devices.
+    publish_seq_no: AtomicU32,
+}
+
+impl Ingress {
+    /// Start configuring a new ingress.
+    pub fn builder() -> IngressBuilder {
+        IngressBuilder::default()
+    }
+
+    /// The address the ingress is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.inner.local_addr
+    }
+
+    /// Shut the ingress down.
+    ///
+    /// New connections and calls are refused, in-flight calls get the drain timeout to complete,
+    /// then every session is closed and reported as `Closed`. Returns when all sessions are gone.
+    ///
+    /// embedded-dtls can't send a close_notify alert yet, so each device gets a `TopicClose` message
+    /// instead. It drops the connection and tries to connect again after its backoff.
+    pub async fn shutdown(&self) {
+        let engine = &self.inner.engine;
+
+        engine.shutdown.cancel();
+        engine.tasks.close();
+        engine.tasks.wait().await;
+    }
+
+    /// Helper method to get access to a specific device's API client.
+    async fn api_handle(&self, device: &DeviceId) -> Result<HostClient<FatalError>, api::ApiError> {
+        // Hold the read lock to the shared state as short as possible.
+        self.inner
+            .engine
+            .clients
+            .read()
+            .await
+            .get(device)
+            .map(|session| session.client.clone())
+            .ok_or(api::ApiError::DeviceNotFound)
+    }
+
+    /// Like `api_handle`, for a call that is drained on shutdown while the token is held.
+    async fn call_handle(
+        &self,
+        device: &DeviceId,
+    ) -> Result<
+        (
+            HostClient<FatalError>,
+            Arc<engine::PendingCalls>,
+            TaskTrackerToken,
+        ),
+        api::ApiError,
+    > {
+        let clients = self.inner.engine.clients.read().await;
+        let session = clients.get(device).ok_or(api::ApiError::DeviceNotFound)?;
+
+        if session.calls.is_closed() {
+            return Err(api::ApiError::ShuttingDown);
+        }
+
+        Ok((
+            session.client.clone(),
+            session.pending.clone(),
+            session.calls.token(),
+        ))
+    }
+}
+
+/// Builder for an [`Ingress`].
+pub struct IngressBuilder {
+    bind: SocketAddr,
+    credentials: Option<PskStore>,
+    packet_queue_depth: usize,
+    api_queue_depth: usize,
+    connection_queue_depth: usize,
+    topic_queue_depth: usize,
+    firmware_image: Option<FirmwareImage>,
+    heartbeat_interval: Duration,
+    missed_heartbeats: u32,
+    shutdown: CancellationToken,
+    drain_timeout: Duration,
+    max_handshakes: usize,
+    handshake_rate_limit: engine::RateLimit,
+    handshake_backoff: Option<Duration>,
+    announce: Option<engine::Announce>,
+}
+
+impl Default for IngressBuilder {
+    fn default() -> Self {
+        Self {
+            bind: ([0, 0, 0, 0], 8321).into(),
+            credentials: None,
+            packet_queue_depth: 10,
+            api_queue_depth: 10,
+            connection_queue_depth: 1000,
+            topic_queue_depth: 100,
+            firmware_image: None,
+            heartbeat_interval: Duration::from_secs(2),
+            missed_heartbeats: 2,
+            shutdown: CancellationToken::new(),
+            drain_timeout: Duration::from_secs(5),
+            max_handshakes: 100,
+            handshake_rate_limit: engine::RateLimit {
+                burst: 5,
+                interval: Duration::from_secs(1),
+            },
+            handshake_backoff: None,
+            announce: None,
+        }
+    }
+}
+
+impl IngressBuilder {
+    /// The address to listen on, defaults to `0.0.0.0:8321`. Use port 0 for an ephemeral port.
+    pub fn bind(mut self, addr: impl Into<SocketAddr>) -> Self {
+        self.bind = addr.into();
+        self
+    }
+
+    /// The credentials of the devices that are allowed to connect.
+    pub fn credentials(mut self, credentials: PskStore) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Depth of the queues of received and sent packets for each device, defaults to 10.
+    pub fn packet_queue_depth(mut self, depth: usize) -> Self {
+        self.packet_queue_depth = depth;
+        self
+    }
+
+    /// Depth of the queue of outgoing RPC requests for each device, defaults to 10.
+    pub fn api_queue_depth(mut self, depth: usize) -> Self {
+        self.api_queue_depth = depth;
+        self
+    }
+
+    /// Depth of the queue of connection events, defaults to 1000.
+    pub fn connection_queue_depth(mut self, depth: usize) -> Self {
+        self.connection_queue_depth = depth;
+        self
+    }
+
+    /// Depth of the queue of each topic subscription, defaults to 100.
+    pub fn topic_queue_depth(mut self, depth: usize) -> Self {
+        self.topic_queue_depth = depth;
+        self
+    }
+
+    /// The firmware devices must run. Devices with another version are updated to it before they
+    /// are reported as connected, by default devices are not checked. Devices without a bootloader
+    /// only stage the image and are reported with the firmware they run.
+    pub fn firmware_image(mut self, image: FirmwareImage) -> Self {
+        self.firmware_image = Some(image);
+        self
+    }
+
+    /// How often devices send heartbeats, defaults to 2 s as in the firmware. Must be above zero.
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.heartbeat_interval = interval;
+        self
+    }
+
+    /// Number of heartbeats in a row a device can miss before it's reported as stale, defaults
+    /// to 2. A session without any traffic for 5 s is closed, so only a threshold below that
+    /// gives a `Stale` event before the `Closed` one. Must be above zero.
+    pub fn missed_heartbeats(mut self, missed: u32) -> Self {
+        self.missed_heartbeats = missed;
+        self
+    }
+
+    /// Shut the ingress down when this token is cancelled, e.g. a token shared with the rest of
+    /// the application. This starts the same shutdown as [`Ingress::shutdown`], without waiting
+    /// for it to complete.
+    pub fn shutdown_token(mut self, token: CancellationToken) -> Self {
+        self.shutdown = token;
+        self
+    }
+
+    /// How long in-flight calls may take to complete when shutting down, defaults to 5 s.
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Maximum number of DTLS handshakes in progress at once, defaults to 100. A handshake holds
+    /// its buffers until it completes or times out, new sources beyond this are dropped.
+    pub fn max_concurrent_handshakes(mut self, max: usize) -> Self {
+        self.max_handshakes = max;
+        self
+    }
+
+    /// Each source IP can start `burst` handshakes at once, then one more every `interval`.
+    /// Defaults to 5 and 1 s. Devices behind the same NAT share the limit.
+    pub fn handshake_rate_limit(mut self, burst: u32, interval: Duration) -> Self {
+        self.handshake_rate_limit = engine::RateLimit { burst, interval };
+        self
+    }
+
+    /// Ignore a source IP for this long after a failed handshake, e.g. a device with the wrong
+    /// key. Off by default, devices behind the same NAT share the IP.
+    pub fn handshake_backoff(mut self, backoff: Duration) -> Self {
+        self.handshake_backoff = Some(backoff);
+        self
+    }
+
+    /// Announce the ingress to `target` every `interval`, so devices on a new network can find it
+    /// without being reflashed. Usually the broadcast address and
+    /// [`DISCOVERY_PORT`](rpc_definition::discovery::DISCOVERY_PORT), off by default.
+    pub fn announce(mut self, target: impl Into<SocketAddr>, interval: Duration) -> Self {
+        self.announce = Some(engine::Announce {
+            target: target.into(),
+            interval,
+        });
+        self
+    }
+
+    /// Bind the socket and start the ingress in the background.
+    ///
+    /// The ingress runs until it's shut down, or the tokio runtime is.
+    pub async fn build(self) -> Result<Ingress, BuildError> {
+        let credentials = self.credentials.ok_or(BuildError::MissingCredentials)?;
+        if self.heartbeat_interval.is_zero() || self.missed_heartbeats == 0 {
+            return Err(BuildError::InvalidHeartbeat);
+        }
+        let socket = UdpSocket::bind(self.bind).await?;
+        let local_addr = socket.local_addr()?;
+
+        let engine = Arc::new(engine::State::new(
+            socket,
+            credentials,
+            self.connection_queue_depth,
+            self.shutdown,
+            engine::Config {
+                packet_queue_depth: self.packet_queue_depth,
+                api_queue_depth: self.api_queue_depth,
+                firmware_image: self.firmware_image,
+                heartbeat_interval: self.heartbeat_interval,
+                missed_heartbeats: self.missed_heartbeats,
+                drain_timeout: self.drain_timeout,
+                max_handshakes: self.max_handshakes,
+                handshake_rate_limit: self.handshake_rate_limit,
+                handshake_backoff: self.handshake_backoff,
+                announce: self.announce,
+            },
+        ));
+
+        let ingress = Ingress {
+            inner: Arc::new(Inner {
+                engine: engine.clone(),
+                topics: subscriptions::Topics::new(self.topic_queue_depth),
+                local_addr,
+                publish_seq_no: AtomicU32::new(0),
+            }),
+        };
+
+        let consolidation = ingress.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = subscriptions::subscription_consolidation(consolidation) => {}
+                _ = engine::udp_listener(engine.clone()) => {}
+                _ = engine.credentials.watch_file() => {}
+                _ = engine::announcer(engine.clone()) => {}
+                // The listener keeps running until the workers have closed their sessions.
+                _ = async {
+                    engine.shutdown.cancelled().await;
+                    engine.tasks.close();
+                    engine.tasks.wait().await;
+                } => {
+                    log::debug!("Ingress on {local_addr} has shut down");
+                }
+            }
+        });
 
-    tokio::select! {
-        _ = subscriptions::subscription_consolidation() => {}
-        _ = engine::udp_listener(socket) => {}
+        Ok(ingress)
     }
 }
 
-/// Helper method to get access to a specific device's API client.
-async fn api_handle(device: &IpAddr) -> Result<HostClient<FatalError>, api::ApiError> {
-    // Hold the read lock to the global state as short as possible.
-    engine::API_CLIENTS
-        .read()
-        .await
-        .get(&device)
-        .ok_or(api::ApiError::IpNotFound)
-        .cloned()
+/// Errors when starting an ingress.
+#[derive(Debug, thiserror::Error)]
+pub enum BuildError {
+    #[error("no credentials were given")]
+    MissingCredentials,
+    #[error("the heartbeat interval and the missed heartbeats must be above zero")]
+    InvalidHeartbeat,
+    #[error("unable to bind the socket: {0}")]
+    Bind(#[from] io::Error),
 }
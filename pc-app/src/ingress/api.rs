@@ -1,70 +1,379 @@
-use super::api_handle;
+use super::{DeviceId, HandshakeError, Ingress};
+
+pub use super::engine::{ConnectedDevice, DeviceHealth};
+use log::*;
+use postcard::experimental::schema::Schema;
 use rpc_definition::{
     endpoints::{
+        deferred::{DeferredStats, DeferredStatsEndpoint, GetDeferredStats},
+        device_info::{DeviceInfo, DeviceInfoEndpoint, GetDeviceInfo},
         pingpong::{Ping, PingPongEndpoint},
         sleep::{Sleep, SleepDone, SleepEndpoint},
+        stream::{
+            SetStreamRate, SetStreamRateEndpoint, StartStream, StartStreamEndpoint, StopStream,
+            StopStreamEndpoint, StreamResponse, StreamStatus, StreamTopic,
+        },
     },
-    postcard_rpc::host_client::HostErr,
+    fragment::MAX_FRAGMENTED_SIZE,
+    postcard_rpc::{host_client::HostErr, Endpoint, Topic},
     wire_error::FatalError,
 };
-use std::{future::Future, net::IpAddr, time::Duration};
-use tokio::time::timeout;
-
-// TODO: Do retries.
-
-/// Example public API endpoint.
-///
-/// This will make the MCU server wait the requested time before answering.
-pub async fn sleep(device: IpAddr, sleep: Duration) -> Result<SleepDone, ApiError> {
-    let sleep_cmd = Sleep {
-        seconds: sleep.as_secs() as u32,
-        micros: sleep.subsec_micros(),
-    };
-    let api = api_handle(&device).await?;
-
-    timeout_helper(
-        api.send_resp::<SleepEndpoint>(&sleep_cmd),
-        sleep + Duration::from_secs(1),
-    )
-    .await
+use serde::{de::DeserializeOwned, Serialize};
+use std::{collections::BTreeMap, net::IpAddr, sync::atomic::Ordering, time::Duration};
+use tokio::time::{timeout, Instant};
+
+/// Largest header of a message, the key and a sequence number with all bits in use.
+const MAX_HEADER_SIZE: usize = 8 + 5;
+
+/// Options of a single [`Ingress::call`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CallOptions {
+    timeout: Duration,
+    retries: usize,
+    backoff: Duration,
+    deadline: Option<Instant>,
 }
 
-/// Example public API endpoint.
-///
-/// This will perform a ping/pong exchange with the device.
-pub async fn ping(device: IpAddr) -> Result<(), ApiError> {
-    let api = api_handle(&device).await?;
-
-    timeout_helper(
-        api.send_resp::<PingPongEndpoint>(&Ping {}),
-        Duration::from_secs(1),
-    )
-    .await
-    .map(|_pong| ())
+impl Default for CallOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(1),
+            retries: 0,
+            backoff: Duration::from_millis(100),
+            deadline: None,
+        }
+    }
 }
 
-async fn timeout_helper<F, T>(f: F, timeout_after: Duration) -> Result<T, ApiError>
-where
-    F: Future<Output = Result<T, HostErr<FatalError>>>,
-{
-    // TODO: Settable timeout, always have in public API? Seems not nice...
-    timeout(timeout_after, f)
+impl CallOptions {
+    /// Timeout of each attempt, defaults to 1 s.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Number of retries after an attempt got no response or the device was busy, defaults to 0.
+    ///
+    /// A retry sends the request again, only use this for requests that are safe to repeat. It
+    /// goes to the session the device has at that point, also if it reconnected in between.
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Wait before the first retry, doubled for every following retry. Defaults to 100 ms.
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Give up at this point in time, regardless of the timeout and retries left.
+    pub fn deadline(mut self, deadline: impl Into<Instant>) -> Self {
+        self.deadline = Some(deadline.into());
+        self
+    }
+
+    /// Timeout of the next attempt, `None` if the deadline has passed.
+    fn attempt_timeout(&self) -> Option<Duration> {
+        match self.deadline {
+            Some(deadline) => {
+                let left = deadline.checked_duration_since(Instant::now())?;
+                (!left.is_zero()).then_some(left.min(self.timeout))
+            }
+            None => Some(self.timeout),
+        }
+    }
+}
+
+impl Ingress {
+    /// Call any endpoint of a device.
+    ///
+    /// Only attempts that got no response are retried, or that the device was too busy for, after
+    /// the time it asked for. Other errors reported by the device are returned directly. A request
+    /// too large to be sent fails with `ApiError::TooLarge` right away.
+    pub async fn call<E>(
+        &self,
+        device: &DeviceId,
+        request: &E::Request,
+        options: CallOptions,
+    ) -> Result<E::Response, ApiError>
+    where
+        E: Endpoint,
+        E::Request: Serialize + Schema,
+        E::Response: DeserializeOwned + Schema,
+    {
+        check_size(request)?;
+
+        let mut backoff = options.backoff;
+        let mut attempt = 0;
+
+        loop {
+            let timeout_after = options.attempt_timeout().ok_or(ApiError::NoResponse)?;
+
+            // The device may have reconnected since the last attempt, always use its current
+            // session.
+            let (api, pending, _in_flight) = self.call_handle(device).await?;
+
+            let result = timeout(timeout_after, pending.call::<E>(&api, request))
+                .await
+                .map_err(|_timeout| ApiError::NoResponse)
+                .and_then(|r| r.map_err(Into::into));
+
+            let (wait, error) = match result {
+                Err(ApiError::NoResponse) if attempt < options.retries => {
+                    attempt += 1;
+                    debug!("{device}: No response on {}, retry {attempt}", E::PATH);
+
+                    let wait = backoff;
+                    backoff *= 2;
+                    (wait, ApiError::NoResponse)
+                }
+                Err(busy @ ApiError::Busy { retry_after }) if attempt < options.retries => {
+                    attempt += 1;
+                    debug!(
+                        "{device}: Busy on {}, retry {attempt} in {retry_after:?}",
+                        E::PATH
+                    );
+
+                    (retry_after, busy)
+                }
+                result => return result,
+            };
+
+            let wait_until = Instant::now() + wait;
+            if options
+                .deadline
+                .is_some_and(|deadline| wait_until >= deadline)
+            {
+                return Err(error);
+            }
+            tokio::time::sleep_until(wait_until).await;
+        }
+    }
+
+    /// Publish a message on a topic to a device.
+    ///
+    /// This is fire-and-forget, the device does not answer so there is no round trip, and no way
+    /// to tell whether the message arrived. A message too large to be sent fails with
+    /// `ApiError::TooLarge`.
+    pub async fn publish<T>(&self, device: &DeviceId, msg: &T::Message) -> Result<(), ApiError>
+    where
+        T: Topic,
+        T::Message: Serialize,
+    {
+        check_size(msg)?;
+
+        let api = self.api_handle(device).await?;
+        let seq_no = self.inner.publish_seq_no.fetch_add(1, Ordering::Relaxed);
+
+        api.publish::<T>(seq_no, msg)
+            .await
+            .map_err(|_closed| ApiError::NoResponse)
+    }
+
+    /// Publish a message on a topic to all connected devices, returns the number of devices it
+    /// was sent to. A message too large to be sent goes to none.
+    pub async fn publish_all<T>(&self, msg: &T::Message) -> usize
+    where
+        T: Topic,
+        T::Message: Serialize,
+    {
+        if check_size(msg).is_err() {
+            warn!("Message to publish on {} is too large to send", T::PATH);
+            return 0;
+        }
+
+        // Don't hold the lock while sending.
+        let clients: Vec<_> = self
+            .inner
+            .engine
+            .clients
+            .read()
+            .await
+            .values()
+            .map(|session| session.client.clone())
+            .collect();
+        let seq_no = self.inner.publish_seq_no.fetch_add(1, Ordering::Relaxed);
+
+        let mut sent = 0;
+        for api in clients {
+            if api.publish::<T>(seq_no, msg).await.is_ok() {
+                sent += 1;
+            }
+        }
+
+        sent
+    }
+
+    /// Example public API endpoint.
+    ///
+    /// This will make the MCU server wait the requested time before answering.
+    pub async fn sleep(&self, device: &DeviceId, sleep: Duration) -> Result<SleepDone, ApiError> {
+        let sleep_cmd = Sleep {
+            seconds: sleep.as_secs() as u32,
+            micros: sleep.subsec_micros(),
+        };
+
+        self.call::<SleepEndpoint>(
+            device,
+            &sleep_cmd,
+            CallOptions::default().timeout(sleep + Duration::from_secs(1)),
+        )
+        .await
+    }
+
+    /// Example public API endpoint.
+    ///
+    /// This will perform a ping/pong exchange with the device.
+    pub async fn ping(&self, device: &DeviceId) -> Result<(), ApiError> {
+        self.call::<PingPongEndpoint>(device, &Ping {}, CallOptions::default())
+            .await
+            .map(|_pong| ())
+    }
+
+    /// Get the identity, firmware, uptime and last reset reason of a device.
+    pub async fn device_info(&self, device: &DeviceId) -> Result<DeviceInfo, ApiError> {
+        self.call::<DeviceInfoEndpoint>(
+            device,
+            &GetDeviceInfo {},
+            CallOptions::default().retries(2),
+        )
         .await
-        .map_err(|_timeout| ApiError::NoResponse)?
-        .map_err(Into::into)
+    }
+
+    /// Get how full the deferred responses of a device are, such as those of `Sleep`.
+    pub async fn deferred_stats(&self, device: &DeviceId) -> Result<DeferredStats, ApiError> {
+        self.call::<DeferredStatsEndpoint>(
+            device,
+            &GetDeferredStats {},
+            CallOptions::default().retries(2),
+        )
+        .await
+    }
+
+    /// Start streaming a topic from a device, at the rate last set.
+    pub async fn start_stream(
+        &self,
+        device: &DeviceId,
+        topic: StreamTopic,
+    ) -> Result<StreamStatus, ApiError> {
+        let response = self
+            .call::<StartStreamEndpoint>(device, &StartStream { topic }, CallOptions::default())
+            .await?;
+
+        stream_status(response)
+    }
+
+    /// Stop streaming a topic from a device.
+    pub async fn stop_stream(
+        &self,
+        device: &DeviceId,
+        topic: StreamTopic,
+    ) -> Result<StreamStatus, ApiError> {
+        let response = self
+            .call::<StopStreamEndpoint>(device, &StopStream { topic }, CallOptions::default())
+            .await?;
+
+        stream_status(response)
+    }
+
+    /// Set the rate a device streams a topic at, in messages per second. This does not start or
+    /// stop the stream.
+    pub async fn set_stream_rate(
+        &self,
+        device: &DeviceId,
+        topic: StreamTopic,
+        hz: u32,
+    ) -> Result<StreamStatus, ApiError> {
+        let response = self
+            .call::<SetStreamRateEndpoint>(
+                device,
+                &SetStreamRate { topic, hz },
+                CallOptions::default(),
+            )
+            .await?;
+
+        stream_status(response)
+    }
+
+    /// The currently connected devices.
+    pub async fn devices(&self) -> Vec<ConnectedDevice> {
+        self.inner
+            .engine
+            .clients
+            .read()
+            .await
+            .iter()
+            .map(|(device, session)| session.stats.snapshot(device, session.ip))
+            .collect()
+    }
+
+    /// The heartbeat health of a device, `None` if it has never sent a heartbeat.
+    pub fn health(&self, device: &DeviceId) -> Option<DeviceHealth> {
+        self.inner.engine.health.get(device)
+    }
+
+    /// Number of failed or refused DTLS handshakes per reason since the ingress started.
+    pub fn handshake_failures(&self) -> BTreeMap<HandshakeError, u64> {
+        self.inner.engine.handshake_failures.snapshot()
+    }
+
+    /// Get the IP a device is currently connected from.
+    ///
+    /// This is metadata only, the IP of a device can change between connections.
+    pub async fn ip_address(&self, device: &DeviceId) -> Result<IpAddr, ApiError> {
+        self.inner
+            .engine
+            .clients
+            .read()
+            .await
+            .get(device)
+            .map(|session| session.ip)
+            .ok_or(ApiError::DeviceNotFound)
+    }
 }
 
 /// Errors of the public API.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum ApiError {
-    IpNotFound,
+    DeviceNotFound,
     NoResponse,
+    /// The ingress is shutting down and takes no new calls.
+    ShuttingDown,
     // Unsure if the ones below should be log::warn/error instead of be given to the user.
     // Not sure if a user really can do anything with them.
     BadResponse,
     Malformed,
     TooManyConcurrentApiCalls,
     Unimplemented,
+    /// The device refused the request, e.g. as an argument is out of range.
+    Rejected,
+    /// The device has no room for the call right now, and asked to retry after a while.
+    Busy {
+        retry_after: Duration,
+    },
+    /// The request, or the response to it, is larger than the device or the ingress can handle.
+    TooLarge,
+}
+
+/// Refuse a message that does not fit in the fragments of one frame, the RPC worker can't send it.
+fn check_size<T: Serialize + ?Sized>(msg: &T) -> Result<(), ApiError> {
+    match postcard::experimental::serialized_size(msg) {
+        Ok(size) if size + MAX_HEADER_SIZE <= MAX_FRAGMENTED_SIZE => Ok(()),
+        Ok(_) => Err(ApiError::TooLarge),
+        Err(_) => Err(ApiError::Malformed),
+    }
+}
+
+/// The status of a stream, or why the device refused the request.
+fn stream_status(response: StreamResponse) -> Result<StreamStatus, ApiError> {
+    match response {
+        StreamResponse::Status(status) => Ok(status),
+        StreamResponse::Error(e) => {
+            debug!("Stream request rejected: {e:?}");
+            Err(ApiError::Rejected)
+        }
+    }
 }
 
 /// Auto-convert from internal communication errors to user understandable errors.
@@ -75,6 +384,10 @@ impl From<HostErr<FatalError>> for ApiError {
                 FatalError::UnknownEndpoint => ApiError::Unimplemented,
                 FatalError::NotEnoughSenders => ApiError::TooManyConcurrentApiCalls,
                 FatalError::WireFailure => ApiError::Malformed,
+                FatalError::Busy(retry_after_ms) => ApiError::Busy {
+                    retry_after: Duration::from_millis(retry_after_ms as u64),
+                },
+                FatalError::MessageTooLarge => ApiError::TooLarge,
             },
             HostErr::BadResponse => ApiError::BadResponse,
             HostErr::Postcard(_) => ApiError::Malformed,
@@ -0,0 +1,330 @@
+//! Per-device pre-shared keys, loaded from a TOML or JSON file.
+//!
+//! The file lists one entry per device, and optionally the factory master secret:
+//!
+//! ```toml
+//! master_secret = "6d6173746572"
+//!
+//! [[device]]
+//! identity = "hello world"
+//! key = "313131313132333435363738393071776572747975696f706173646667686a6b6c7a7863"
+//! name = "Lab board"
+//! tags = ["lab"]
+//! ```
+//!
+//! Devices with an identity derived from their UID (see [`rpc_definition::psk`]) don't need an
+//! entry, their key is derived from the master secret when they connect. A listed entry takes
+//! precedence over the derived key.
+//!
+//! The file is watched for changes, and a changed or removed entry tears down any live session
+//! that was established with the old key.
+
+use super::DeviceId;
+use log::*;
+use rpc_definition::psk;
+use rustc_hash::FxHashMap;
+use serde::Deserialize;
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::sync::watch;
+
+/// How often the PSK file is checked for modifications.
+const RELOAD_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Credentials and metadata of a single device.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceCredential {
+    /// The DTLS PSK identity, this is also the device's [`DeviceId`].
+    pub identity: DeviceId,
+    /// The pre-shared key.
+    pub key: Vec<u8>,
+    /// Optional human readable name.
+    pub name: Option<String>,
+    /// Optional tags.
+    pub tags: Vec<String>,
+}
+
+/// A snapshot of all known credentials.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PskTable {
+    devices: FxHashMap<DeviceId, DeviceCredential>,
+    master_secret: Option<Vec<u8>>,
+}
+
+impl PskTable {
+    /// Look up the credential of a PSK identity, derived from the master secret if it is not
+    /// listed.
+    pub fn get(&self, identity: &[u8]) -> Option<DeviceCredential> {
+        if let Some(credential) = self.devices.get(&DeviceId::from(identity)) {
+            return Some(credential.clone());
+        }
+
+        let master_secret = self.master_secret.as_ref()?;
+        if !psk::is_derived(identity) {
+            return None;
+        }
+
+        Some(DeviceCredential {
+            identity: DeviceId::from(identity),
+            key: psk::derive_key(master_secret, identity).to_vec(),
+            name: None,
+            tags: Vec::new(),
+        })
+    }
+
+    /// Iterate over all listed credentials, derived ones are not included.
+    pub fn iter(&self) -> impl Iterator<Item = &DeviceCredential> {
+        self.devices.values()
+    }
+
+    /// Parse a PSK table, the format is chosen based on the file extension.
+    pub fn parse(path: &Path, contents: &str) -> Result<Self, CredentialsError> {
+        let file: PskFile = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => serde_json::from_str(contents)?,
+            _ => toml::from_str(contents)?,
+        };
+
+        let master_secret = file
+            .master_secret
+            .map(|secret| decode_hex(&secret).ok_or(CredentialsError::InvalidMasterSecret))
+            .transpose()?;
+
+        let mut devices = FxHashMap::default();
+
+        for entry in file.device {
+            let identity = DeviceId::from(entry.identity.as_str());
+            let key = decode_hex(&entry.key).ok_or_else(|| CredentialsError::InvalidKey {
+                identity: entry.identity.clone(),
+            })?;
+
+            let credential = DeviceCredential {
+                identity: identity.clone(),
+                key,
+                name: entry.name,
+                tags: entry.tags,
+            };
+
+            if devices.insert(identity, credential).is_some() {
+                return Err(CredentialsError::DuplicateIdentity(entry.identity));
+            }
+        }
+
+        Ok(Self {
+            devices,
+            master_secret,
+        })
+    }
+}
+
+/// A hot reloadable store of device credentials.
+#[derive(Clone)]
+pub struct PskStore {
+    path: PathBuf,
+    table: Arc<watch::Sender<Arc<PskTable>>>,
+}
+
+impl PskStore {
+    /// Load the credentials from a file.
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, CredentialsError> {
+        let path = path.into();
+        let table = PskTable::parse(&path, &std::fs::read_to_string(&path)?)?;
+
+        Ok(Self {
+            path,
+            table: Arc::new(watch::channel(Arc::new(table)).0),
+        })
+    }
+
+    /// The current credentials.
+    pub fn current(&self) -> Arc<PskTable> {
+        self.table.borrow().clone()
+    }
+
+    /// Get notified on every change of the credentials.
+    pub fn subscribe(&self) -> watch::Receiver<Arc<PskTable>> {
+        self.table.subscribe()
+    }
+
+    /// Re-read the credentials file. On error the current credentials are kept.
+    pub async fn reload(&self) -> Result<(), CredentialsError> {
+        let contents = tokio::fs::read_to_string(&self.path).await?;
+        let table = PskTable::parse(&self.path, &contents)?;
+
+        self.table.send_if_modified(|current| {
+            if **current == table {
+                return false;
+            }
+
+            info!(
+                "Reloaded {} credentials from {}",
+                table.devices.len(),
+                self.path.display()
+            );
+            *current = Arc::new(table);
+            true
+        });
+
+        Ok(())
+    }
+
+    /// Watch the credentials file and reload it when it's modified.
+    pub(crate) async fn watch_file(&self) -> ! {
+        let mut last_modified = self.modified().await;
+        let mut interval = tokio::time::interval(RELOAD_POLL_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let modified = self.modified().await;
+            if modified == last_modified {
+                continue;
+            }
+            last_modified = modified;
+
+            if let Err(e) = self.reload().await {
+                error!("Failed to reload {}: {e}", self.path.display());
+            }
+        }
+    }
+
+    async fn modified(&self) -> Option<SystemTime> {
+        tokio::fs::metadata(&self.path)
+            .await
+            .and_then(|m| m.modified())
+            .ok()
+    }
+}
+
+/// Errors when loading credentials.
+#[derive(Debug, thiserror::Error)]
+pub enum CredentialsError {
+    #[error("unable to read the credentials file: {0}")]
+    Io(#[from] io::Error),
+    #[error("malformed TOML: {0}")]
+    Toml(#[from] toml::de::Error),
+    #[error("malformed JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("the key of '{identity}' is empty or not valid hex")]
+    InvalidKey { identity: String },
+    #[error("the master secret is empty or not valid hex")]
+    InvalidMasterSecret,
+    #[error("the identity '{0}' is listed more than once")]
+    DuplicateIdentity(String),
+}
+
+/// On-disk format of the credentials file.
+#[derive(Deserialize)]
+struct PskFile {
+    master_secret: Option<String>,
+    #[serde(default)]
+    device: Vec<PskFileEntry>,
+}
+
+#[derive(Deserialize)]
+struct PskFileEntry {
+    identity: String,
+    key: String,
+    name: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+/// Decode a key or secret, which can't be empty.
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    // `from_str_radix` would also accept a sign.
+    if s.is_empty() || !s.len().is_multiple_of(2) || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(contents: &str) -> Result<PskTable, CredentialsError> {
+        PskTable::parse(Path::new("psk.toml"), contents)
+    }
+
+    #[test]
+    fn hex_is_decoded() {
+        assert_eq!(decode_hex("00ff7A"), Some(vec![0x00, 0xff, 0x7a]));
+
+        assert_eq!(decode_hex(""), None);
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("zz"), None);
+        assert_eq!(decode_hex("+1"), None);
+        assert_eq!(decode_hex("0x00"), None);
+        assert_eq!(decode_hex("éé"), None);
+    }
+
+    #[test]
+    fn entries_are_parsed() {
+        let table = parse(
+            r#"
+            master_secret = "6d6173746572"
+
+            [[device]]
+            identity = "sim-0"
+            key = "0102"
+            name = "Lab board"
+            tags = ["lab"]
+            "#,
+        )
+        .unwrap();
+
+        let credential = table.get(b"sim-0").unwrap();
+        assert_eq!(credential.key, [1, 2]);
+        assert_eq!(credential.name.as_deref(), Some("Lab board"));
+        assert_eq!(credential.tags, ["lab"]);
+        assert_eq!(table.master_secret.as_deref(), Some(&b"master"[..]));
+
+        let json = PskTable::parse(
+            Path::new("psk.json"),
+            r#"{ "device": [{ "identity": "sim-0", "key": "0102" }] }"#,
+        )
+        .unwrap();
+        assert_eq!(json.get(b"sim-0").unwrap().key, [1, 2]);
+    }
+
+    #[test]
+    fn invalid_entries_are_refused() {
+        let bad_hex = parse("[[device]]\nidentity = \"sim-0\"\nkey = \"0g\"\n");
+        assert!(
+            matches!(bad_hex, Err(CredentialsError::InvalidKey { identity }) if identity == "sim-0")
+        );
+
+        let empty_key = parse("[[device]]\nidentity = \"sim-0\"\nkey = \"\"\n");
+        assert!(matches!(
+            empty_key,
+            Err(CredentialsError::InvalidKey { .. })
+        ));
+
+        let duplicate = parse(
+            "[[device]]\nidentity = \"sim-0\"\nkey = \"01\"\n\
+             [[device]]\nidentity = \"sim-0\"\nkey = \"02\"\n",
+        );
+        assert!(
+            matches!(duplicate, Err(CredentialsError::DuplicateIdentity(identity)) if identity == "sim-0")
+        );
+
+        let empty_secret = parse("master_secret = \"\"\n");
+        assert!(matches!(
+            empty_secret,
+            Err(CredentialsError::InvalidMasterSecret)
+        ));
+
+        assert!(matches!(
+            parse("[[device]]\nidentity = \"sim-0\"\n"),
+            Err(CredentialsError::Toml(_))
+        ));
+    }
+}
@@ -1,7 +1,8 @@
 //! The engine drives all communication.
 //!
-//! Note: This app IP as identifier for each device. You should not do that when running UDP
-//! unless you have authentication on the packets, as UDP source addresses are trivial to spoof.
+//! Note: Devices are identified by the PSK identity they authenticate with in the DTLS handshake.
+//! The source address is only used to route packets to the correct worker, as UDP source addresses
+//! are trivial to spoof and change whenever a DHCP lease does.
 
 use embedded_dtls::{
     queue_helpers::framed_queue,
@@ -11,140 +12,419 @@ use embedded_dtls::{
     },
 };
 use log::*;
-use once_cell::sync::{Lazy, OnceCell};
 use rustc_hash::FxHashMap;
-use std::net::IpAddr;
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 use tokio::{
     net::UdpSocket,
     sync::{
         broadcast,
         mpsc::{channel, error::TrySendError, Receiver, Sender},
-        RwLock,
+        watch, RwLock,
     },
+    time::{interval, timeout},
 };
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
 use rpc_definition::{
+    endpoints::device_info::{DeviceInfo, DeviceInfoEndpoint, GetDeviceInfo},
     postcard_rpc::host_client::HostClient,
+    topics::{
+        close::{Close, TopicClose},
+        heartbeat::Health,
+    },
     wire_error::{FatalError, ERROR_PATH},
 };
 
-use crate::ingress::engine::edtls::Delay;
+use crate::ingress::{
+    credentials::{DeviceCredential, PskStore, PskTable},
+    engine::{
+        admission::{Admission, Admitted},
+        edtls::Delay,
+        firmware_update::FirmwareStatus,
+        handshake::HandshakeFailures,
+        health::HealthRegistry,
+        stats::SessionStats,
+    },
+};
 use postcard_rpc::HostClientExt;
 
+mod admission;
+mod announce;
+mod client_hello;
 mod edtls;
+mod firmware_update;
+mod handshake;
+mod health;
+mod pending;
 mod postcard_rpc;
+mod stats;
+
+pub(crate) use admission::RateLimit;
+pub(crate) use announce::{announcer, Announce};
+pub use firmware_update::FirmwareImage;
+pub use handshake::HandshakeError;
+pub use health::DeviceHealth;
+pub(crate) use pending::PendingCalls;
+pub use stats::ConnectedDevice;
+
+/// Depth of the queue of heartbeat sequence numbers from the RPC worker to the health monitor.
+const HEARTBEAT_QUEUE_DEPTH: usize = 10;
+
+/// How long a closing session is kept, for the close message to be sent.
+const CLOSE_LINGER: Duration = Duration::from_millis(100);
+
+/// How often the listener forgets about workers and sources that are gone.
+const EVICTION_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Timeout of the device information request when a device connects.
+const DEVICE_INFO_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Identifier of a device, this is the PSK identity it authenticated with in the DTLS handshake.
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DeviceId(Arc<[u8]>);
+
+impl DeviceId {
+    /// The raw PSK identity.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<&[u8]> for DeviceId {
+    fn from(identity: &[u8]) -> Self {
+        Self(identity.into())
+    }
+}
+
+impl From<&str> for DeviceId {
+    fn from(identity: &str) -> Self {
+        identity.as_bytes().into()
+    }
+}
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Identities are usually printable, fall back to hex if they are not.
+        match std::str::from_utf8(&self.0) {
+            Ok(s) => f.write_str(s),
+            Err(_) => self.0.iter().try_for_each(|b| write!(f, "{b:02x}")),
+        }
+    }
+}
+
+impl fmt::Debug for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DeviceId({self})")
+    }
+}
 
 /// The new state of a connection.
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Connection {
     /// A new connection was established.
-    New(IpAddr),
+    New {
+        device: DeviceId,
+        /// Information the device reported when it connected, `None` if it did not answer, e.g.
+        /// because its firmware predates the request.
+        info: Option<DeviceInfo>,
+    },
     /// A connection was dropped.
-    Closed(IpAddr),
+    Closed(DeviceId),
+    /// The device missed too many heartbeats in a row, the link may be down.
+    Stale(DeviceId),
+    /// A stale device is sending heartbeats again.
+    Recovered(DeviceId),
+    /// The heartbeat sequence of the device started over, it has rebooted.
+    Rebooted(DeviceId),
+    /// A device failed the DTLS handshake, or was refused one.
+    HandshakeFailed {
+        addr: SocketAddr,
+        reason: HandshakeError,
+    },
 }
 
-/// Global singleton for the UDP socket.
-///
-/// RX happens in `udp_listener`, TX in `communication_worker`.
-static SOCKET: OnceCell<UdpSocket> = OnceCell::new();
+/// Settings of an ingress instance that the engine uses.
+pub(crate) struct Config {
+    /// Depth of the queue of received packets for each device.
+    pub packet_queue_depth: usize,
+    /// Depth of the queue of outgoing RPC requests for each device.
+    pub api_queue_depth: usize,
+    /// The firmware devices are updated to before they are accepted, if any.
+    pub firmware_image: Option<FirmwareImage>,
+    /// How often devices send heartbeats.
+    pub heartbeat_interval: Duration,
+    /// Number of heartbeats in a row a device can miss before it's stale.
+    pub missed_heartbeats: u32,
+    /// How long in-flight calls may take to complete when shutting down.
+    pub drain_timeout: Duration,
+    /// Maximum number of handshakes in progress at once.
+    pub max_handshakes: usize,
+    /// Rate of new handshakes allowed from one source IP.
+    pub handshake_rate_limit: RateLimit,
+    /// How long to ignore a source IP after a failed handshake, if at all.
+    pub handshake_backoff: Option<Duration>,
+    /// Where and how often to announce the ingress, if at all.
+    pub announce: Option<Announce>,
+}
+
+/// Shared state of one ingress instance.
+pub(crate) struct State {
+    /// The UDP socket, RX happens in `udp_listener`, TX in `communication_worker`.
+    pub socket: Arc<UdpSocket>,
+    /// The active API clients for use by public API.
+    pub clients: RwLock<FxHashMap<DeviceId, Session>>,
+    /// Signals that a connection was established or dropped.
+    pub connections: broadcast::Sender<Connection>,
+    /// The credentials of the devices that are allowed to connect.
+    pub credentials: PskStore,
+    /// Heartbeat health of all devices.
+    pub health: HealthRegistry,
+    /// Decides which new sources get a worker.
+    pub admission: Mutex<Admission>,
+    /// Failed handshakes per reason.
+    pub handshake_failures: HandshakeFailures,
+    /// Cancelled when the ingress shuts down.
+    pub shutdown: CancellationToken,
+    /// The communication workers and their cleanup, awaited when shutting down.
+    pub tasks: TaskTracker,
+    pub config: Config,
+    next_session_id: AtomicU64,
+}
+
+impl State {
+    pub fn new(
+        socket: UdpSocket,
+        credentials: PskStore,
+        connection_queue_depth: usize,
+        shutdown: CancellationToken,
+        config: Config,
+    ) -> Self {
+        let mut clients = FxHashMap::default();
+        clients.reserve(1000);
+
+        Self {
+            socket: Arc::new(socket),
+            clients: RwLock::new(clients),
+            connections: broadcast::channel(connection_queue_depth).0,
+            credentials,
+            health: HealthRegistry::new(config.heartbeat_interval, config.missed_heartbeats),
+            admission: Mutex::new(Admission::new(
+                config.max_handshakes,
+                config.handshake_rate_limit,
+            )),
+            handshake_failures: HandshakeFailures::default(),
+            shutdown,
+            tasks: TaskTracker::new(),
+            config,
+            next_session_id: AtomicU64::new(0),
+        }
+    }
+}
 
 /// Core socket listener, handles all incoming packets.
 ///
-/// This should run until the app closes.
-pub async fn udp_listener(socket: UdpSocket) -> ! {
-    let socket = SOCKET.get_or_init(|| socket);
+/// This should run until the ingress has shut down. Once shutting down no new workers are
+/// created, the running ones still get their packets to close their sessions.
+pub async fn udp_listener(state: Arc<State>) -> ! {
+    let socket = &state.socket;
 
-    // Wire workers are handling RX/TX packets, one worker per IP connected.
-    let mut wire_workers = FxHashMap::default();
+    // Wire workers are handling RX/TX packets, one worker per source address (IP and port).
+    let mut wire_workers: FxHashMap<SocketAddr, WireWorker> = FxHashMap::default();
     wire_workers.reserve(1000);
 
+    let mut eviction = interval(EVICTION_INTERVAL);
+
     debug!("Waiting for connections...");
 
     loop {
         let mut rx_buf = Vec::with_capacity(2048);
 
-        let Ok((len, from)) = socket.recv_buf_from(&mut rx_buf).await else {
+        let received = tokio::select! {
+            received = socket.recv_buf_from(&mut rx_buf) => received,
+            _ = eviction.tick() => {
+                wire_workers.values_mut().for_each(WireWorker::settle);
+                wire_workers.retain(|_, worker| !worker.packets.is_closed());
+                state.admission.lock().unwrap().evict();
+                continue;
+            }
+        };
+
+        let Ok((len, from)) = received else {
             error!("The socket was unable to receive data");
             continue;
         };
         assert_eq!(rx_buf.len(), len); // Assumption: We don't need `len`.
 
-        let ip = from.ip();
+        // Send the packet to the existing RX/TX worker.
+        let rx_buf = match wire_workers.get_mut(&from) {
+            Some(worker) => {
+                worker.settle();
+
+                // A new `ClientHello` on an established session means that the device restarted,
+                // or that someone spoofs its address. Start a new handshake right away instead of
+                // waiting for the old session to time out, the old one is kept until it succeeds.
+                if worker.established.load(Ordering::Relaxed)
+                    && client_hello::psk_identities(&rx_buf).is_some()
+                {
+                    debug!("{from}: New handshake on an established session");
+                    rx_buf
+                } else {
+                    match worker.packets.try_send(rx_buf) {
+                        Ok(()) => continue,
+                        Err(TrySendError::Full(_)) => {
+                            error!("{from}: Can't keep up with incoming packets");
+                            continue;
+                        }
+                        // The old worker has shut down, this can happen when a device was
+                        // connected, shut down, and connected again.
+                        Err(TrySendError::Closed(rx_buf)) => {
+                            wire_workers.remove(&from);
+                            rx_buf
+                        }
+                    }
+                }
+            }
+            None => rx_buf,
+        };
 
-        // Find existing RX/TX worker or create a new one.
-        let worker = wire_workers
-            .entry(ip)
-            .or_insert_with(|| create_communication_worker(ip));
+        if state.shutdown.is_cancelled() {
+            debug!("{from}: Shutting down, ignoring new connection");
+            continue;
+        }
 
-        // Send packet to the worker, or create it again if it has closed its connection.
-        if let Err(e) = worker.try_send(rx_buf) {
-            match e {
-                TrySendError::Full(_) => {
-                    error!("{ip}: Can't keep up with incoming packets");
-                }
-                TrySendError::Closed(retry_payload) => {
-                    // Recreate the worker if the old one has shut down.
-                    // This can happen when a device was connected, shut down, and connected again.
-                    wire_workers.insert(ip, create_communication_worker(ip));
-
-                    if let Err(e) = wire_workers.get_mut(&ip).unwrap().try_send(retry_payload) {
-                        error!(
-                            "{}: Retry worker failed to start with error {e:?}",
-                            from.ip()
-                        );
-                    }
+        // Or create a new one, if the source passes admission.
+        let admitted =
+            state
+                .admission
+                .lock()
+                .unwrap()
+                .admit(from.ip(), &rx_buf, &state.credentials.current());
+
+        match admitted {
+            Ok(admitted) => {
+                let mut worker = create_communication_worker(&state, from, admitted, rx_buf);
+                worker.replaces = wire_workers.remove(&from).map(Box::new);
+                wire_workers.insert(from, worker);
+            }
+            Err(rejected) => {
+                debug!("{from}: New connection rejected: {rejected:?}");
+
+                if let Some(reason) = rejected.reason() {
+                    state.handshake_failures.count(reason);
+                    let _ = state
+                        .connections
+                        .send(Connection::HandshakeFailed { addr: from, reason });
                 }
             }
         }
     }
 }
 
-// Helper to create a new worker for a specific IP.
-fn create_communication_worker(from: IpAddr) -> Sender<Vec<u8>> {
-    let (rx_packet_sender, rx_packet_recv) = channel(10);
-    tokio::spawn(communication_worker(from, rx_packet_recv));
-    rx_packet_sender
-}
-
-/// Global state of the active API clients for use by public API.
-pub(crate) static API_CLIENTS: Lazy<RwLock<FxHashMap<IpAddr, HostClient<FatalError>>>> =
-    Lazy::new(|| {
-        RwLock::new({
-            let mut m = FxHashMap::default();
-            m.reserve(1000);
-            m
-        })
-    });
-
-/// Global subscription to signal a new connection is available.
-pub(crate) static CONNECTION_SUBSCRIBER: Lazy<broadcast::Sender<Connection>> =
-    Lazy::new(|| broadcast::channel(1000).0);
-
-/// This handles incoming packets from a specific IP.
-async fn communication_worker(ip: IpAddr, packet_recv: Receiver<Vec<u8>>) {
-    debug!("{ip}: Registered new connection, starting handshake");
-
-    // TODO: This is where we should perform version checks and firmware update devices before
-    // accepting them as active. Most likely they will restart, and this connection will be closed
-    // and recreated as soon as the device comes back updated and can pass this check.
-    //
-    // match firmware_updating::check_version_and_maybe_update(&mut packet_recv) {
-    //     FirmwareUpdateStatus::NeedsUpdating => {
-    //         debug!("{ip}: Firmware needs updating, performing firmware update");
-    //
-    //         firmware_updating::start_firmware_update(&ip, packet_recv).await;
-    //
-    //         // Close the worker and await the reconnection after updates.
-    //         return;
-    //     }
-    //     FirmwareUpdateStatus::Valid => {
-    //         debug!("{ip}: Firmware valid, continuing");
-    //     }
-    // }
+/// The listener's handle to the worker of a source address.
+struct WireWorker {
+    /// Received packets for the worker, dropping this closes the worker.
+    packets: Sender<Vec<u8>>,
+    /// The worker has completed the handshake.
+    established: Arc<AtomicBool>,
+    /// The established worker this one takes over from once its handshake succeeds. It gets no
+    /// packets meanwhile, and takes the address back if the handshake fails.
+    replaces: Option<Box<WireWorker>>,
+}
 
+impl WireWorker {
+    /// Settle a pending takeover once the handshake of this worker has finished.
+    fn settle(&mut self) {
+        let Some(replaced) = self.replaces.take() else {
+            return;
+        };
+
+        if self.established.load(Ordering::Relaxed) {
+            // Dropping the old worker's packet queue closes its session.
+            drop(replaced);
+        } else if self.packets.is_closed() {
+            *self = *replaced;
+        } else {
+            self.replaces = Some(replaced);
+        }
+    }
+}
+
+// Helper to create a new worker for a specific source address.
+fn create_communication_worker(
+    state: &Arc<State>,
+    from: SocketAddr,
+    admitted: Admitted,
+    client_hello: Vec<u8>,
+) -> WireWorker {
+    let (rx_packet_sender, rx_packet_recv) = channel(state.config.packet_queue_depth);
+    let established = Arc::new(AtomicBool::new(false));
+
+    state.tasks.spawn(communication_worker(
+        state.clone(),
+        from,
+        admitted,
+        client_hello,
+        rx_packet_recv,
+        established.clone(),
+    ));
+
+    WireWorker {
+        packets: rx_packet_sender,
+        established,
+        replaces: None,
+    }
+}
+
+/// An authenticated session with a device.
+#[derive(Clone)]
+pub(crate) struct Session {
+    /// API client for the device.
+    pub client: HostClient<FatalError>,
+    /// The IP the device is currently connected from.
+    pub ip: IpAddr,
+    /// Timing of the session.
+    pub stats: Arc<SessionStats>,
+    /// The in-flight calls, drained before the session is closed on shutdown.
+    pub calls: TaskTracker,
+    /// Calls from the public API waiting for their response, cancelled on the device if dropped.
+    pub pending: Arc<PendingCalls>,
+    /// Unique ID of the session, used to not clean up a newer session for the same device.
+    id: u64,
+}
+
+/// This handles incoming packets from a specific source address.
+///
+/// The worker is created on the first packet from a source, the `ClientHello` that admission found
+/// to offer a known PSK identity.
+async fn communication_worker(
+    state: Arc<State>,
+    addr: SocketAddr,
+    Admitted { credential, permit }: Admitted,
+    client_hello: Vec<u8>,
+    packet_recv: Receiver<Vec<u8>>,
+    established: Arc<AtomicBool>,
+) {
+    // Check the credential once up front, it may have changed since admission.
+    let mut credential_changes = state.credentials.subscribe();
+    credential_changes.mark_changed();
+
+    let device = credential.identity.clone();
+
+    debug!("{addr}: Registered new connection from {device}, starting handshake");
+
+    // Only the claimed identity is accepted, so a successful handshake authenticates it. The key
+    // was looked up or derived from the master secret when the device was admitted.
     let psk = [(
-        Identity::from(b"hello world"),
-        Key::from(b"11111234567890qwertyuiopasdfghjklzxc"),
+        Identity::from(device.as_bytes()),
+        Key::from(credential.key.as_slice()),
     )];
 
     let server_config = ServerConfig { psk: &psk };
@@ -152,44 +432,364 @@ async fn communication_worker(ip: IpAddr, packet_recv: Receiver<Vec<u8>>) {
     let buf = &mut vec![0; 16 * 1024];
     let rng = &mut rand::rngs::OsRng;
 
-    let rx = edtls::RxEndpoint::new((ip, 8321), packet_recv);
-    let tx = edtls::TxEndpoint::new((ip, 8321));
+    let stats = Arc::new(SessionStats::new());
 
-    let server_connection = open_server(rx, tx, &server_config, rng, buf).await.unwrap();
+    let rx = edtls::RxEndpoint::new((addr.ip(), addr.port()), packet_recv, stats.clone())
+        .with_pending(client_hello);
+    let transport_failure = rx.failure();
+    let tx = edtls::TxEndpoint::new(state.socket.clone(), (addr.ip(), addr.port()));
 
-    let (mut tx_sender, mut tx_receiver) = framed_queue(10);
-    let (mut rx_sender, mut rx_receiver) = framed_queue(10);
+    // A handshake takes up to the receive timeout to fail, don't hold up the shutdown with it.
+    let handshake_start = Instant::now();
+    let handshake = tokio::select! {
+        handshake = open_server(rx, tx, &server_config, rng, buf) => handshake,
+        _ = state.shutdown.cancelled() => {
+            debug!("{addr}: Ingress is shutting down, abandoning the handshake with {device}");
+            return;
+        }
+    };
+
+    let server_connection = match handshake {
+        Ok(connection) => connection,
+        Err(e) => {
+            let reason = HandshakeError::new(transport_failure.get().copied());
+            warn!("{addr}: Handshake with {device} failed, {reason}: {e:?}");
+
+            state.handshake_failures.count(reason);
+            if let Some(backoff) = state.config.handshake_backoff {
+                if reason != HandshakeError::Aborted {
+                    state.admission.lock().unwrap().back_off(addr.ip(), backoff);
+                }
+            }
 
-    // We have one host client per connection.
-    let (hostclient, rpc_worker) = HostClient::new_edtls(ERROR_PATH, 10);
+            let _ = state
+                .connections
+                .send(Connection::HandshakeFailed { addr, reason });
+            return;
+        }
+    };
+    stats.connected(handshake_start.elapsed());
+    drop(permit);
+    established.store(true, Ordering::Relaxed);
 
-    let _ = CONNECTION_SUBSCRIBER.send(Connection::New(ip));
+    let (mut tx_sender, mut tx_receiver) = framed_queue(state.config.packet_queue_depth);
+    let (mut rx_sender, mut rx_receiver) = framed_queue(state.config.packet_queue_depth);
 
-    // Store the API client for access by public APIs
-    {
-        API_CLIENTS.write().await.insert(ip, hostclient);
-    }
+    // We have one host client per connection.
+    let (hostclient, rpc_worker) = HostClient::new_edtls(ERROR_PATH, state.config.api_queue_depth);
+    let (heartbeat_sender, heartbeat_receiver) = channel(HEARTBEAT_QUEUE_DEPTH);
 
     let mut rx_buf = vec![0; 1536];
     let mut tx_buf = vec![0; 1536];
 
+    let session_id = state.next_session_id.fetch_add(1, Ordering::Relaxed);
+    let calls = TaskTracker::new();
+    let pending = Arc::new(PendingCalls::new());
+
+    let new_session = Session {
+        client: hostclient.clone(),
+        ip: addr.ip(),
+        stats,
+        calls: calls.clone(),
+        pending: pending.clone(),
+        id: session_id,
+    };
+
+    // Cleans up when the worker returns, or unwinds from a panic in the select below.
+    let _guard = SessionGuard {
+        state: state.clone(),
+        device: device.clone(),
+        addr,
+        session_id,
+    };
+
     let mut delay = Delay;
     tokio::select! {
         e = server_connection.run(&mut rx_buf, &mut tx_buf, &mut rx_sender, &mut tx_receiver, &mut delay) => {
             let e = e.unwrap_err();
-            error!("{ip}: Edtls connection stopped: {e:?}");
+            error!("{device}: Edtls connection stopped: {e:?}");
         },
-        e = rpc_worker.run(ip, &mut rx_receiver, &mut tx_sender) => {
+        e = rpc_worker.run(&device, &heartbeat_sender, &pending, &mut rx_receiver, &mut tx_sender) => {
             let e = e.unwrap_err();
-            error!("{ip}: Rpc worker stopped: {e:?}");
+            error!("{device}: Rpc worker stopped: {e:?}");
+        }
+        _ = revoked(&mut credential_changes, &credential) => {
+            warn!("{device}: Credentials were revoked or changed, closing the session");
+        }
+        _ = session(&state, &device, addr, new_session, heartbeat_receiver) => {}
+        _ = async {
+            drain(&state, &device, &calls).await;
+            close(&device, &hostclient).await;
+        } => {
+            info!("{device}: Ingress is shutting down, the session is closed");
+        }
+    }
+
+    // No responses arrive anymore, fail the calls still waiting for one.
+    pending.close();
+}
+
+/// Removes a session from the public APIs and reports it closed when dropped, unless a newer
+/// session for the same device has replaced it.
+///
+/// Being a drop guard the cleanup also happens when the worker panics. `Drop` can't take the async
+/// lock, so the cleanup is spawned as a task of its own.
+struct SessionGuard {
+    state: Arc<State>,
+    device: DeviceId,
+    addr: SocketAddr,
+    session_id: u64,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        // Without a runtime there is no one left to tell.
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+
+        let state = self.state.clone();
+        let device = self.device.clone();
+        let addr = self.addr;
+        let session_id = self.session_id;
+
+        self.state.tasks.spawn_on(
+            async move {
+                let mut clients = state.clients.write().await;
+                let ours = clients
+                    .get(&device)
+                    .is_some_and(|session| session.id == session_id);
+
+                // Sent under the lock, so snapshots and events agree.
+                if ours && clients.remove(&device).is_some() {
+                    let _ = state.connections.send(Connection::Closed(device.clone()));
+                }
+
+                debug!("{device}: Connection from {addr} dropped");
+            },
+            &runtime,
+        );
+    }
+}
+
+/// Resolves when the ingress shuts down and the in-flight calls of a session have completed, or
+/// the drain timeout has passed. New calls are refused from the start of the shutdown.
+async fn drain(state: &State, device: &DeviceId, calls: &TaskTracker) {
+    state.shutdown.cancelled().await;
+    calls.close();
+
+    if timeout(state.config.drain_timeout, calls.wait())
+        .await
+        .is_err()
+    {
+        warn!(
+            "{device}: {} calls still in flight after {:?}, closing anyway",
+            calls.len(),
+            state.config.drain_timeout
+        );
+    }
+}
+
+/// Tell the device that its session ends.
+///
+/// embedded-dtls has no way to send a `close_notify` alert, the device acts on `TopicClose`
+/// instead. The connection is kept for a moment, for the message to be sent before it's dropped.
+async fn close(device: &DeviceId, client: &HostClient<FatalError>) {
+    // Topics get no response, the sequence number is unused.
+    if client.publish::<TopicClose>(0, &Close {}).await.is_err() {
+        debug!("{device}: The session ended before it could be closed");
+        return;
+    }
+
+    tokio::time::sleep(CLOSE_LINGER).await;
+}
+
+/// Makes the worker of this device panic once it's announced, to test the cleanup.
+#[cfg(test)]
+static PANIC_AFTER_CONNECT: Mutex<Option<DeviceId>> = Mutex::new(None);
+
+/// Make sure the device runs the correct firmware, then make it available to the public APIs.
+///
+/// Resolves only if the session should end, e.g. after a firmware update when the device restarts
+/// and reconnects with the new firmware.
+async fn session(
+    state: &State,
+    device: &DeviceId,
+    addr: SocketAddr,
+    session: Session,
+    heartbeats: Receiver<(u32, Option<Health>)>,
+) {
+    match firmware_update::check_version_and_maybe_update(
+        device,
+        &session.client,
+        state.config.firmware_image.as_ref(),
+    )
+    .await
+    {
+        Ok(FirmwareStatus::Valid | FirmwareStatus::Staged) => {}
+        Ok(FirmwareStatus::Updated) => {
+            // Close the worker and await the reconnection after the restart.
+            return;
+        }
+        Err(e) => {
+            error!("{device}: Firmware update failed: {e}");
+            return;
+        }
+    }
+
+    let info = device_info(device, &session.client).await;
+    let stats = session.stats.clone();
+
+    // Store the API client for access by public APIs. If the device was already connected from
+    // somewhere else, the new session takes over.
+    {
+        let mut clients = state.clients.write().await;
+
+        // Staleness is about the old session, the new one starts out fresh.
+        state.health.connected(device);
+
+        if let Some(old) = clients.insert(device.clone(), session) {
+            warn!(
+                "{device}: Reconnected from {addr}, replacing session from {}",
+                old.ip
+            );
         }
+
+        // Sent under the lock, so snapshots and events agree.
+        let _ = state.connections.send(Connection::New {
+            device: device.clone(),
+            info,
+        });
+    }
+
+    #[cfg(test)]
+    if PANIC_AFTER_CONNECT.lock().unwrap().as_ref() == Some(device) {
+        panic!("{device}: Panicking as asked by the test");
     }
 
-    // How to guarantee that we do a nice cleanup? What if code in the select panics?
-    // cleanup of global state
-    API_CLIENTS.write().await.remove(&ip);
+    monitor_heartbeats(state, device, &stats, heartbeats).await
+}
 
-    let _ = CONNECTION_SUBSCRIBER.send(Connection::Closed(ip));
+/// Ask a newly connected device about itself.
+async fn device_info(device: &DeviceId, client: &HostClient<FatalError>) -> Option<DeviceInfo> {
+    match timeout(
+        DEVICE_INFO_TIMEOUT,
+        client.send_resp::<DeviceInfoEndpoint>(&GetDeviceInfo {}),
+    )
+    .await
+    {
+        Ok(Ok(info)) => {
+            debug!("{device}: {info:?}");
+            Some(info)
+        }
+        Ok(Err(e)) => {
+            warn!("{device}: Device information request failed: {e:?}");
+            None
+        }
+        Err(_timeout) => {
+            warn!("{device}: Device information request timed out");
+            None
+        }
+    }
+}
 
-    debug!("{ip}: Connection dropped");
+/// Track the heartbeats of a device and report changes in its health.
+async fn monitor_heartbeats(
+    state: &State,
+    device: &DeviceId,
+    stats: &SessionStats,
+    mut heartbeats: Receiver<(u32, Option<Health>)>,
+) -> ! {
+    loop {
+        let events = match timeout(state.health.stale_after, heartbeats.recv()).await {
+            Ok(Some((sequence_number, report))) => {
+                stats.heartbeat_received();
+                state.health.heartbeat(device, sequence_number, report)
+            }
+            // The RPC worker is gone, the session is about to end.
+            Ok(None) => std::future::pending().await,
+            Err(_timeout) => state.health.missed(device).into_iter().collect(),
+        };
+
+        for event in events {
+            debug!("{device}: Health changed: {event:?}");
+            let _ = state.connections.send(event);
+        }
+    }
+}
+
+/// Resolves when the credential a session was established with is removed or changed.
+async fn revoked(changes: &mut watch::Receiver<Arc<PskTable>>, credential: &DeviceCredential) {
+    loop {
+        if changes.changed().await.is_err() {
+            // The store is gone, the credentials can't change anymore.
+            std::future::pending::<()>().await;
+        }
+
+        let still_valid = changes
+            .borrow_and_update()
+            .get(credential.identity.as_bytes())
+            .is_some_and(|current| current.key == credential.key);
+
+        if !still_valid {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ingress::{subscriptions::Subscription, Ingress};
+    use device_sim::DeviceConfig;
+
+    const KEY: &[u8] = b"11111234567890qwertyuiopasdfghjklzxc";
+
+    #[tokio::test]
+    async fn a_panicking_worker_still_closes_its_session() {
+        let device = DeviceId::from("panicking-sim");
+        *PANIC_AFTER_CONNECT.lock().unwrap() = Some(device.clone());
+
+        let psk_file =
+            std::env::temp_dir().join(format!("pc-app-engine-test-{}.toml", std::process::id()));
+        let key: String = KEY.iter().map(|b| format!("{b:02x}")).collect();
+        std::fs::write(
+            &psk_file,
+            format!("[[device]]\nidentity = \"{device}\"\nkey = \"{key}\"\n"),
+        )
+        .unwrap();
+        let credentials = PskStore::load(&psk_file).unwrap();
+        std::fs::remove_file(&psk_file).unwrap();
+
+        let ingress = Ingress::builder()
+            .bind(([127, 0, 0, 1], 0))
+            .credentials(credentials)
+            .build()
+            .await
+            .unwrap();
+        let mut connections = ingress.connection();
+
+        let sim = device_sim::spawn(DeviceConfig::new(
+            ingress.local_addr(),
+            device.as_bytes(),
+            KEY,
+        ));
+
+        // The worker panics right after announcing the device.
+        assert!(matches!(
+            next(&mut connections).await,
+            Connection::New { device: new, .. } if new == device
+        ));
+        assert_eq!(next(&mut connections).await, Connection::Closed(device));
+        assert!(ingress.devices().await.is_empty());
+
+        sim.abort();
+    }
+
+    async fn next(connections: &mut Subscription<Connection>) -> Connection {
+        timeout(Duration::from_secs(10), connections.recv())
+            .await
+            .expect("event in time")
+            .expect("subscription open")
+    }
 }
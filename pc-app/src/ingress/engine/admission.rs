@@ -0,0 +1,190 @@
+//! Admission of new sources, before any per-connection state is allocated.
+//!
+//! Anyone can send a datagram with any source address, so a new source has to pass three checks
+//! before it gets a worker:
+//!
+//! 1. The datagram is a `ClientHello` offering a known or derived PSK identity. This is stateless
+//!    and cheap, garbage and unknown devices are dropped without allocating anything.
+//! 2. The source IP has not started too many handshakes recently, limited by a token bucket.
+//! 3. There are less than the maximum number of handshakes running, as each one holds its buffers
+//!    until it completes or times out.
+//!
+//! The source address is not verified with a `HelloVerifyRequest` cookie, the embedded-dtls
+//! client doesn't answer one. The limits above are what bound the cost of a spoofed flood.
+
+use super::{client_hello, handshake::HandshakeError};
+use crate::ingress::credentials::{DeviceCredential, PskTable};
+use rustc_hash::FxHashMap;
+use std::{net::IpAddr, sync::Arc, time::Duration};
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::Instant,
+};
+
+/// Rate of new handshakes allowed from one source IP.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct RateLimit {
+    /// Handshakes that can be started at once.
+    pub burst: u32,
+    /// Time until one more handshake is allowed.
+    pub interval: Duration,
+}
+
+/// Why a new source was not admitted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Rejected {
+    /// The datagram is not a `ClientHello`.
+    NotClientHello,
+    /// The `ClientHello` offers no known PSK identity.
+    UnknownIdentity,
+    /// The source IP has started too many handshakes recently.
+    RateLimited,
+    /// Too many handshakes are running.
+    TooManyHandshakes,
+    /// A handshake from the source IP failed recently.
+    BackingOff,
+}
+
+impl Rejected {
+    /// How the rejection is reported, garbage is only logged.
+    pub fn reason(self) -> Option<HandshakeError> {
+        match self {
+            Self::NotClientHello => None,
+            Self::UnknownIdentity => Some(HandshakeError::UnknownIdentity),
+            Self::RateLimited | Self::TooManyHandshakes | Self::BackingOff => {
+                Some(HandshakeError::RateLimited)
+            }
+        }
+    }
+}
+
+/// A source that may start a handshake.
+pub(crate) struct Admitted {
+    /// The credential the device claims.
+    pub credential: DeviceCredential,
+    /// Held until the handshake has completed.
+    pub permit: OwnedSemaphorePermit,
+}
+
+/// Decides which new sources get a worker.
+pub(crate) struct Admission {
+    handshakes: Arc<Semaphore>,
+    rate_limit: RateLimit,
+    sources: FxHashMap<IpAddr, Bucket>,
+}
+
+impl Admission {
+    pub fn new(max_handshakes: usize, rate_limit: RateLimit) -> Self {
+        Self {
+            handshakes: Arc::new(Semaphore::new(max_handshakes)),
+            rate_limit,
+            sources: FxHashMap::default(),
+        }
+    }
+
+    /// Check the first datagram from a source.
+    pub fn admit(
+        &mut self,
+        from: IpAddr,
+        datagram: &[u8],
+        table: &PskTable,
+    ) -> Result<Admitted, Rejected> {
+        let credential = client_hello::psk_identities(datagram)
+            .ok_or(Rejected::NotClientHello)?
+            .into_iter()
+            .find_map(|offered| table.get(offered))
+            .ok_or(Rejected::UnknownIdentity)?;
+
+        let now = Instant::now();
+        let bucket = self
+            .sources
+            .entry(from)
+            .or_insert_with(|| Bucket::full(self.rate_limit, now));
+
+        if bucket.blocked_until.is_some_and(|until| now < until) {
+            return Err(Rejected::BackingOff);
+        }
+
+        // Take the permit first, a source should not pay for a handshake that never started.
+        let permit = self
+            .handshakes
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| Rejected::TooManyHandshakes)?;
+
+        if !bucket.take(self.rate_limit, now) {
+            return Err(Rejected::RateLimited);
+        }
+
+        Ok(Admitted { credential, permit })
+    }
+
+    /// Ignore a source IP for a while.
+    pub fn back_off(&mut self, from: IpAddr, duration: Duration) {
+        let now = Instant::now();
+
+        self.sources
+            .entry(from)
+            .or_insert_with(|| Bucket::full(self.rate_limit, now))
+            .blocked_until = Some(now + duration);
+    }
+
+    /// Forget sources whose bucket has filled up again and that are not backed off, they are no
+    /// different from new ones.
+    pub fn evict(&mut self) {
+        let now = Instant::now();
+        let rate_limit = self.rate_limit;
+
+        self.sources.retain(|_, bucket| {
+            bucket.blocked_until.is_some_and(|until| now < until)
+                || !bucket.is_full(rate_limit, now)
+        });
+    }
+}
+
+/// Token bucket of one source.
+struct Bucket {
+    tokens: u32,
+    refilled_at: Instant,
+    blocked_until: Option<Instant>,
+}
+
+impl Bucket {
+    fn full(rate_limit: RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: rate_limit.burst,
+            refilled_at: now,
+            blocked_until: None,
+        }
+    }
+
+    fn refill(&mut self, rate_limit: RateLimit, now: Instant) {
+        let elapsed = now.duration_since(self.refilled_at);
+        let new = (elapsed.as_nanos() / rate_limit.interval.as_nanos().max(1)).min(u32::MAX as u128)
+            as u32;
+
+        if self.tokens.saturating_add(new) >= rate_limit.burst {
+            self.tokens = rate_limit.burst;
+            self.refilled_at = now;
+        } else {
+            self.tokens += new;
+            self.refilled_at += rate_limit.interval * new;
+        }
+    }
+
+    fn take(&mut self, rate_limit: RateLimit, now: Instant) -> bool {
+        self.refill(rate_limit, now);
+
+        if self.tokens == 0 {
+            return false;
+        }
+
+        self.tokens -= 1;
+        true
+    }
+
+    fn is_full(&mut self, rate_limit: RateLimit, now: Instant) -> bool {
+        self.refill(rate_limit, now);
+        self.tokens == rate_limit.burst
+    }
+}
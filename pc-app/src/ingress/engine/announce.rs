@@ -0,0 +1,63 @@
+//! Announces the ingress on the local network, so devices find it without a configured address.
+
+use super::State;
+use log::*;
+use rpc_definition::discovery::Announcement;
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{net::UdpSocket, time::interval};
+
+/// Where and how often to announce the ingress.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Announce {
+    pub target: SocketAddr,
+    pub interval: Duration,
+}
+
+/// Periodically send an announcement, if configured.
+///
+/// This should run until the app closes, failing to announce does not stop the ingress.
+pub async fn announcer(state: Arc<State>) -> ! {
+    let Some(announce) = state.config.announce else {
+        std::future::pending().await
+    };
+
+    let socket = match bind(announce.target).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("Unable to create the announcement socket: {e}");
+            std::future::pending().await
+        }
+    };
+
+    let port = state.socket.local_addr().map_or(0, |addr| addr.port());
+    let announcement =
+        postcard::to_stdvec(&Announcement::new(port)).expect("an announcement always serializes");
+
+    debug!("Announcing port {port} to {}", announce.target);
+
+    let mut interval = interval(announce.interval);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = socket.send_to(&announcement, announce.target).await {
+            warn!("Unable to announce to {}: {e}", announce.target);
+        }
+    }
+}
+
+/// A socket that can send to `target`, also if it's a broadcast address.
+async fn bind(target: SocketAddr) -> std::io::Result<UdpSocket> {
+    let bind: SocketAddr = match target {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+
+    let socket = UdpSocket::bind(bind).await?;
+    socket.set_broadcast(true)?;
+    Ok(socket)
+}
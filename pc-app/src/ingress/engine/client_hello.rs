@@ -0,0 +1,108 @@
+//! Minimal parsing of plaintext DTLS 1.3 `ClientHello` records.
+//!
+//! The engine uses this to learn which PSK identity a device is about to authenticate with before
+//! the handshake is started, so only that identity's key is handed to `open_server`. A successful
+//! handshake then proves that the device owns that identity.
+
+/// Record content type for handshake messages.
+const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+/// Handshake message type of a `ClientHello`.
+const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 1;
+/// Extension carrying the offered PSK identities.
+const EXTENSION_PRE_SHARED_KEY: u16 = 41;
+
+/// Extract the PSK identities offered in a `ClientHello`.
+///
+/// Returns `None` if the datagram does not start with a well-formed, unfragmented `ClientHello`.
+pub fn psk_identities(datagram: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut record = Reader(datagram);
+
+    // DTLSPlaintext header: type, legacy version, epoch, sequence number, length.
+    if record.u8()? != CONTENT_TYPE_HANDSHAKE {
+        return None;
+    }
+    record.take(2 + 2 + 6)?;
+    let mut handshake = Reader(record.vec16()?);
+
+    // Handshake header: type, length, message sequence, fragment offset, fragment length.
+    if handshake.u8()? != HANDSHAKE_TYPE_CLIENT_HELLO {
+        return None;
+    }
+    let length = handshake.u24()?;
+    handshake.take(2)?;
+    let fragment_offset = handshake.u24()?;
+    let fragment_length = handshake.u24()?;
+    if fragment_offset != 0 || fragment_length != length {
+        return None;
+    }
+
+    // ClientHello body: version, random, session id, cookie, cipher suites, compression.
+    let mut hello = Reader(handshake.take(length)?);
+    hello.take(2 + 32)?;
+    hello.vec8()?;
+    hello.vec8()?;
+    hello.vec16()?;
+    hello.vec8()?;
+
+    let mut extensions = Reader(hello.vec16()?);
+    while !extensions.is_empty() {
+        let extension_type = extensions.u16()?;
+        let mut extension = Reader(extensions.vec16()?);
+
+        if extension_type == EXTENSION_PRE_SHARED_KEY {
+            let mut identities = Reader(extension.vec16()?);
+            let mut found = Vec::new();
+
+            while !identities.is_empty() {
+                found.push(identities.vec16()?);
+                // Obfuscated ticket age, unused for external PSKs.
+                identities.take(4)?;
+            }
+
+            return Some(found);
+        }
+    }
+
+    Some(Vec::new())
+}
+
+/// Big endian reader over a byte slice.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|b| u32::from_be_bytes([0, b[0], b[1], b[2]]) as usize)
+    }
+
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let n = self.u8()? as usize;
+        self.take(n)
+    }
+
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let n = self.u16()? as usize;
+        self.take(n)
+    }
+}
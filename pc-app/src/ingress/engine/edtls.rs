@@ -1,7 +1,15 @@
-use std::{fmt::Debug, net::IpAddr, time::Duration};
+use std::{
+    fmt::Debug,
+    net::IpAddr,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use super::stats::SessionStats;
 
 use embedded_dtls::{self, DelayNs};
 use tokio::{
+    net::UdpSocket,
     sync::mpsc::Receiver,
     time::{error::Elapsed, timeout},
 };
@@ -14,26 +22,61 @@ impl DelayNs for Delay {
     }
 }
 
-use super::SOCKET;
-
 pub struct TxEndpoint {
+    socket: Arc<UdpSocket>,
     endpoint: (IpAddr, u16),
 }
 
 impl TxEndpoint {
-    pub fn new(endpoint: (IpAddr, u16)) -> Self {
-        Self { endpoint }
+    pub fn new(socket: Arc<UdpSocket>, endpoint: (IpAddr, u16)) -> Self {
+        Self { socket, endpoint }
     }
 }
 
+/// Why receiving from the worker's packet queue failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum ReceiveError {
+    #[error("connection timed out")]
+    TimedOut,
+    #[error("all senders were closed")]
+    Closed,
+    #[error("could not fit the received datagram into the buffer")]
+    Oversized,
+}
+
 pub struct RxEndpoint {
     endpoint: (IpAddr, u16),
     rx: Receiver<Vec<u8>>,
+    pending: Option<Vec<u8>>,
+    stats: Arc<SessionStats>,
+    failure: Arc<OnceLock<ReceiveError>>,
 }
 
 impl RxEndpoint {
-    pub fn new(endpoint: (IpAddr, u16), rx: Receiver<Vec<u8>>) -> Self {
-        Self { endpoint, rx }
+    pub fn new(endpoint: (IpAddr, u16), rx: Receiver<Vec<u8>>, stats: Arc<SessionStats>) -> Self {
+        Self {
+            endpoint,
+            rx,
+            pending: None,
+            stats,
+            failure: Arc::default(),
+        }
+    }
+
+    /// Set to the first receive error, which is lost inside the DTLS error.
+    pub fn failure(&self) -> Arc<OnceLock<ReceiveError>> {
+        self.failure.clone()
+    }
+
+    /// Give back a packet that was already taken from the queue, it is received before any other.
+    pub fn with_pending(mut self, packet: Vec<u8>) -> Self {
+        self.pending = Some(packet);
+        self
+    }
+
+    fn fail(&self, e: ReceiveError) -> ReceiveError {
+        let _ = self.failure.set(e);
+        e
     }
 }
 
@@ -52,37 +95,40 @@ impl Debug for RxEndpoint {
 }
 
 impl embedded_dtls::RxEndpoint for RxEndpoint {
-    type ReceiveError = anyhow::Error;
+    type ReceiveError = ReceiveError;
 
     async fn recv<'a>(&mut self, buf: &'a mut [u8]) -> Result<&'a mut [u8], Self::ReceiveError> {
-        match timeout(Duration::from_secs(5), self.rx.recv()).await {
+        let received = match self.pending.take() {
+            Some(packet) => Ok(Some(packet)),
+            None => timeout(Duration::from_secs(5), self.rx.recv()).await,
+        };
+
+        match received {
             Ok(Some(received_data)) => {
+                self.stats.packet_received();
+
                 let n = received_data.len();
                 if buf.len() < n {
-                    return Err(anyhow::anyhow!(
-                        "Could not fit the received datagram into the buffer"
-                    ));
+                    return Err(self.fail(ReceiveError::Oversized));
                 }
                 let buf = &mut buf[..n];
                 buf.copy_from_slice(&received_data);
                 Ok(buf)
             }
-            Ok(None) => Err(anyhow::anyhow!("All senders were closed")),
-            Err(Elapsed { .. }) => Err(anyhow::anyhow!("Connection timed out")),
+            Ok(None) => Err(self.fail(ReceiveError::Closed)),
+            Err(Elapsed { .. }) => Err(self.fail(ReceiveError::TimedOut)),
         }
     }
 }
+
 impl embedded_dtls::TxEndpoint for TxEndpoint {
     type SendError = anyhow::Error;
 
     async fn send(&mut self, buf: &[u8]) -> Result<(), Self::SendError> {
-        let socket = SOCKET
-            .get()
-            .ok_or_else(|| anyhow::anyhow!("Socket is not initialized"))?;
-        socket
+        self.socket
             .send_to(buf, self.endpoint)
             .await
-            .map_err(|e| anyhow::Error::from(e))?;
+            .map_err(anyhow::Error::from)?;
         Ok(())
     }
 }
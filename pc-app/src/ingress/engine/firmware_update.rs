@@ -0,0 +1,215 @@
+//! Host side of the firmware update.
+//!
+//! Before a device is announced as connected its firmware version is checked against the image
+//! given to the ingress. If they differ the image is transferred in chunks. A device that can
+//! install it restarts into it, after which it reconnects and goes through the check again. The
+//! others keep it staged and are announced with the firmware they run, on later connections they
+//! answer that the image is staged without it being transferred again.
+//!
+//! The device keeps its progress over a dropped connection, and every request is retried when it
+//! gets no answer, so an update survives both packet loss and reconnects. A device with firmware
+//! that can't tell its version is updated as well.
+
+use log::*;
+use postcard::experimental::schema::Schema;
+use rpc_definition::{
+    crc::crc32,
+    endpoints::firmware_update::{
+        FinishUpdate, FinishUpdateEndpoint, FirmwareVersion, FirmwareVersionEndpoint,
+        GetFirmwareVersion, StartUpdate, StartUpdateEndpoint, UpdateError, UpdateResponse,
+        WriteChunk, WriteChunkEndpoint, CHUNK_SIZE,
+    },
+    heapless,
+    postcard_rpc::{
+        host_client::{HostClient, HostErr},
+        Endpoint,
+    },
+    wire_error::FatalError,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{io, path::Path, time::Duration};
+use tokio::time::timeout;
+
+use super::DeviceId;
+
+/// Timeout of version requests and chunk writes.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+/// Timeout of requests that erase or read back the full update slot.
+const SLOT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many times a request is retried before giving up on the connection.
+const MAX_RETRIES: usize = 5;
+
+/// A firmware image that devices are updated to.
+#[derive(Clone, Debug)]
+pub struct FirmwareImage {
+    version: FirmwareVersion,
+    data: Vec<u8>,
+    crc: u32,
+}
+
+impl FirmwareImage {
+    /// Create an image from a raw binary.
+    pub fn new(version: FirmwareVersion, data: Vec<u8>) -> Self {
+        let crc = crc32(&data);
+        Self { version, data, crc }
+    }
+
+    /// Load a raw binary (not an ELF) from a file.
+    pub fn load(version: FirmwareVersion, path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::new(version, std::fs::read(path)?))
+    }
+
+    /// The version of the image.
+    pub fn version(&self) -> FirmwareVersion {
+        self.version
+    }
+}
+
+/// Outcome of the version check.
+pub(crate) enum FirmwareStatus {
+    /// The device runs the expected firmware.
+    Valid,
+    /// The device was updated and is restarting.
+    Updated,
+    /// The device keeps the image, but can't install it and runs its old firmware.
+    Staged,
+}
+
+/// Errors that abort a firmware update.
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum FirmwareUpdateError {
+    #[error("the device did not answer, even after retries")]
+    NoResponse,
+    #[error("the device rejected the update: {0:?}")]
+    Rejected(UpdateError),
+    #[error("the device failed the request: {0:?}")]
+    Failed(HostErr<FatalError>),
+}
+
+/// Check the version of a device, and update it if it does not run `image`.
+pub(crate) async fn check_version_and_maybe_update(
+    device: &DeviceId,
+    client: &HostClient<FatalError>,
+    image: Option<&FirmwareImage>,
+) -> Result<FirmwareStatus, FirmwareUpdateError> {
+    let Some(image) = image else {
+        return Ok(FirmwareStatus::Valid);
+    };
+
+    // Firmware older than the version endpoint is certainly not the image.
+    let version =
+        match request::<FirmwareVersionEndpoint>(client, &GetFirmwareVersion {}, REQUEST_TIMEOUT)
+            .await
+        {
+            Ok(version) => Some(version),
+            Err(FirmwareUpdateError::Failed(HostErr::Wire(FatalError::UnknownEndpoint))) => None,
+            Err(e) => return Err(e),
+        };
+
+    if version == Some(image.version) {
+        debug!("{device}: Firmware {:?} is valid", image.version);
+        return Ok(FirmwareStatus::Valid);
+    }
+
+    info!(
+        "{device}: Updating firmware from {version:?} to {:?}",
+        image.version
+    );
+
+    let start = StartUpdate {
+        version: image.version,
+        size: image.data.len() as u32,
+        crc: image.crc,
+    };
+
+    let mut offset = match request::<StartUpdateEndpoint>(client, &start, SLOT_TIMEOUT).await? {
+        UpdateResponse::Staged => return Ok(staged(device, image)),
+        response => progress(response)?,
+    };
+
+    if offset != 0 {
+        info!("{device}: Resuming firmware update at {offset}");
+    }
+
+    while (offset as usize) < image.data.len() {
+        let end = (offset as usize + CHUNK_SIZE).min(image.data.len());
+        let data = &image.data[offset as usize..end];
+
+        let chunk = WriteChunk {
+            offset,
+            crc: crc32(data),
+            data: heapless::Vec::from_slice(data).unwrap(),
+        };
+
+        // The device answers with where it wants to continue, this also covers lost responses.
+        offset = progress(request::<WriteChunkEndpoint>(client, &chunk, REQUEST_TIMEOUT).await?)?;
+    }
+
+    match request::<FinishUpdateEndpoint>(client, &FinishUpdate {}, SLOT_TIMEOUT).await? {
+        UpdateResponse::Done => {
+            info!("{device}: Firmware update done, the device is restarting");
+            Ok(FirmwareStatus::Updated)
+        }
+        UpdateResponse::Progress(at) => {
+            // Should not happen as we've written the full image, the next connection resumes.
+            warn!("{device}: Firmware update incomplete at {at}");
+            Err(FirmwareUpdateError::Rejected(UpdateError::NotStarted))
+        }
+        UpdateResponse::Error(e) => Err(FirmwareUpdateError::Rejected(e)),
+        UpdateResponse::Staged => Ok(staged(device, image)),
+    }
+}
+
+/// Report an image the device keeps without installing it.
+fn staged(device: &DeviceId, image: &FirmwareImage) -> FirmwareStatus {
+    warn!(
+        "{device}: Firmware {:?} is staged, but the device can't install it",
+        image.version
+    );
+    FirmwareStatus::Staged
+}
+
+/// Get the next offset from an update response.
+fn progress(response: UpdateResponse) -> Result<u32, FirmwareUpdateError> {
+    match response {
+        UpdateResponse::Progress(offset) => Ok(offset),
+        UpdateResponse::Done | UpdateResponse::Staged => {
+            Err(FirmwareUpdateError::Rejected(UpdateError::NotStarted))
+        }
+        UpdateResponse::Error(e) => Err(FirmwareUpdateError::Rejected(e)),
+    }
+}
+
+/// Send a request, retrying when it gets no answer. All update requests are idempotent so this is
+/// safe. Errors reported by the device are returned right away.
+async fn request<E>(
+    client: &HostClient<FatalError>,
+    req: &E::Request,
+    timeout_after: Duration,
+) -> Result<E::Response, FirmwareUpdateError>
+where
+    E: Endpoint,
+    E::Request: Serialize + Schema,
+    E::Response: DeserializeOwned + Schema,
+{
+    for _ in 0..MAX_RETRIES {
+        match timeout(timeout_after, client.send_resp::<E>(req)).await {
+            Ok(Ok(response)) => return Ok(response),
+            Ok(Err(HostErr::Closed)) => {
+                debug!(
+                    "Firmware update request {} got no answer, retrying",
+                    E::PATH
+                );
+            }
+            Ok(Err(e)) => {
+                warn!("Firmware update request {} failed: {e:?}", E::PATH);
+                return Err(FirmwareUpdateError::Failed(e));
+            }
+            Err(_timeout) => {
+                debug!("Firmware update request {} timed out, retrying", E::PATH);
+            }
+        }
+    }
+
+    Err(FirmwareUpdateError::NoResponse)
+}
@@ -0,0 +1,58 @@
+//! Failed DTLS handshakes, reported as connection events and counted per reason.
+
+use super::edtls::ReceiveError;
+use std::{collections::BTreeMap, sync::Mutex};
+
+/// Why a DTLS handshake failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, thiserror::Error)]
+pub enum HandshakeError {
+    /// The device stopped answering during the handshake.
+    #[error("the device stopped answering")]
+    TimedOut,
+    /// The ingress cut the handshake short, e.g. when shutting down.
+    #[error("the handshake was aborted")]
+    Aborted,
+    /// The messages of the device could not be decrypted or verified, it most likely uses the
+    /// wrong key.
+    #[error("the device used the wrong key")]
+    BadKey,
+    /// The device sent a handshake message larger than the ingress accepts.
+    #[error("the device sent an oversized message")]
+    Oversized,
+    /// The device offered no PSK identity the ingress knows, no handshake was started.
+    #[error("the device offered an unknown identity")]
+    UnknownIdentity,
+    /// The source started too many handshakes recently, or too many are running, no handshake
+    /// was started.
+    #[error("too many handshakes")]
+    RateLimited,
+}
+
+impl HandshakeError {
+    /// Classify a failed handshake. The DTLS errors don't tell if the transport failed, so that
+    /// is given separately.
+    pub(crate) fn new(transport: Option<ReceiveError>) -> Self {
+        match transport {
+            Some(ReceiveError::TimedOut) => Self::TimedOut,
+            Some(ReceiveError::Closed) => Self::Aborted,
+            Some(ReceiveError::Oversized) => Self::Oversized,
+            None => Self::BadKey,
+        }
+    }
+}
+
+/// Number of failed handshakes per reason.
+#[derive(Default)]
+pub(crate) struct HandshakeFailures(Mutex<BTreeMap<HandshakeError, u64>>);
+
+impl HandshakeFailures {
+    /// Count a failed handshake.
+    pub fn count(&self, reason: HandshakeError) {
+        *self.0.lock().unwrap().entry(reason).or_default() += 1;
+    }
+
+    /// The counts so far, reasons that never happened are left out.
+    pub fn snapshot(&self) -> BTreeMap<HandshakeError, u64> {
+        self.0.lock().unwrap().clone()
+    }
+}
@@ -0,0 +1,229 @@
+//! Heartbeat based health tracking of devices.
+//!
+//! Every device sends a heartbeat with an increasing sequence number. Gaps in the sequence are
+//! counted as lost heartbeats, a sequence that starts over means the device rebooted, and no
+//! heartbeat for a while marks the device as stale. Heartbeats arrive over UDP, so one that is
+//! late or duplicated is ignored rather than taken for a reboot. The registry outlives sessions,
+//! so a device that reconnects after a dropped link is told apart from one that rebooted.
+
+use super::{Connection, DeviceId};
+use rpc_definition::topics::heartbeat::Health;
+use rustc_hash::FxHashMap;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// How far back the sequence number of a heartbeat may go before it's taken as a reboot, for
+/// firmware that does not report its uptime.
+const REORDER_WINDOW: u32 = 32;
+
+/// Health of a device, as seen from its heartbeats.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeviceHealth {
+    /// Number of heartbeats received.
+    pub received: u64,
+    /// Number of heartbeats that never arrived, based on gaps in the sequence numbers.
+    pub lost: u64,
+    /// Number of times the device was seen rebooting.
+    pub reboots: u64,
+    /// Sequence number of the last heartbeat.
+    pub last_sequence_number: Option<u32>,
+    /// When the last heartbeat arrived.
+    pub last_heartbeat: Option<SystemTime>,
+    /// The device missed too many heartbeats in a row.
+    pub stale: bool,
+    /// The health the device reported in its last heartbeat, `None` for firmware that does not
+    /// report it.
+    pub report: Option<Health>,
+    /// When the device booted, from the uptime it reported in its last heartbeat.
+    booted: Option<Instant>,
+}
+
+impl DeviceHealth {
+    /// Estimate of the packet loss, between 0 and 1.
+    pub fn loss(&self) -> f32 {
+        let total = self.received + self.lost;
+
+        if total == 0 {
+            0.
+        } else {
+            self.lost as f32 / total as f32
+        }
+    }
+}
+
+/// Health of all devices that have been connected.
+pub(crate) struct HealthRegistry {
+    devices: Mutex<FxHashMap<DeviceId, DeviceHealth>>,
+    heartbeat_interval: Duration,
+    /// A device is stale after this long without a heartbeat.
+    pub stale_after: Duration,
+}
+
+impl HealthRegistry {
+    pub fn new(heartbeat_interval: Duration, missed_heartbeats: u32) -> Self {
+        Self {
+            devices: Mutex::new(FxHashMap::default()),
+            heartbeat_interval,
+            stale_after: heartbeat_interval * missed_heartbeats,
+        }
+    }
+
+    /// The health of a device, if it ever connected.
+    pub fn get(&self, device: &DeviceId) -> Option<DeviceHealth> {
+        self.devices.lock().unwrap().get(device).cloned()
+    }
+
+    /// Register a heartbeat, returns the events it caused.
+    pub fn heartbeat(
+        &self,
+        device: &DeviceId,
+        sequence_number: u32,
+        report: Option<Health>,
+    ) -> Vec<Connection> {
+        let mut devices = self.devices.lock().unwrap();
+        let health = devices.entry(device.clone()).or_default();
+        let mut events = Vec::new();
+        let booted = report
+            .as_ref()
+            .and_then(|report| Instant::now().checked_sub(Duration::from_millis(report.uptime_ms)));
+
+        match health.last_sequence_number {
+            Some(last) if sequence_number <= last => {
+                let rebooted = match (health.booted, booted) {
+                    // A late heartbeat moves the boot time forward by how late it is, about an
+                    // interval per heartbeat it is behind. A reboot moves it by the uptime before
+                    // it, which is more than that.
+                    (Some(before), Some(now)) => {
+                        let late = self.heartbeat_interval * (last - sequence_number)
+                            + self.heartbeat_interval / 2;
+                        now.saturating_duration_since(before) > late
+                    }
+                    _ => last - sequence_number > REORDER_WINDOW,
+                };
+
+                if !rebooted {
+                    // Late or duplicated, there is nothing new in it.
+                    return events;
+                }
+
+                health.reboots += 1;
+                events.push(Connection::Rebooted(device.clone()));
+            }
+            Some(last) => health.lost += (sequence_number - last - 1) as u64,
+            None => {}
+        }
+
+        if health.stale {
+            health.stale = false;
+            events.push(Connection::Recovered(device.clone()));
+        }
+
+        health.received += 1;
+        health.last_sequence_number = Some(sequence_number);
+        health.last_heartbeat = Some(SystemTime::now());
+        health.report = report;
+        health.booted = booted;
+
+        events
+    }
+
+    /// A new session with the device started, whether the old one went stale doesn't matter to it.
+    pub fn connected(&self, device: &DeviceId) {
+        if let Some(health) = self.devices.lock().unwrap().get_mut(device) {
+            health.stale = false;
+        }
+    }
+
+    /// The device has not sent a heartbeat for `stale_after`, returns the event if this is news.
+    pub fn missed(&self, device: &DeviceId) -> Option<Connection> {
+        let mut devices = self.devices.lock().unwrap();
+        let health = devices.entry(device.clone()).or_default();
+
+        if health.stale {
+            return None;
+        }
+
+        health.stale = true;
+        Some(Connection::Stale(device.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_secs(2);
+
+    fn report(uptime_ms: u64) -> Option<Health> {
+        Some(Health {
+            version: 1,
+            uptime_ms,
+            ethernet_tx_free: 1,
+            sleep_free: 8,
+            eth_rx_frames: 0,
+            eth_tx_frames: 0,
+            eth_rx_errors: 0,
+            eth_rx_missed: 0,
+            net_dropped: 0,
+            dtls_errors: 0,
+            link_up: true,
+        })
+    }
+
+    #[test]
+    fn late_and_duplicated_heartbeats_are_not_reboots() {
+        let registry = HealthRegistry::new(INTERVAL, 2);
+        let device = DeviceId::from("sim-0");
+
+        // The heartbeats all arrive at once, as if the last of them is the one on time.
+        registry.heartbeat(&device, 10, report(20_000));
+        registry.heartbeat(&device, 11, report(22_000));
+        assert!(registry.heartbeat(&device, 11, report(22_000)).is_empty());
+        assert!(registry.heartbeat(&device, 9, report(18_000)).is_empty());
+        // Without uptime, only going back further than reordering can explain counts.
+        assert!(registry.heartbeat(&device, 5, None).is_empty());
+
+        let health = registry.get(&device).unwrap();
+        assert_eq!(health.reboots, 0);
+        assert_eq!(health.received, 2);
+        assert_eq!(health.last_sequence_number, Some(11));
+    }
+
+    #[test]
+    fn a_sequence_that_starts_over_is_a_reboot() {
+        let registry = HealthRegistry::new(INTERVAL, 2);
+        let device = DeviceId::from("sim-0");
+
+        // Up for a minute before the reboot, the heartbeats started when it got an address.
+        registry.heartbeat(&device, 10, report(60_000));
+        assert_eq!(
+            registry.heartbeat(&device, 1, report(2_000)),
+            [Connection::Rebooted(device.clone())]
+        );
+
+        registry.heartbeat(&device, 100, None);
+        assert_eq!(
+            registry.heartbeat(&device, 1, None),
+            [Connection::Rebooted(device.clone())]
+        );
+        assert_eq!(registry.get(&device).unwrap().reboots, 2);
+    }
+
+    #[test]
+    fn a_new_session_is_not_recovered() {
+        let registry = HealthRegistry::new(INTERVAL, 2);
+        let device = DeviceId::from("sim-0");
+
+        registry.heartbeat(&device, 10, report(20_000));
+        assert_eq!(
+            registry.missed(&device),
+            Some(Connection::Stale(device.clone()))
+        );
+
+        registry.connected(&device);
+        assert!(registry.heartbeat(&device, 11, report(22_000)).is_empty());
+        assert!(!registry.get(&device).unwrap().stale);
+    }
+}
@@ -0,0 +1,163 @@
+//! Calls from the public API, sent with sequence numbers of the ingress so they can be cancelled.
+//!
+//! `HostClient::send_resp` keeps the sequence number of a call to itself, so a call that is dropped
+//! or times out can't be told to the device, which then spends its capacity on a response nobody
+//! waits for. Calls from the public API are instead sent with a sequence number from here, the RPC
+//! worker hands their responses back, and a call dropped before its response arrived sends a
+//! `Cancel` to the device.
+
+use rpc_definition::{
+    cancel::{Cancel, TopicCancel},
+    postcard_rpc::{
+        host_client::{HostClient, HostErr, RpcFrame},
+        Endpoint, Key, Topic,
+    },
+    wire_error::{FatalError, ERROR_KEY},
+};
+use rustc_hash::FxHashMap;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::sync::oneshot;
+
+/// Sequence numbers of the ingress have the top bit set, out of the way of those `HostClient`
+/// counts up from zero.
+const SEQ_NO_BIT: u32 = 0x8000_0000;
+
+/// The calls of one session that wait for a response.
+pub(crate) struct PendingCalls {
+    next_seq_no: AtomicU32,
+    /// `None` once the session has ended.
+    waiting: Mutex<Option<FxHashMap<u32, oneshot::Sender<RpcFrame>>>>,
+}
+
+impl PendingCalls {
+    pub fn new() -> Self {
+        Self {
+            next_seq_no: AtomicU32::new(0),
+            waiting: Mutex::new(Some(FxHashMap::default())),
+        }
+    }
+
+    /// Hand a response to the call waiting for it, the frame is given back if there is none.
+    pub fn complete(&self, frame: RpcFrame) -> Result<(), RpcFrame> {
+        let waiting = self
+            .waiting
+            .lock()
+            .unwrap()
+            .as_mut()
+            .and_then(|waiting| waiting.remove(&frame.header.seq_no));
+
+        match waiting {
+            Some(response) => {
+                let _ = response.send(frame);
+                Ok(())
+            }
+            None => Err(frame),
+        }
+    }
+
+    /// The session has ended, fail all calls that wait for a response and any new ones.
+    pub fn close(&self) {
+        self.waiting.lock().unwrap().take();
+    }
+
+    /// Call `E` on the device. If this is dropped before the response arrives, e.g. as it timed out,
+    /// the call is cancelled on the device through `client`, the session it was sent on.
+    pub async fn call<E>(
+        self: &Arc<Self>,
+        client: &HostClient<FatalError>,
+        request: &E::Request,
+    ) -> Result<E::Response, HostErr<FatalError>>
+    where
+        E: Endpoint,
+        E::Request: Serialize,
+        E::Response: DeserializeOwned,
+    {
+        let (seq_no, response) = self.register();
+        let _cancel = CancelOnDrop {
+            client: client.clone(),
+            pending: self.clone(),
+            seq_no,
+        };
+
+        client
+            .publish::<RequestOf<E>>(seq_no, request)
+            .await
+            .map_err(|_closed| HostErr::Closed)?;
+        let frame = response.await.map_err(|_closed| HostErr::Closed)?;
+
+        match frame.header.key {
+            key if key == E::RESP_KEY => Ok(postcard::from_bytes(&frame.body)?),
+            key if key == ERROR_KEY => Err(HostErr::Wire(postcard::from_bytes(&frame.body)?)),
+            _ => Err(HostErr::BadResponse),
+        }
+    }
+
+    /// A sequence number for a new call, and where its response arrives.
+    fn register(&self) -> (u32, oneshot::Receiver<RpcFrame>) {
+        let seq_no = self.next_seq_no.fetch_add(1, Ordering::Relaxed) | SEQ_NO_BIT;
+        let (response, receiver) = oneshot::channel();
+
+        // After `close` the sender is dropped right away, failing the call.
+        if let Some(waiting) = self.waiting.lock().unwrap().as_mut() {
+            waiting.insert(seq_no, response);
+        }
+
+        (seq_no, receiver)
+    }
+
+    /// Stop waiting for the response of a call, returns `false` if it already arrived.
+    fn forget(&self, seq_no: u32) -> bool {
+        self.waiting
+            .lock()
+            .unwrap()
+            .as_mut()
+            .is_some_and(|waiting| waiting.remove(&seq_no).is_some())
+    }
+}
+
+/// Sends a `Cancel` for a call that is dropped while it waits for its response.
+struct CancelOnDrop {
+    client: HostClient<FatalError>,
+    pending: Arc<PendingCalls>,
+    seq_no: u32,
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        // Answered, or the session is gone.
+        if !self.pending.forget(self.seq_no) {
+            return;
+        }
+
+        // Without a runtime there is no one left to tell.
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+
+        let client = self.client.clone();
+        let seq_no = self.seq_no;
+
+        runtime.spawn(async move {
+            let _ = client
+                .publish::<TopicCancel>(seq_no, &Cancel { seq_no })
+                .await;
+        });
+    }
+}
+
+/// The request of an endpoint as a topic, so `HostClient::publish` sends it with our sequence
+/// number.
+struct RequestOf<E>(PhantomData<E>);
+
+impl<E: Endpoint> Topic for RequestOf<E> {
+    type Message = E::Request;
+    const PATH: &'static str = E::PATH;
+    const TOPIC_KEY: Key = E::REQ_KEY;
+}
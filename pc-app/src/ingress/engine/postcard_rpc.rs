@@ -1,15 +1,25 @@
-use std::{convert::Infallible, net::IpAddr};
+use std::convert::Infallible;
 
 use embedded_dtls::{ApplicationDataReceiver, ApplicationDataSender};
-use log::{debug, trace};
+use log::{debug, trace, warn};
 use rpc_definition::{
+    fragment::{Fragmenter, Reassembler, ReassemblyError, MAX_FRAGMENTED_SIZE, MAX_RECORD_SIZE},
     postcard_rpc::{
-        headered::extract_header_from_bytes,
+        headered::{extract_header_from_bytes, to_stdvec_keyed},
         host_client::{HostClient, ProcessError, RpcFrame, WireContext},
+        Topic,
     },
-    wire_error::FatalError,
+    topics::heartbeat::{self, Health, HeartbeatAck, TopicHeartbeat, TopicHeartbeatAck},
+    wire_error::{FatalError, ERROR_KEY},
 };
 use rustc_hash::FxHashMap;
+use tokio::sync::mpsc;
+
+use super::{pending::PendingCalls, DeviceId};
+
+/// Largest message received from a device, larger ones are dropped and fail the call waiting for
+/// them with `FatalError::MessageTooLarge`.
+const MAX_MESSAGE_SIZE: usize = 8 * 1024;
 
 pub trait HostClientExt {
     fn new_edtls(
@@ -31,7 +41,9 @@ pub struct HostClientEdtlsWorker {
 impl HostClientEdtlsWorker {
     pub async fn run<Receiver, Sender>(
         self,
-        ip: IpAddr,
+        device: &DeviceId,
+        heartbeats: &mpsc::Sender<(u32, Option<Health>)>,
+        pending: &PendingCalls,
         rx_receiver: &mut Receiver,
         tx_sender: &mut Sender,
     ) -> Result<Infallible, anyhow::Error>
@@ -47,6 +59,9 @@ impl HostClientEdtlsWorker {
         } = self.w;
 
         let mut subs = FxHashMap::default();
+        let mut storage = vec![0; MAX_MESSAGE_SIZE];
+        let mut reassembler = Reassembler::new(&mut storage);
+        let mut record = [0; MAX_RECORD_SIZE];
 
         loop {
             // Adapted from `cobs_wire_worker`.
@@ -55,7 +70,7 @@ impl HostClientEdtlsWorker {
                 sub = new_subs.recv() => {
                     // Receiver returns None when all Senders have hung up.
                     let Some(new_subscription) = sub else {
-                        return Err(anyhow::anyhow!("{ip}: Subscription channel sender closed - HostClient dropped?"));
+                        return Err(anyhow::anyhow!("{device}: Subscription channel sender closed - HostClient dropped?"));
                     };
 
                     subs.insert(new_subscription.key, new_subscription.tx);
@@ -63,13 +78,20 @@ impl HostClientEdtlsWorker {
                 out = outgoing.recv() => {
                     // Receiver returns None when all Senders have hung up.
                     let Some(msg) = out else {
-                        return Err(anyhow::anyhow!("{ip}: Outgoing channel sender closed - HostClient dropped"));
+                        return Err(anyhow::anyhow!("{device}: Outgoing channel sender closed - HostClient dropped"));
                     };
 
-                    // Send message via the UDP socket.
-                    // TODO: Fix comments
-                    if let Err(_) = tx_sender.send(msg.to_bytes()).await {
-                        return Err(anyhow::anyhow!("{ip}: Edtls tx_receiver closed - connection dropped?"));
+                    // Send message via the UDP socket, in fragments if it does not fit a record.
+                    let msg = msg.to_bytes();
+                    if msg.len() > MAX_FRAGMENTED_SIZE {
+                        warn!("{device}: Message of {} bytes is too large to send, it is dropped", msg.len());
+                    }
+
+                    let mut fragmenter = Fragmenter::new(&msg);
+                    while let Some(record) = fragmenter.next_record(&mut record) {
+                        if tx_sender.send(record).await.is_err() {
+                            return Err(anyhow::anyhow!("{device}: Edtls tx_receiver closed - connection dropped?"));
+                        }
                     }
                 }
                 // FIXME: This is really ugly but it works
@@ -79,20 +101,52 @@ impl HostClientEdtlsWorker {
                     {
                         // Make sure the UDP RX worker is still alive.
                         let Ok(packet) = rx_receiver.peek().await else {
-                            return Err(anyhow::anyhow!("{ip}: Edtls rx_sender closed - connection dropped?"));
+                            return Err(anyhow::anyhow!("{device}: Edtls rx_sender closed - connection dropped?"));
                         };
 
                         let packet = packet.as_ref();
 
-                        trace!("{ip}: Received packet {packet:02x?}");
+                        trace!("{device}: Received packet {packet:02x?}");
 
-                        // Attempt to extract a header so we can get the sequence number.
                         // Since UDP is already full packets, we don't need to use COBS or similar, a
-                        // packet is a full message.
-                        if let Ok((hdr, body)) = extract_header_from_bytes(&packet) {
+                        // packet is a full message or a fragment of one.
+                        let message = match reassembler.push(packet) {
+                            Ok(message) => message.map(<[u8]>::to_vec),
+                            Err(ReassemblyError::TooLarge { seq_no }) => {
+                                warn!("{device}: Message {seq_no} is too large, it is dropped");
+                                // A call waiting for it fails right away, instead of timing out.
+                                to_stdvec_keyed(seq_no, ERROR_KEY, &FatalError::MessageTooLarge).ok()
+                            }
+                            Err(ReassemblyError::Missing { seq_no }) => {
+                                warn!("{device}: Message {seq_no} lost a fragment, it is dropped");
+                                to_stdvec_keyed(seq_no, ERROR_KEY, &FatalError::WireFailure).ok()
+                            }
+                            Err(ReassemblyError::Malformed) => {
+                                debug!("{device}: Malformed packet {packet:x?}");
+                                None
+                            }
+                        };
+
+                        // Attempt to extract a header so we can get the sequence number.
+                        if let Some(Ok((hdr, body))) = message.as_deref().map(extract_header_from_bytes) {
                             // Got a header, turn it into a frame.
                             let frame = RpcFrame { header: hdr.clone(), body: body.to_vec() };
 
+                            // The engine tracks the health of devices from their heartbeats, and
+                            // acknowledges them so the device knows the session is alive.
+                            if hdr.key == TopicHeartbeat::TOPIC_KEY {
+                                if let Some((heartbeat, health)) = heartbeat::decode(body) {
+                                    let _ = heartbeats.try_send((heartbeat.sequence_number, health));
+
+                                    let ack = HeartbeatAck { sequence_number: heartbeat.sequence_number };
+                                    if let Ok(ack) = to_stdvec_keyed(hdr.seq_no, TopicHeartbeatAck::TOPIC_KEY, &ack) {
+                                        if tx_sender.send(ack).await.is_err() {
+                                            return Err(anyhow::anyhow!("{device}: Edtls tx_receiver closed - connection dropped?"));
+                                        }
+                                    }
+                                }
+                            }
+
                             // Give priority to subscriptions. TBH I only do this because I know a hashmap
                             // lookup is cheaper than a waitmap search.
                             if let Some(tx) = subs.get_mut(&hdr.key) {
@@ -101,14 +155,13 @@ impl HostClientEdtlsWorker {
                                     // But if sending failed, the listener is gone, so drop it.
                                     subs.remove(&hdr.key);
                                 }
-                            } else {
-                                // Wake the given sequence number. If the WaitMap is closed, we're done here
+                            } else if let Err(frame) = pending.complete(frame) {
+                                // Not a call from the public API, wake the given sequence number.
+                                // If the WaitMap is closed, we're done here
                                 if let Err(ProcessError::Closed) = incoming.process(frame) {
-                                    return Err(anyhow::anyhow!("{ip}: Incoming channel receiver closed - HostClient dropped"));
+                                    return Err(anyhow::anyhow!("{device}: Incoming channel receiver closed - HostClient dropped"));
                                 }
                             }
-                        } else {
-                            debug!("{ip}: Malformed packet {packet:x?}");
                         }
                     }
                     rx_receiver.pop().ok();
@@ -0,0 +1,80 @@
+//! Timing information of sessions, exposed through the device listing.
+
+use super::DeviceId;
+use std::{
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+/// A connected device and the timing of its session.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectedDevice {
+    /// The device.
+    pub id: DeviceId,
+    /// The IP the device is connected from.
+    pub ip: IpAddr,
+    /// When the DTLS handshake completed.
+    pub connected_at: SystemTime,
+    /// How long the DTLS handshake took.
+    pub handshake_duration: Duration,
+    /// When the last datagram arrived from the device.
+    pub last_packet: SystemTime,
+    /// When the last heartbeat arrived from the device, if any.
+    pub last_heartbeat: Option<SystemTime>,
+}
+
+/// Timing of one session, updated by the worker as packets arrive.
+pub(crate) struct SessionStats(Mutex<Timing>);
+
+#[derive(Clone, Copy)]
+struct Timing {
+    connected_at: SystemTime,
+    handshake_duration: Duration,
+    last_packet: SystemTime,
+    last_heartbeat: Option<SystemTime>,
+}
+
+impl SessionStats {
+    pub fn new() -> Self {
+        let now = SystemTime::now();
+
+        Self(Mutex::new(Timing {
+            connected_at: now,
+            handshake_duration: Duration::ZERO,
+            last_packet: now,
+            last_heartbeat: None,
+        }))
+    }
+
+    /// The handshake is done and the session is established.
+    pub fn connected(&self, handshake_duration: Duration) {
+        let mut timing = self.0.lock().unwrap();
+        timing.connected_at = SystemTime::now();
+        timing.handshake_duration = handshake_duration;
+    }
+
+    /// A datagram arrived from the device.
+    pub fn packet_received(&self) {
+        self.0.lock().unwrap().last_packet = SystemTime::now();
+    }
+
+    /// A heartbeat arrived from the device.
+    pub fn heartbeat_received(&self) {
+        self.0.lock().unwrap().last_heartbeat = Some(SystemTime::now());
+    }
+
+    /// The public view of the session.
+    pub fn snapshot(&self, id: &DeviceId, ip: IpAddr) -> ConnectedDevice {
+        let timing = *self.0.lock().unwrap();
+
+        ConnectedDevice {
+            id: id.clone(),
+            ip,
+            connected_at: timing.connected_at,
+            handshake_duration: timing.handshake_duration,
+            last_packet: timing.last_packet,
+            last_heartbeat: timing.last_heartbeat,
+        }
+    }
+}
@@ -1,11 +1,10 @@
-use super::{api_handle, engine};
+use super::{api::ConnectedDevice, engine, DeviceId, Ingress};
 use log::*;
-use once_cell::sync::Lazy;
-use rpc_definition::topics::{
-    heartbeat::{Heartbeat, TopicHeartbeat},
-    some_data::{SomeData, TopicSomeData},
+use rpc_definition::{
+    postcard_rpc::{host_client::HostClient, Topic},
+    topics::{heartbeat::TopicHeartbeat, some_data::TopicSomeData},
+    wire_error::FatalError,
 };
-use std::net::IpAddr;
 use tokio::sync::broadcast;
 
 pub use engine::Connection;
@@ -20,97 +19,138 @@ where
     /// Receive a value from a subscription.
     pub async fn recv(&mut self) -> Result<T, SubscriptionError> {
         self.0.recv().await.map_err(|e| match e {
-            broadcast::error::RecvError::Closed => unreachable!("We don't close the channel"),
+            broadcast::error::RecvError::Closed => SubscriptionError::Closed,
             broadcast::error::RecvError::Lagged(_) => SubscriptionError::MessagesDropped,
         })
     }
 }
 
-/// Get an event on connection change.
-pub fn connection() -> Subscription<Connection> {
-    Subscription(engine::CONNECTION_SUBSCRIBER.subscribe())
+impl Ingress {
+    /// Get an event on connection change.
+    pub fn connection(&self) -> Subscription<Connection> {
+        Subscription(self.inner.engine.connections.subscribe())
+    }
+
+    /// Get the currently connected devices, and an event on every connection change after that.
+    ///
+    /// No change is missed or seen twice between the snapshot and the events.
+    pub async fn connection_with_snapshot(&self) -> ConnectionSnapshot {
+        // Connection events are sent while holding the lock, so subscribing under the lock is
+        // atomic with the snapshot.
+        let clients = self.inner.engine.clients.read().await;
+
+        ConnectionSnapshot {
+            devices: clients
+                .iter()
+                .map(|(device, session)| session.stats.snapshot(device, session.ip))
+                .collect(),
+            changes: Subscription(self.inner.engine.connections.subscribe()),
+        }
+    }
+}
+
+/// The connected devices at one point in time, and all changes since.
+pub struct ConnectionSnapshot {
+    /// The devices that were connected when the snapshot was taken.
+    pub devices: Vec<ConnectedDevice>,
+    /// Connection changes after the snapshot.
+    pub changes: Subscription<Connection>,
 }
 
 /// Errors on subscription.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum SubscriptionError {
-    /// The requested IP did not exist in the system.
-    IpNotFound,
+    /// The requested device did not exist in the system.
+    DeviceNotFound,
     /// Some messages were dropped due to queue being full, `recv` again to get the oldest value.
     MessagesDropped,
+    /// The ingress has shut down and every handle to it is dropped, no more messages will come.
+    Closed,
 }
 
-// TODO: The entire module from here could be a macro.
-// ```
-// subscriptions!(
-//  /* [method name, data type, static name, RPC queue depth, broadcast queue depth], */
-//     [heartbeat, Heartbeat, HEARTBEAT_SUBSCRIBER, 10, 100],
-//     [some_data, SomeData, SOMEDATA_SUBSCRIBER, 10, 100],
-// );
-// ```
+/// Generates the plumbing of all topics from one declaration per topic:
+///
+/// - a broadcast channel in [`Topics`],
+/// - a public method on [`Ingress`] returning a typed [`Subscription`],
+/// - a forwarder in `forward_topics` that moves the topic's messages from each device to the
+///   broadcast channel.
+///
+/// Each entry is `[method name, topic type, RPC queue depth]`, with the doc comment of the method.
+macro_rules! subscriptions {
+    ($($(#[$doc:meta])* [$name:ident, $topic:ty, $rpc_queue_depth:expr],)*) => {
+        /// Broadcast channels of all topics of one ingress.
+        pub(crate) struct Topics {
+            $($name: broadcast::Sender<(DeviceId, <$topic as Topic>::Message)>,)*
+        }
 
-/// Global subscription for heartbeats.
-pub(crate) static HEARTBEAT_SUBSCRIBER: Lazy<broadcast::Sender<(IpAddr, Heartbeat)>> =
-    Lazy::new(|| broadcast::channel(100).0);
+        impl Topics {
+            pub fn new(queue_depth: usize) -> Self {
+                Self {
+                    $($name: broadcast::channel(queue_depth).0,)*
+                }
+            }
+        }
 
-/// Example public topic subscription (unsolicited messages).
-///
-/// Get heartbeats from a device.
-pub async fn heartbeat() -> Subscription<(IpAddr, Heartbeat)> {
-    Subscription(HEARTBEAT_SUBSCRIBER.subscribe())
+        impl Ingress {
+            $(
+                $(#[$doc])*
+                pub async fn $name(&self) -> Subscription<(DeviceId, <$topic as Topic>::Message)> {
+                    Subscription(self.inner.topics.$name.subscribe())
+                }
+            )*
+        }
+
+        /// Subscribe to all topics of a device and forward them until the device disconnects.
+        async fn forward_topics(ingress: Ingress, device: DeviceId, api: HostClient<FatalError>) {
+            $(
+                let Ok(mut $name) = api.subscribe::<$topic>($rpc_queue_depth).await else {
+                    return;
+                };
+            )*
+
+            let topics = &ingress.inner.topics;
+
+            tokio::select! {
+                $(
+                    _ = async {
+                        while let Some(s) = $name.recv().await {
+                            let _ = topics.$name.send((device.clone(), s));
+                        }
+                    } => {}
+                )*
+            }
+        }
+    };
 }
 
-/// Global subscription for some data.
-pub(crate) static SOMEDATA_SUBSCRIBER: Lazy<broadcast::Sender<(IpAddr, SomeData)>> =
-    Lazy::new(|| broadcast::channel(100).0);
+subscriptions! {
+    /// Example public topic subscription (unsolicited messages).
+    ///
+    /// Get heartbeats from a device.
+    [heartbeat, TopicHeartbeat, 10],
 
-/// Example public topic subscription (unsolicited messages).
-///
-/// Get some data from a device.
-pub async fn some_data() -> Subscription<(IpAddr, SomeData)> {
-    Subscription(SOMEDATA_SUBSCRIBER.subscribe())
+    /// Example public topic subscription (unsolicited messages).
+    ///
+    /// Get some data from a device.
+    [some_data, TopicSomeData, 10],
 }
 
 /// This tracks unsolicited messages and sends them on the correct endpoint, in the end
 /// consolidating all messages of the same type into one stream of `(source, message)`.
-pub(crate) async fn subscription_consolidation() {
+pub(crate) async fn subscription_consolidation(ingress: Ingress) {
+    let mut connection = ingress.connection();
+
     loop {
         // On every new connection, subscribe to data for that device.
-        match connection().recv().await {
-            Ok(Connection::New(ip)) => {
-                let Ok(api) = api_handle(&ip).await else {
-                    continue;
-                };
-
-                // Get subscriptions to all topic
-                let Ok(mut heartbeat) = api.subscribe::<TopicHeartbeat>(10).await else {
-                    continue;
-                };
-
-                let Ok(mut some_data) = api.subscribe::<TopicSomeData>(10).await else {
+        match connection.recv().await {
+            Ok(Connection::New { device, .. }) => {
+                let Ok(api) = ingress.api_handle(&device).await else {
                     continue;
                 };
 
-                // TODO: Add next subscription here.
-
-                tokio::spawn(async move {
-                    tokio::select! {
-                        _ = async {
-                            while let Some(s) = heartbeat.recv().await {
-                                let _ = HEARTBEAT_SUBSCRIBER.send((ip, s));
-                            }
-                        } => {}
-                        _ = async {
-                            while let Some(s) = some_data.recv().await {
-                                let _ = SOMEDATA_SUBSCRIBER.send((ip, s));
-                            }
-                        } => {}
-
-                        // TODO: Add next subscription forwarder here.
-                    }
-                });
+                tokio::spawn(forward_topics(ingress.clone(), device, api));
             }
-            Ok(Connection::Closed(_)) => {}
+            Ok(_) => {}
             Err(_) => error!("subscription_consolidation: Unable to keep up with new connecitons"),
         }
     }
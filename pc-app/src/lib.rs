@@ -0,0 +1,4 @@
+//! A small example ingress handling many concurrent connections to embedded devices connected via
+//! UDP, where each device implementes `postcard-rpc` for RPCs and unsoliced messages (topics).
+
+pub mod ingress;
@@ -1,46 +1,98 @@
 //! A small example ingress handling many concurrent connections to embedded devices connected via
 //! UDP, where each device implementes `postcard-rpc` for RPCs and unsoliced messages (topics).
 //!
-//! Note: This app uses IP as identifier for each device, you should not do that when running UDP,
-//! as UDP source addresses are trivial to spoof.
+//! Devices are identified by their DTLS PSK identity, never by their IP address, as UDP source
+//! addresses are trivial to spoof and change with DHCP leases.
 
-use ingress::subscriptions::{connection, Connection};
 use log::*;
+use pc_app::ingress::{
+    credentials::PskStore,
+    subscriptions::{Connection, SubscriptionError},
+    DeviceId, Ingress,
+};
+use rpc_definition::{
+    discovery::DISCOVERY_PORT,
+    topics::time::{Time, TopicTime},
+};
 use std::{
-    net::IpAddr,
-    time::{Duration, Instant},
+    net::Ipv4Addr,
+    time::{Duration, Instant, SystemTime},
 };
 use tokio::{join, time::interval};
 
-// This is the library
-pub mod ingress;
-
 // This is the app using the library
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     pretty_env_logger::init();
 
+    // The device credentials file can be given as the first argument.
+    let psk_file = std::env::args().nth(1).unwrap_or_else(|| "psk.toml".into());
+    let credentials = PskStore::load(&psk_file)?;
+
     info!("Starting ingress");
-    tokio::spawn(ingress::run_ingress());
+    let ingress = Ingress::builder()
+        .credentials(credentials)
+        .announce(
+            (Ipv4Addr::BROADCAST, DISCOVERY_PORT),
+            Duration::from_secs(5),
+        )
+        .build()
+        .await?;
+
+    tokio::spawn(streaming_test(ingress.clone()));
+    tokio::spawn(broadcast_time(ingress.clone()));
+
+    tokio::select! {
+        _ = log_connections(&ingress) => {}
+        _ = tokio::signal::ctrl_c() => info!("Shutting down"),
+    }
 
-    // TODO: Use the API here.
-    let mut connecton = connection();
+    ingress.shutdown().await;
+    Ok(())
+}
 
-    tokio::spawn(streaming_test());
+/// Log connection changes and start the API tests on new connections.
+async fn log_connections(ingress: &Ingress) {
+    // TODO: Use the API here.
+    let mut connecton = ingress.connection();
 
     loop {
-        let Ok(connection) = connecton.recv().await else {
-            continue;
+        let connection = match connecton.recv().await {
+            Ok(connection) => connection,
+            Err(SubscriptionError::MessagesDropped) => {
+                error!("Connection subscription has lost messages!");
+                continue;
+            }
+            Err(e) => {
+                error!("Connection subscription ended: {e:?}");
+                return;
+            }
         };
 
         match connection {
-            Connection::New(ip) => {
-                info!("{ip}: New connection established.");
-
-                tokio::spawn(test_sleep_api(ip));
-                tokio::spawn(test_pingpong_api(ip));
+            Connection::New { device, info } => {
+                match info {
+                    Some(info) => info!(
+                        "{device}: New connection established, firmware {}.{}.{} ({}), up for {} ms.",
+                        info.firmware_version.major,
+                        info.firmware_version.minor,
+                        info.firmware_version.patch,
+                        info.git_hash,
+                        info.uptime_ms
+                    ),
+                    None => info!("{device}: New connection established."),
+                }
+
+                tokio::spawn(test_sleep_api(ingress.clone(), device.clone()));
+                tokio::spawn(test_pingpong_api(ingress.clone(), device));
+            }
+            Connection::Closed(device) => info!("{device}: Connection lost."),
+            Connection::Stale(device) => warn!("{device}: Missing heartbeats."),
+            Connection::Recovered(device) => info!("{device}: Heartbeats are back."),
+            Connection::Rebooted(device) => warn!("{device}: Device rebooted."),
+            Connection::HandshakeFailed { addr, reason } => {
+                warn!("{addr}: Handshake failed, {reason}.")
             }
-            Connection::Closed(ip) => info!("{ip}: Connection lost."),
         }
     }
 }
@@ -49,16 +101,16 @@ async fn main() -> anyhow::Result<()> {
 ///
 /// This is a command that will take as long as we request to finish, exemplifying a command that
 /// has processing delay associated with it.
-async fn test_sleep_api(ip: IpAddr) {
-    info!("{ip}: Sleep API test started.");
+async fn test_sleep_api(ingress: Ingress, device: DeviceId) {
+    info!("{device}: Sleep API test started.");
 
     loop {
         // `join!` over multiple commands in reverse order, this should still work as this
         // command can execute out-of-order.
         let (r1, r2, r3) = join!(
-            sleep_request(ip, Duration::from_millis(500)),
-            sleep_request(ip, Duration::from_millis(400)),
-            sleep_request(ip, Duration::from_millis(300)),
+            sleep_request(&ingress, &device, Duration::from_millis(500)),
+            sleep_request(&ingress, &device, Duration::from_millis(400)),
+            sleep_request(&ingress, &device, Duration::from_millis(300)),
         );
 
         if r1.is_err() || r2.is_err() || r3.is_err() {
@@ -68,17 +120,17 @@ async fn test_sleep_api(ip: IpAddr) {
 }
 
 /// Helper for sleep requests.
-async fn sleep_request(ip: IpAddr, sleep: Duration) -> Result<(), ()> {
-    match ingress::api::sleep(ip, sleep).await {
+async fn sleep_request(ingress: &Ingress, device: &DeviceId, sleep: Duration) -> Result<(), ()> {
+    match ingress.sleep(device, sleep).await {
         Ok(done) => {
             let dur = Duration::from_micros(
                 done.slept_for.seconds as u64 * 1000000 + done.slept_for.micros as u64,
             );
-            info!("{ip}: Sleep done! {dur:?}");
+            info!("{device}: Sleep done! {dur:?}");
             Ok(())
         }
         Err(e) => {
-            error!("{ip}: Sleep failed! Error = {e:?}");
+            error!("{device}: Sleep failed! Error = {e:?}");
             Err(())
         }
     }
@@ -87,8 +139,8 @@ async fn sleep_request(ip: IpAddr, sleep: Duration) -> Result<(), ()> {
 /// Test the ping pong API and measure round trip time.
 ///
 /// This exemplifies a command that answers directly.
-async fn test_pingpong_api(ip: IpAddr) {
-    info!("{ip}: Pingpong API test started.");
+async fn test_pingpong_api(ingress: Ingress, device: DeviceId) {
+    info!("{device}: Pingpong API test started.");
 
     let mut interval = interval(Duration::from_secs(1));
 
@@ -96,13 +148,13 @@ async fn test_pingpong_api(ip: IpAddr) {
         interval.tick().await;
 
         let now = Instant::now();
-        match ingress::api::ping(ip).await {
+        match ingress.ping(&device).await {
             Ok(_pong) => {
                 let elapsed = now.elapsed();
-                info!("{ip}: Pong! Round trip took {elapsed:?}");
+                info!("{device}: Pong! Round trip took {elapsed:?}");
             }
             Err(e) => {
-                error!("{ip}: Ping failed! Error = {e:?}");
+                error!("{device}: Ping failed! Error = {e:?}");
                 break;
             }
         }
@@ -112,15 +164,43 @@ async fn test_pingpong_api(ip: IpAddr) {
 /// Subscribe to the heartbeat topic and await streaming data to come.
 ///
 /// This exemplifies unsolicited data.
-async fn streaming_test() {
-    let mut heartbeat = ingress::subscriptions::heartbeat().await;
+async fn streaming_test(ingress: Ingress) {
+    let mut heartbeat = ingress.heartbeat().await;
 
     loop {
-        let Ok((ip, heartbeat)) = heartbeat.recv().await else {
-            error!("Subscription has lost messages!");
-            continue;
+        let (device, heartbeat) = match heartbeat.recv().await {
+            Ok(heartbeat) => heartbeat,
+            Err(SubscriptionError::MessagesDropped) => {
+                error!("Subscription has lost messages!");
+                continue;
+            }
+            Err(e) => {
+                error!("Heartbeat subscription ended: {e:?}");
+                return;
+            }
         };
 
-        info!("{ip}: Got heartbeat! {heartbeat:?}");
+        info!("{device}: Got heartbeat! {heartbeat:?}");
+    }
+}
+
+/// Publish the time to all devices every now and then.
+///
+/// This exemplifies a topic from the backend to the devices, that needs no answer.
+async fn broadcast_time(ingress: Ingress) {
+    let mut interval = interval(Duration::from_secs(10));
+
+    loop {
+        interval.tick().await;
+
+        let unix_time_ms = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let sent = ingress
+            .publish_all::<TopicTime>(&Time { unix_time_ms })
+            .await;
+
+        debug!("Published the time to {sent} devices");
     }
 }
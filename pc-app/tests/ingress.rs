@@ -0,0 +1,1015 @@
+//! End-to-end tests of the ingress against simulated devices.
+
+mod support;
+
+use pc_app::ingress::{
+    api::{ApiError, CallOptions},
+    credentials::PskStore,
+    subscriptions::{Connection, SubscriptionError},
+    BuildError, DeviceId, FirmwareImage, HandshakeError, Ingress,
+};
+use rpc_definition::{
+    discovery::Announcement,
+    endpoints::{
+        firmware_update::FirmwareVersion,
+        pingpong::{Ping, PingPongEndpoint, Pong},
+        sleep::{Sleep, SleepEndpoint},
+        stream::{StreamStatus, StreamTopic},
+    },
+    postcard_rpc::{endpoint, topic},
+    psk,
+    topics::{
+        heartbeat::HEALTH_VERSION,
+        time::{Time, TopicTime},
+    },
+    PROTOCOL_VERSION,
+};
+use std::{net::UdpSocket, time::Duration};
+use support::{within, TestBed, EVENT_TIMEOUT};
+use tokio::time::Instant;
+
+#[tokio::test]
+async fn device_connects_and_answers_ping() {
+    let mut bed = TestBed::start(&["sim-0"]).await;
+    let _device = bed.spawn("sim-0");
+
+    let device = bed.expect_new("sim-0").await;
+    bed.expect_ping(&device).await;
+}
+
+#[tokio::test]
+async fn devices_are_told_apart_by_identity() {
+    let mut bed = TestBed::start(&["sim-0", "sim-1", "sim-2"]).await;
+    let _devices = device_sim::spawn_many(bed.ingress.local_addr(), "sim", support::KEY, 3);
+
+    let mut connected = Vec::new();
+    for _ in 0..3 {
+        let Connection::New { device, .. } = bed.next_connection().await else {
+            panic!("expected a new connection");
+        };
+        connected.push(device);
+    }
+    connected.sort();
+
+    let expected: Vec<DeviceId> = ["sim-0", "sim-1", "sim-2"].map(DeviceId::from).into();
+    assert_eq!(connected, expected);
+
+    for device in &connected {
+        bed.expect_ping(device).await;
+    }
+}
+
+#[tokio::test]
+async fn unknown_identity_is_not_connected() {
+    let mut bed = TestBed::start(&["sim-0"]).await;
+    let _intruder = bed.spawn("intruder");
+    let _device = bed.spawn("sim-0");
+
+    // The only connection event is the known device.
+    bed.expect_new("sim-0").await;
+    assert_eq!(
+        bed.ingress.ping(&"intruder".into()).await,
+        Err(ApiError::DeviceNotFound)
+    );
+}
+
+#[tokio::test]
+async fn sleep_completes_out_of_order() {
+    let mut bed = TestBed::start(&["sim-0"]).await;
+    let _device = bed.spawn("sim-0");
+    let device = bed.expect_new("sim-0").await;
+
+    let start = Instant::now();
+    let timed_sleep = |ms| {
+        let ingress = bed.ingress.clone();
+        let device = device.clone();
+        async move {
+            ingress
+                .sleep(&device, Duration::from_millis(ms))
+                .await
+                .expect("sleep succeeds");
+            start.elapsed()
+        }
+    };
+
+    // Requested longest first, the responses must come back shortest first.
+    let (slow, medium, fast) = tokio::join!(timed_sleep(600), timed_sleep(400), timed_sleep(200));
+
+    assert!(fast < medium, "{fast:?} >= {medium:?}");
+    assert!(medium < slow, "{medium:?} >= {slow:?}");
+    assert!(slow < Duration::from_millis(600) + Duration::from_millis(500));
+}
+
+#[tokio::test]
+async fn timed_out_calls_are_cancelled_on_the_device() {
+    let mut bed = TestBed::start(&["sim-0"]).await;
+    let _device = bed.spawn("sim-0");
+    let device = bed.expect_new("sim-0").await;
+
+    // More long sleeps than the device can hold, each given up on long before it is done.
+    let long = Sleep {
+        seconds: 5,
+        micros: 0,
+    };
+    // The simulator holds 8 deferred responses, twice that would be refused without cancelling.
+    for _ in 0..16 {
+        let result = bed
+            .ingress
+            .call::<SleepEndpoint>(
+                &device,
+                &long,
+                CallOptions::default()
+                    .timeout(Duration::from_millis(100))
+                    .retries(0),
+            )
+            .await;
+        assert_eq!(result, Err(ApiError::NoResponse));
+    }
+
+    // Had they not been cancelled, the device would be over capacity.
+    within(
+        Duration::from_secs(1),
+        bed.ingress.sleep(&device, Duration::from_millis(100)),
+    )
+    .await
+    .expect("the sleep is not rejected");
+}
+
+#[tokio::test]
+async fn busy_devices_refuse_calls_with_a_retry_hint() {
+    let mut bed = TestBed::start(&["sim-0"]).await;
+    let _device = bed.spawn("sim-0");
+    let device = bed.expect_new("sim-0").await;
+
+    // Two more sleeps than the simulator holds, requested one after another.
+    let mut sleeps = Vec::new();
+    for _ in 0..10 {
+        let ingress = bed.ingress.clone();
+        let device = device.clone();
+        sleeps.push(tokio::spawn(async move {
+            ingress.sleep(&device, Duration::from_millis(500)).await
+        }));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    let mut busy = 0;
+    for sleep in sleeps {
+        match sleep.await.unwrap() {
+            Ok(_) => {}
+            Err(ApiError::Busy { retry_after }) => {
+                assert!(retry_after <= Duration::from_millis(500), "{retry_after:?}");
+                busy += 1;
+            }
+            Err(e) => panic!("unexpected error {e:?}"),
+        }
+    }
+    assert_eq!(busy, 2);
+
+    let stats = bed.ingress.deferred_stats(&device).await.unwrap();
+    assert_eq!(stats.capacity, 8);
+    assert_eq!(stats.queued, 0);
+    assert_eq!(stats.peak, 8);
+    assert_eq!(stats.completed, 8);
+    assert_eq!(stats.rejected, 2);
+
+    // With a retry the call waits until there is room.
+    let mut sleeps = Vec::new();
+    for _ in 0..10 {
+        let ingress = bed.ingress.clone();
+        let device = device.clone();
+        sleeps.push(tokio::spawn(async move {
+            ingress
+                .call::<SleepEndpoint>(
+                    &device,
+                    &Sleep {
+                        seconds: 0,
+                        micros: 200_000,
+                    },
+                    CallOptions::default().retries(3),
+                )
+                .await
+        }));
+    }
+    for sleep in sleeps {
+        sleep.await.unwrap().expect("the retry succeeds");
+    }
+}
+
+#[tokio::test]
+async fn heartbeats_are_forwarded() {
+    let mut bed = TestBed::start(&["sim-0"]).await;
+    let mut heartbeat = bed.ingress.heartbeat().await;
+
+    let mut config = bed.device("sim-0");
+    config.heartbeat_interval = Duration::from_millis(100);
+    let _device = device_sim::spawn(config);
+
+    let device = bed.expect_new("sim-0").await;
+
+    let (from, first) = within(EVENT_TIMEOUT, heartbeat.recv()).await.unwrap();
+    let (_, second) = within(EVENT_TIMEOUT, heartbeat.recv()).await.unwrap();
+
+    assert_eq!(from, device);
+    assert!(second.sequence_number > first.sequence_number);
+}
+
+#[tokio::test]
+async fn streams_are_controlled_from_the_host() {
+    let mut bed = TestBed::start(&["sim-0"]).await;
+    let mut some_data = bed.ingress.some_data().await;
+    let _device = bed.spawn("sim-0");
+    let device = bed.expect_new("sim-0").await;
+
+    let status = bed
+        .ingress
+        .set_stream_rate(&device, StreamTopic::SomeData, 50)
+        .await
+        .unwrap();
+    assert_eq!(
+        status,
+        StreamStatus {
+            running: false,
+            hz: 50
+        }
+    );
+
+    let status = bed
+        .ingress
+        .start_stream(&device, StreamTopic::SomeData)
+        .await
+        .unwrap();
+    assert!(status.running);
+
+    let (from, first) = within(EVENT_TIMEOUT, some_data.recv()).await.unwrap();
+    let (_, second) = within(EVENT_TIMEOUT, some_data.recv()).await.unwrap();
+    assert_eq!(from, device);
+    assert_eq!(second.data, first.data + 1);
+
+    let status = bed
+        .ingress
+        .stop_stream(&device, StreamTopic::SomeData)
+        .await
+        .unwrap();
+    assert!(!status.running);
+
+    // Whatever was sent before the stream stopped, then nothing.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    while tokio::time::timeout(Duration::ZERO, some_data.recv())
+        .await
+        .is_ok()
+    {}
+    assert!(
+        tokio::time::timeout(Duration::from_millis(200), some_data.recv())
+            .await
+            .is_err()
+    );
+
+    let result = bed
+        .ingress
+        .set_stream_rate(&device, StreamTopic::SomeData, 0)
+        .await;
+    assert_eq!(result, Err(ApiError::Rejected));
+}
+
+#[tokio::test]
+async fn topics_are_published_to_devices() {
+    let mut bed = TestBed::start(&["sim-0", "sim-1"]).await;
+    let _first = bed.spawn("sim-0");
+    let device = bed.expect_new("sim-0").await;
+
+    let time = Time {
+        unix_time_ms: 1_700_000_000_000,
+    };
+    bed.ingress
+        .publish::<TopicTime>(&device, &time)
+        .await
+        .unwrap();
+    assert_eq!(
+        bed.ingress
+            .publish::<TopicTime>(&"sim-1".into(), &time)
+            .await,
+        Err(ApiError::DeviceNotFound)
+    );
+
+    let _second = bed.spawn("sim-1");
+    bed.expect_new("sim-1").await;
+    assert_eq!(bed.ingress.publish_all::<TopicTime>(&time).await, 2);
+
+    // Nothing comes back, and the devices carry on.
+    bed.ingress.ping(&device).await.unwrap();
+    bed.ingress.ping(&"sim-1".into()).await.unwrap();
+}
+
+#[tokio::test]
+async fn device_info_is_reported_on_connect() {
+    let mut bed = TestBed::start(&["sim-0"]).await;
+
+    let mut config = bed.device("sim-0");
+    config.uid = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+    let _device = device_sim::spawn(config);
+
+    let Connection::New { device, info } = bed.next_connection().await else {
+        panic!("expected a new connection");
+    };
+    let info = info.expect("the device reports its information");
+    assert_eq!(info.uid, [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+    assert_eq!(info.hostname, "rpc-0102030405060708090A0B0C");
+    assert_eq!(info.protocol_version, PROTOCOL_VERSION);
+
+    // Asking again gives the same device, a bit older.
+    let again = bed.ingress.device_info(&device).await.unwrap();
+    assert_eq!(again.uid, info.uid);
+    assert!(again.uptime_ms >= info.uptime_ms);
+}
+
+#[tokio::test]
+async fn unknown_endpoint_is_reported() {
+    let mut bed = TestBed::start(&["sim-0"]).await;
+    let _device = bed.spawn("sim-0");
+    let device = bed.expect_new("sim-0").await;
+
+    let result = bed
+        .ingress
+        .call::<BulkEndpoint>(&device, &Vec::new(), CallOptions::default())
+        .await;
+
+    assert_eq!(result, Err(ApiError::Unimplemented));
+}
+
+// An endpoint no device implements, taking as many bytes as asked for.
+endpoint!(BulkEndpoint, Vec<u8>, (), "test/bulk");
+
+#[tokio::test]
+async fn large_messages_are_fragmented_or_refused() {
+    let mut bed = TestBed::start(&["sim-0"]).await;
+    let _device = bed.spawn("sim-0");
+    let device = bed.expect_new("sim-0").await;
+
+    // Larger than a record, the device puts it back together and finds no such endpoint.
+    let result = bed
+        .ingress
+        .call::<BulkEndpoint>(&device, &vec![0xa5; 400], CallOptions::default())
+        .await;
+    assert_eq!(result, Err(ApiError::Unimplemented));
+
+    // Larger than the device can take, it says so instead of dropping it.
+    let result = bed
+        .ingress
+        .call::<BulkEndpoint>(&device, &vec![0xa5; 1000], CallOptions::default())
+        .await;
+    assert_eq!(result, Err(ApiError::TooLarge));
+
+    // The session is still fine.
+    bed.expect_ping(&device).await;
+}
+
+topic!(BulkTopic, Vec<u8>, "test/bulk");
+
+#[tokio::test]
+async fn messages_too_large_to_send_are_refused_right_away() {
+    let mut bed = TestBed::start(&["sim-0"]).await;
+    let _device = bed.spawn("sim-0");
+    let device = bed.expect_new("sim-0").await;
+
+    let start = Instant::now();
+    let result = bed
+        .ingress
+        .call::<BulkEndpoint>(
+            &device,
+            &vec![0xa5; 70_000],
+            CallOptions::default().timeout(Duration::from_secs(5)),
+        )
+        .await;
+    assert_eq!(result, Err(ApiError::TooLarge));
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    let result = bed
+        .ingress
+        .publish::<BulkTopic>(&device, &vec![0xa5; 70_000])
+        .await;
+    assert_eq!(result, Err(ApiError::TooLarge));
+    assert_eq!(
+        bed.ingress
+            .publish_all::<BulkTopic>(&vec![0xa5; 70_000])
+            .await,
+        0
+    );
+
+    bed.expect_ping(&device).await;
+}
+
+#[tokio::test]
+async fn session_is_cleaned_up_when_device_goes_away() {
+    let mut bed = TestBed::start(&["sim-0"]).await;
+    let device_task = bed.spawn("sim-0");
+    let device = bed.expect_new("sim-0").await;
+
+    device_task.abort();
+
+    // The worker notices the silence when its receive times out.
+    bed.expect_closed("sim-0").await;
+
+    assert_eq!(
+        bed.ingress.ping(&device).await,
+        Err(ApiError::DeviceNotFound)
+    );
+    assert_eq!(
+        bed.ingress.ip_address(&device).await,
+        Err(ApiError::DeviceNotFound)
+    );
+}
+
+#[tokio::test]
+async fn device_reconnects_from_the_same_address() {
+    let mut bed = TestBed::start(&["sim-0"]).await;
+
+    // Pick a free port to reuse for both power cycles.
+    let bind = UdpSocket::bind("127.0.0.1:0")
+        .and_then(|s| s.local_addr())
+        .unwrap();
+
+    let mut config = bed.device("sim-0");
+    config.bind = Some(bind);
+
+    let first_boot = device_sim::spawn(config.clone());
+    bed.expect_new("sim-0").await;
+
+    // Wait for the task to be gone so the port is free again.
+    first_boot.abort();
+    let _ = first_boot.await;
+    bed.expect_closed("sim-0").await;
+
+    // The old worker for this address has shut down, so the listener has to recreate it.
+    let _second_boot = device_sim::spawn(config);
+    let device = bed.expect_new("sim-0").await;
+
+    bed.expect_ping(&device).await;
+    assert_eq!(bed.ingress.ip_address(&device).await, Ok(bind.ip()));
+}
+
+#[tokio::test]
+async fn late_consumer_gets_a_snapshot() {
+    let mut bed = TestBed::start(&["sim-0", "sim-1"]).await;
+    let _first = bed.spawn("sim-0");
+    let first = bed.expect_new("sim-0").await;
+
+    let mut snapshot = bed.ingress.connection_with_snapshot().await;
+    let ids: Vec<_> = snapshot.devices.iter().map(|d| d.id.clone()).collect();
+    assert_eq!(ids, std::slice::from_ref(&first));
+    assert!(snapshot.devices[0].last_packet >= snapshot.devices[0].connected_at);
+
+    let _second = bed.spawn("sim-1");
+    assert!(matches!(
+        within(EVENT_TIMEOUT, snapshot.changes.recv()).await,
+        Ok(Connection::New { device, .. }) if device == "sim-1".into()
+    ));
+
+    let mut ids: Vec<_> = bed
+        .ingress
+        .devices()
+        .await
+        .into_iter()
+        .map(|d| d.id)
+        .collect();
+    ids.sort();
+    assert_eq!(ids, [first, "sim-1".into()]);
+}
+
+#[tokio::test]
+async fn stale_link_and_reboot_are_reported() {
+    let heartbeat_interval = Duration::from_millis(100);
+    let mut bed = TestBed::start_with(&["sim-0"], |builder| {
+        builder
+            .heartbeat_interval(heartbeat_interval)
+            .missed_heartbeats(2)
+    })
+    .await;
+
+    let mut config = bed.device("sim-0");
+    config.heartbeat_interval = heartbeat_interval;
+
+    let first_boot = device_sim::spawn(config.clone());
+    let device = bed.expect_new("sim-0").await;
+
+    within(EVENT_TIMEOUT, async {
+        while bed.ingress.health(&device).map_or(0, |h| h.received) < 3 {
+            tokio::time::sleep(heartbeat_interval).await;
+        }
+    })
+    .await;
+
+    // Silence is first reported as a stale link, later the session times out.
+    first_boot.abort();
+    let _ = first_boot.await;
+    bed.expect_connection(Connection::Stale(device.clone()))
+        .await;
+    bed.expect_closed("sim-0").await;
+
+    // The heartbeat sequence of a fresh boot starts over.
+    let _second_boot = device_sim::spawn(config);
+    bed.expect_new("sim-0").await;
+    bed.expect_connection(Connection::Rebooted(device.clone()))
+        .await;
+
+    let health = bed.ingress.health(&device).unwrap();
+    assert_eq!(health.reboots, 1);
+    assert!(!health.stale);
+}
+
+#[tokio::test]
+async fn zero_heartbeat_settings_are_refused() {
+    let psk_file = support::write_psk_file(&["sim-0"]);
+    let builder = || {
+        Ingress::builder()
+            .bind(([127, 0, 0, 1], 0))
+            .credentials(PskStore::load(&psk_file).unwrap())
+    };
+
+    let result = builder().heartbeat_interval(Duration::ZERO).build().await;
+    assert!(matches!(result, Err(BuildError::InvalidHeartbeat)));
+
+    let result = builder().missed_heartbeats(0).build().await;
+    assert!(matches!(result, Err(BuildError::InvalidHeartbeat)));
+}
+
+#[tokio::test]
+async fn heartbeats_report_the_device_health() {
+    let heartbeat_interval = Duration::from_millis(100);
+    let mut bed = TestBed::start_with(&["sim-0"], |builder| {
+        builder.heartbeat_interval(heartbeat_interval)
+    })
+    .await;
+
+    let mut config = bed.device("sim-0");
+    config.heartbeat_interval = heartbeat_interval;
+
+    let _device = device_sim::spawn(config);
+    let device = bed.expect_new("sim-0").await;
+
+    let report = within(EVENT_TIMEOUT, async {
+        loop {
+            if let Some(report) = bed.ingress.health(&device).and_then(|h| h.report) {
+                break report;
+            }
+            tokio::time::sleep(heartbeat_interval).await;
+        }
+    })
+    .await;
+
+    assert_eq!(report.version, HEALTH_VERSION);
+    assert!(report.uptime_ms >= heartbeat_interval.as_millis() as u64);
+    assert!(report.link_up);
+    assert_eq!(report.dtls_errors, 0);
+}
+
+#[tokio::test]
+async fn shutdown_drains_calls_and_closes_every_session() {
+    let mut bed = TestBed::start(&["sim-0", "sim-1"]).await;
+    let _devices = device_sim::spawn_many(bed.ingress.local_addr(), "sim", support::KEY, 2);
+    for _ in 0..2 {
+        assert!(matches!(
+            bed.next_connection().await,
+            Connection::New { .. }
+        ));
+    }
+
+    let device = DeviceId::from("sim-0");
+    let in_flight = tokio::spawn({
+        let ingress = bed.ingress.clone();
+        let device = device.clone();
+        async move { ingress.sleep(&device, Duration::from_millis(500)).await }
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    within(EVENT_TIMEOUT, bed.ingress.shutdown()).await;
+
+    // The call that was in flight completed before its session closed.
+    assert!(in_flight.await.unwrap().is_ok());
+
+    let mut closed = vec![bed.next_connection().await, bed.next_connection().await];
+    closed.sort_by_key(|event| format!("{event:?}"));
+    assert_eq!(
+        closed,
+        [
+            Connection::Closed("sim-0".into()),
+            Connection::Closed("sim-1".into())
+        ]
+    );
+
+    assert!(bed.ingress.devices().await.is_empty());
+    assert_eq!(
+        bed.ingress.ping(&device).await,
+        Err(ApiError::DeviceNotFound)
+    );
+}
+
+#[tokio::test]
+async fn subscriptions_end_once_the_ingress_is_gone() {
+    let bed = TestBed::start(&["sim-0"]).await;
+    let mut connections = bed.ingress.connection();
+
+    within(EVENT_TIMEOUT, bed.ingress.shutdown()).await;
+    drop(bed);
+
+    let result = within(EVENT_TIMEOUT, connections.recv()).await;
+    assert_eq!(result, Err(SubscriptionError::Closed));
+}
+
+#[tokio::test]
+async fn handshakes_are_rate_limited_per_source() {
+    let mut bed = TestBed::start_with(&["sim-0", "sim-1"], |builder| {
+        builder.handshake_rate_limit(1, Duration::from_secs(3600))
+    })
+    .await;
+
+    let _first = bed.spawn("sim-0");
+    bed.expect_new("sim-0").await;
+
+    // The second device shares the IP of the first, which has used up its handshakes.
+    let _second = bed.spawn("sim-1");
+    let Connection::HandshakeFailed { reason, .. } = bed.next_connection().await else {
+        panic!("expected a refused handshake");
+    };
+    assert_eq!(reason, HandshakeError::RateLimited);
+    assert!(bed.ingress.handshake_failures()[&HandshakeError::RateLimited] >= 1);
+
+    assert_eq!(
+        bed.ingress.ping(&"sim-1".into()).await,
+        Err(ApiError::DeviceNotFound)
+    );
+}
+
+#[tokio::test]
+async fn device_with_a_derived_key_connects_without_an_entry() {
+    let mut bed = TestBed::start(&[]).await;
+
+    let identity = psk::identity(&[
+        0x00, 0x1f, 0x00, 0x2a, 0x31, 0x33, 0x51, 0x0a, 0x33, 0x35, 0x38, 0x37,
+    ]);
+    let name = std::str::from_utf8(&identity).unwrap();
+
+    let mut config = bed.device(name);
+    config.key = psk::derive_key(support::MASTER_SECRET, &identity).to_vec();
+    let _device = device_sim::spawn(config);
+
+    let device = bed.expect_new(name).await;
+    bed.expect_ping(&device).await;
+}
+
+#[tokio::test]
+async fn derived_key_from_another_master_secret_is_rejected() {
+    let mut bed = TestBed::start(&[]).await;
+
+    let identity = psk::identity(&[0xff; psk::UID_LEN]);
+    let mut config = bed.device(std::str::from_utf8(&identity).unwrap());
+    config.key = psk::derive_key(b"another master secret", &identity).to_vec();
+    let _device = device_sim::spawn(config);
+
+    let Connection::HandshakeFailed { reason, .. } = bed.next_connection().await else {
+        panic!("expected a failed handshake");
+    };
+    assert_eq!(reason, HandshakeError::BadKey);
+}
+
+#[tokio::test]
+async fn reloaded_credentials_admit_new_devices() {
+    let mut bed = TestBed::start(&["sim-0"]).await;
+
+    let unknown = bed.spawn("sim-1");
+    let Connection::HandshakeFailed { reason, .. } = bed.next_connection().await else {
+        panic!("expected a refused handshake");
+    };
+    assert_eq!(reason, HandshakeError::UnknownIdentity);
+    unknown.abort();
+
+    bed.rewrite_credentials(&[("sim-0", support::KEY), ("sim-1", support::KEY)])
+        .await;
+
+    let _device = bed.spawn("sim-1");
+    let device = bed.expect_new("sim-1").await;
+    bed.expect_ping(&device).await;
+}
+
+#[tokio::test]
+async fn sessions_close_when_their_key_is_removed_or_changed() {
+    let mut bed = TestBed::start(&["sim-0", "sim-1", "sim-2"]).await;
+
+    let _devices = ["sim-0", "sim-1", "sim-2"].map(|identity| bed.spawn(identity));
+    for _ in 0..3 {
+        assert!(matches!(
+            bed.next_connection().await,
+            Connection::New { .. }
+        ));
+    }
+
+    // sim-0 keeps its key, sim-1 gets a new one and sim-2 is removed.
+    bed.rewrite_credentials(&[("sim-0", support::KEY), ("sim-1", b"a new key for sim-1")])
+        .await;
+
+    let mut closed = Vec::new();
+    while closed.len() < 2 {
+        match bed.next_connection().await {
+            Connection::Closed(device) => closed.push(device),
+            // The devices try again with their old key.
+            Connection::HandshakeFailed { .. } => {}
+            event => panic!("unexpected connection event {event:?}"),
+        }
+    }
+    closed.sort_by_key(|device| device.to_string());
+    assert_eq!(closed, [DeviceId::from("sim-1"), DeviceId::from("sim-2")]);
+
+    bed.expect_ping(&"sim-0".into()).await;
+    assert_eq!(bed.ingress.devices().await.len(), 1);
+}
+
+#[tokio::test]
+async fn handshake_with_the_wrong_key_is_reported() {
+    let mut bed = TestBed::start(&["sim-0"]).await;
+
+    let mut config = bed.device("sim-0");
+    config.key = b"not the key of sim-0".to_vec();
+    let _device = device_sim::spawn(config);
+
+    let Connection::HandshakeFailed { addr, reason } = bed.next_connection().await else {
+        panic!("expected a failed handshake");
+    };
+    assert!(addr.ip().is_loopback());
+    assert_eq!(reason, HandshakeError::BadKey);
+
+    let failures = bed.ingress.handshake_failures();
+    assert!(failures[&HandshakeError::BadKey] >= 1);
+    assert!(!failures.contains_key(&HandshakeError::UnknownIdentity));
+    assert_eq!(bed.ingress.devices().await, []);
+}
+
+#[tokio::test]
+async fn reboot_replaces_the_session_right_away() {
+    let mut bed = TestBed::start(&["sim-0"]).await;
+
+    let bind = UdpSocket::bind("127.0.0.1:0")
+        .and_then(|s| s.local_addr())
+        .unwrap();
+
+    let mut config = bed.device("sim-0");
+    config.bind = Some(bind);
+
+    let first_boot = device_sim::spawn(config.clone());
+    bed.expect_new("sim-0").await;
+
+    first_boot.abort();
+    let _ = first_boot.await;
+
+    // Reboot before the old session has timed out.
+    let start = Instant::now();
+    let _second_boot = device_sim::spawn(config);
+
+    loop {
+        match bed.next_connection().await {
+            Connection::New { device, .. } => {
+                assert_eq!(device, "sim-0".into());
+                break;
+            }
+            Connection::Closed(_) => {}
+            event => panic!("unexpected connection event {event:?}"),
+        }
+    }
+    assert!(
+        start.elapsed() < Duration::from_secs(2),
+        "{:?}",
+        start.elapsed()
+    );
+
+    let device = DeviceId::from("sim-0");
+    bed.expect_ping(&device).await;
+    assert_eq!(bed.ingress.devices().await.len(), 1);
+}
+
+#[tokio::test]
+async fn rejected_handshake_from_a_connected_address_keeps_the_session() {
+    let mut bed = TestBed::start(&["sim-0"]).await;
+    let hello = support::client_hello("intruder").await;
+
+    let (relay, spoofer) = support::relay(bed.ingress.local_addr()).await;
+    let mut config = bed.device("sim-0");
+    config.backend = relay;
+    let _device = device_sim::spawn(config);
+    let device = bed.expect_new("sim-0").await;
+
+    // A `ClientHello` from the address of the device, with an identity that isn't admitted.
+    spoofer
+        .send_to(&hello, bed.ingress.local_addr())
+        .await
+        .unwrap();
+
+    let Connection::HandshakeFailed { reason, .. } = bed.next_connection().await else {
+        panic!("expected a refused handshake");
+    };
+    assert_eq!(reason, HandshakeError::UnknownIdentity);
+
+    bed.expect_ping(&device).await;
+    assert_eq!(bed.ingress.devices().await.len(), 1);
+}
+
+#[tokio::test]
+async fn firmware_updates_are_staged_once() {
+    let data: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+    let version = FirmwareVersion {
+        major: 0,
+        minor: 2,
+        patch: 0,
+    };
+    let image = FirmwareImage::new(version, data.clone());
+    let mut bed = TestBed::start_with(&["sim-0"], |builder| builder.firmware_image(image)).await;
+
+    let bind = UdpSocket::bind("127.0.0.1:0")
+        .and_then(|s| s.local_addr())
+        .unwrap();
+
+    let mut config = bed.device("sim-0");
+    config.bind = Some(bind);
+    let slot = config.update_slot.clone();
+
+    // The device can't install the image, it is announced with the firmware it runs.
+    let first_boot = device_sim::spawn(config.clone());
+    let device = bed.expect_new("sim-0").await;
+
+    let (staged, staged_data) = slot.staged().expect("the image is staged");
+    assert_eq!(staged.version, version);
+    assert_eq!(staged_data, data);
+    let chunks = slot.chunks_written();
+    assert_eq!(chunks, data.len().div_ceil(64));
+
+    // After a reboot the version is checked again, but the staged image is not sent again.
+    first_boot.abort();
+    let _ = first_boot.await;
+    let _second_boot = device_sim::spawn(config);
+
+    loop {
+        match bed.next_connection().await {
+            Connection::New { device: new, .. } => {
+                assert_eq!(new, device);
+                break;
+            }
+            Connection::Closed(_) => {}
+            event => panic!("unexpected connection event {event:?}"),
+        }
+    }
+    assert_eq!(slot.chunks_written(), chunks);
+
+    let info = bed.ingress.device_info(&device).await.unwrap();
+    assert_eq!(info.firmware_version.minor, 1);
+}
+
+#[tokio::test]
+async fn retries_follow_the_device_across_a_reboot() {
+    let mut bed = TestBed::start(&["sim-0"]).await;
+
+    let bind = UdpSocket::bind("127.0.0.1:0")
+        .and_then(|s| s.local_addr())
+        .unwrap();
+
+    let mut config = bed.device("sim-0");
+    config.bind = Some(bind);
+
+    let first_boot = device_sim::spawn(config.clone());
+    let device = bed.expect_new("sim-0").await;
+
+    first_boot.abort();
+    let _ = first_boot.await;
+
+    // The first attempts go to the session of the powered off device.
+    let ingress = bed.ingress.clone();
+    let call = tokio::spawn({
+        let device = device.clone();
+        async move {
+            ingress
+                .call::<PingPongEndpoint>(
+                    &device,
+                    &Ping {},
+                    CallOptions::default()
+                        .timeout(Duration::from_millis(300))
+                        .retries(4),
+                )
+                .await
+        }
+    });
+
+    let _second_boot = device_sim::spawn(config);
+    loop {
+        match bed.next_connection().await {
+            Connection::New { .. } => break,
+            Connection::Closed(_) => {}
+            event => panic!("unexpected connection event {event:?}"),
+        }
+    }
+
+    let result = within(EVENT_TIMEOUT, call).await.unwrap();
+    assert_eq!(result, Ok(Pong {}));
+}
+
+#[tokio::test]
+async fn retries_after_a_reboot_are_cancelled_on_the_new_session() {
+    let mut bed = TestBed::start(&["sim-0"]).await;
+
+    let bind = UdpSocket::bind("127.0.0.1:0")
+        .and_then(|s| s.local_addr())
+        .unwrap();
+
+    let mut config = bed.device("sim-0");
+    config.bind = Some(bind);
+
+    let first_boot = device_sim::spawn(config.clone());
+    let device = bed.expect_new("sim-0").await;
+
+    first_boot.abort();
+    let _ = first_boot.await;
+
+    // Every attempt is given up on long before the sleep is done.
+    let ingress = bed.ingress.clone();
+    let call = tokio::spawn({
+        let device = device.clone();
+        async move {
+            ingress
+                .call::<SleepEndpoint>(
+                    &device,
+                    &Sleep {
+                        seconds: 5,
+                        micros: 0,
+                    },
+                    CallOptions::default()
+                        .timeout(Duration::from_millis(300))
+                        .retries(4),
+                )
+                .await
+        }
+    });
+
+    let _second_boot = device_sim::spawn(config);
+    loop {
+        match bed.next_connection().await {
+            Connection::New { .. } => break,
+            Connection::Closed(_) => {}
+            event => panic!("unexpected connection event {event:?}"),
+        }
+    }
+
+    let result = within(EVENT_TIMEOUT, call).await.unwrap();
+    assert_eq!(result, Err(ApiError::NoResponse));
+
+    // The attempts that reached the rebooted device were cancelled there, none is left waiting.
+    let stats = within(EVENT_TIMEOUT, async {
+        loop {
+            let stats = bed.ingress.deferred_stats(&device).await.unwrap();
+            if stats.queued == 0 {
+                break stats;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await;
+    assert!(stats.cancelled >= 1, "{stats:?}");
+    assert_eq!(stats.completed, 0);
+}
+
+#[tokio::test]
+async fn device_reconnects_when_the_backend_restarts() {
+    let mut first = TestBed::start(&["sim-0"]).await;
+    let addr = first.ingress.local_addr();
+
+    let _device = first.spawn("sim-0");
+    first.expect_new("sim-0").await;
+
+    // The shutdown closes the session, the device doesn't wait for its keepalive to expire.
+    let closed = Instant::now();
+    within(EVENT_TIMEOUT, first.ingress.shutdown()).await;
+    drop(first);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let mut second = TestBed::start_with(&["sim-0"], |builder| builder.bind(addr)).await;
+
+    let device = second.expect_new("sim-0").await;
+    // The keepalive expires after three missed heartbeats of two seconds, at least four seconds
+    // after the shutdown.
+    assert!(closed.elapsed() < Duration::from_secs(3));
+    second.expect_ping(&device).await;
+}
+
+#[tokio::test]
+async fn ingress_announces_itself() {
+    let listener = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let target = listener.local_addr().unwrap();
+
+    let bed = TestBed::start_with(&["sim-0"], |builder| {
+        builder.announce(target, Duration::from_millis(100))
+    })
+    .await;
+
+    let mut buf = [0; 64];
+    let (n, from) = within(EVENT_TIMEOUT, listener.recv_from(&mut buf))
+        .await
+        .unwrap();
+
+    let announcement = Announcement::parse(&buf[..n]).expect("a valid announcement");
+    assert_eq!(announcement.port, bed.ingress.local_addr().port());
+    assert!(from.ip().is_loopback());
+}
@@ -0,0 +1,226 @@
+//! Test support: an ingress on an ephemeral port, with simulated devices attached.
+
+// Not every test uses every helper.
+#![allow(dead_code)]
+
+use device_sim::DeviceConfig;
+use pc_app::ingress::{
+    credentials::PskStore,
+    subscriptions::{Connection, Subscription},
+    DeviceId, Ingress, IngressBuilder,
+};
+use std::{
+    future::Future,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{net::UdpSocket, task::JoinHandle};
+
+/// The key all test devices use.
+pub const KEY: &[u8] = b"11111234567890qwertyuiopasdfghjklzxc";
+
+/// The master secret the keys of devices with derived identities come from.
+pub const MASTER_SECRET: &[u8] = b"test master secret";
+
+/// How long to wait for something that should happen, including a DTLS handshake.
+pub const EVENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An ingress listening on localhost, that knows the devices it was started with.
+pub struct TestBed {
+    pub ingress: Ingress,
+    connections: Subscription<Connection>,
+    credentials: PskStore,
+    psk_file: PathBuf,
+}
+
+impl TestBed {
+    /// Start an ingress that accepts the given identities.
+    pub async fn start(identities: &[&str]) -> Self {
+        Self::start_with(identities, |builder| builder).await
+    }
+
+    /// Start an ingress that accepts the given identities, with extra settings.
+    pub async fn start_with(
+        identities: &[&str],
+        configure: impl FnOnce(IngressBuilder) -> IngressBuilder,
+    ) -> Self {
+        let _ = pretty_env_logger::try_init();
+
+        let psk_file = write_psk_file(identities);
+        let credentials = PskStore::load(&psk_file).expect("valid credentials");
+
+        let builder = Ingress::builder()
+            .bind(([127, 0, 0, 1], 0))
+            .credentials(credentials.clone());
+
+        let ingress = configure(builder).build().await.expect("ingress starts");
+
+        Self {
+            connections: ingress.connection(),
+            ingress,
+            credentials,
+            psk_file,
+        }
+    }
+
+    /// Configuration of a simulated device connecting to this ingress.
+    pub fn device(&self, identity: &str) -> DeviceConfig {
+        DeviceConfig::new(self.ingress.local_addr(), identity, KEY)
+    }
+
+    /// Power on a simulated device.
+    pub fn spawn(&self, identity: &str) -> JoinHandle<()> {
+        device_sim::spawn(self.device(identity))
+    }
+
+    /// Wait for the next connection event.
+    pub async fn next_connection(&mut self) -> Connection {
+        within(EVENT_TIMEOUT, self.connections.recv())
+            .await
+            .expect("connection events are not dropped")
+    }
+
+    /// Wait for the next connection event and check it.
+    pub async fn expect_connection(&mut self, expected: Connection) {
+        assert_eq!(self.next_connection().await, expected);
+    }
+
+    /// Wait for a device to connect.
+    pub async fn expect_new(&mut self, identity: &str) -> DeviceId {
+        let device = DeviceId::from(identity);
+
+        match self.next_connection().await {
+            Connection::New { device: new, .. } if new == device => device,
+            event => panic!("expected {device} to connect, got {event:?}"),
+        }
+    }
+
+    /// Wait for a device to disconnect. A silent device is reported as stale first, that is
+    /// skipped.
+    pub async fn expect_closed(&mut self, identity: &str) {
+        let device = DeviceId::from(identity);
+
+        loop {
+            match self.next_connection().await {
+                Connection::Stale(stale) if stale == device => continue,
+                event => {
+                    assert_eq!(event, Connection::Closed(device));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Replace the credentials file with the given identities and keys, and reload it.
+    pub async fn rewrite_credentials(&self, devices: &[(&str, &[u8])]) {
+        std::fs::write(&self.psk_file, psk_file_contents(devices))
+            .expect("temporary directory is writable");
+        self.credentials.reload().await.expect("valid credentials");
+    }
+
+    /// Check that a device answers pings.
+    pub async fn expect_ping(&self, device: &DeviceId) {
+        self.ingress
+            .ping(device)
+            .await
+            .unwrap_or_else(|e| panic!("{device}: ping failed with {e:?}"));
+    }
+}
+
+impl Drop for TestBed {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.psk_file);
+    }
+}
+
+/// Wait for a future, failing the test if it takes longer than `timeout`.
+pub async fn within<F: Future>(timeout: Duration, f: F) -> F::Output {
+    tokio::time::timeout(timeout, f)
+        .await
+        .expect("timed out waiting for the ingress")
+}
+
+/// Write a credentials file for the identities to a unique temporary path.
+pub fn write_psk_file(identities: &[&str]) -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+    let path = std::env::temp_dir().join(format!(
+        "pc-app-test-{}-{}.toml",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let devices: Vec<_> = identities.iter().map(|identity| (*identity, KEY)).collect();
+    std::fs::write(&path, psk_file_contents(&devices)).expect("temporary directory is writable");
+    path
+}
+
+/// A credentials file with the master secret and the given identities and keys.
+fn psk_file_contents(devices: &[(&str, &[u8])]) -> String {
+    let hex = |bytes: &[u8]| -> String { bytes.iter().map(|b| format!("{b:02x}")).collect() };
+
+    let mut contents = format!("master_secret = \"{}\"\n\n", hex(MASTER_SECRET));
+    contents.extend(devices.iter().map(|(identity, key)| {
+        format!(
+            "[[device]]\nidentity = \"{identity}\"\nkey = \"{}\"\n\n",
+            hex(key)
+        )
+    }));
+
+    contents
+}
+
+/// The first datagram a device with `identity` sends, its `ClientHello`.
+pub async fn client_hello(identity: &str) -> Vec<u8> {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let device = device_sim::spawn(DeviceConfig::new(
+        socket.local_addr().unwrap(),
+        identity,
+        KEY,
+    ));
+
+    let mut buf = vec![0; 2048];
+    let len = within(EVENT_TIMEOUT, socket.recv(&mut buf)).await.unwrap();
+    device.abort();
+
+    buf.truncate(len);
+    buf
+}
+
+/// Forward the datagrams of one device to `ingress` and back, so a test can send from the address
+/// the ingress sees the device at.
+///
+/// Returns the address to point the device at, and the socket that talks to the ingress.
+pub async fn relay(ingress: SocketAddr) -> (SocketAddr, Arc<UdpSocket>) {
+    let front = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let back = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    let front_addr = front.local_addr().unwrap();
+    let to_ingress = back.clone();
+
+    tokio::spawn(async move {
+        let mut device = None;
+        let mut from_device = vec![0; 2048];
+        let mut from_ingress = vec![0; 2048];
+
+        loop {
+            tokio::select! {
+                Ok((len, from)) = front.recv_from(&mut from_device) => {
+                    device = Some(from);
+                    let _ = to_ingress.send_to(&from_device[..len], ingress).await;
+                }
+                Ok(len) = to_ingress.recv(&mut from_ingress) => {
+                    if let Some(device) = device {
+                        let _ = front.send_to(&from_ingress[..len], device).await;
+                    }
+                }
+            }
+        }
+    });
+
+    (front_addr, back)
+}
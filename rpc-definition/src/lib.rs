@@ -1,10 +1,14 @@
 #![no_std]
 
+pub use heapless;
 pub use postcard_rpc;
 
 use postcard::experimental::schema::Schema;
 use serde::{Deserialize, Serialize};
 
+/// Version of the protocol defined here, bumped on changes that break older devices or backends.
+pub const PROTOCOL_VERSION: u16 = 1;
+
 /// Topics are defined here, that is unsolicited messages.
 /// They can go in either direction, Backend -> Device or Backend <- Device, however it's up to the
 /// application to descide.
@@ -17,16 +21,140 @@ pub mod topics {
         // This is how you define a topic.
         topic!(TopicHeartbeat, Heartbeat, "topic/heartbeat");
 
-        /// Heartbeat from devices to backend, here one might also have device health info,
-        /// performance counters, etc.
+        /// Heartbeat from devices to backend.
+        ///
+        /// Devices append a [`Health`] report, sent as a [`HeartbeatWithHealth`] under the key of
+        /// this topic. Trailing bytes are ignored when decoding, so hosts that only know this
+        /// struct keep working, use [`decode`] to get the report as well.
         #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
         #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Schema)]
         pub struct Heartbeat {
-            /// Some device info value example.
+            /// Unused, kept for the layout of the first version.
             pub value: f32,
-            /// Another thing, maybe Ethernet performance counters.
+            /// Increases by one for every heartbeat, starts over when the device restarts.
+            pub sequence_number: u32,
+        }
+
+        /// Version of [`Health`] sent by devices built from this definition.
+        pub const HEALTH_VERSION: u8 = 1;
+
+        /// Health of the device, appended to every heartbeat.
+        ///
+        /// New fields are only ever added at the end together with a bump of `version`, so a
+        /// host decodes the fields it knows of and ignores the rest.
+        #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+        #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Schema)]
+        pub struct Health {
+            /// [`HEALTH_VERSION`] of the device that sent the report.
+            pub version: u8,
+            /// Time since the device started, in milliseconds.
+            pub uptime_ms: u64,
+            /// Free slots in the queue of outgoing messages.
+            pub ethernet_tx_free: u8,
+            /// Free slots for deferred responses, such as those of `Sleep`.
+            pub sleep_free: u8,
+            /// Unicast frames received without errors by the Ethernet MAC.
+            pub eth_rx_frames: u32,
+            /// Frames sent by the Ethernet MAC.
+            pub eth_tx_frames: u32,
+            /// Frames received with CRC or alignment errors.
+            pub eth_rx_errors: u32,
+            /// Frames dropped by the Ethernet MAC as no receive buffer was free.
+            pub eth_rx_missed: u32,
+            /// Datagrams dropped by the network stack, from an unexpected sender or that could
+            /// not be sent or received.
+            pub net_dropped: u32,
+            /// Failed DTLS handshakes and connections closed by an error.
+            pub dtls_errors: u32,
+            /// The Ethernet link is up.
+            pub link_up: bool,
+        }
+
+        /// What devices send on [`TopicHeartbeat`], a [`Heartbeat`] followed by a [`Health`]
+        /// report.
+        #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Schema)]
+        pub struct HeartbeatWithHealth {
+            pub heartbeat: Heartbeat,
+            pub health: Health,
+        }
+
+        /// Decode the body of a heartbeat, the health report is `None` if the device sent none or
+        /// it could not be decoded.
+        pub fn decode(body: &[u8]) -> Option<(Heartbeat, Option<Health>)> {
+            let (heartbeat, rest) = postcard::take_from_bytes::<Heartbeat>(body).ok()?;
+            let health = postcard::from_bytes::<Health>(rest).ok();
+
+            Some((heartbeat, health))
+        }
+
+        topic!(TopicHeartbeatAck, HeartbeatAck, "topic/heartbeat/ack");
+
+        /// Acknowledgement of a heartbeat from backend to device. A device that gets no
+        /// acknowledgements assumes the backend has lost the session, and reconnects.
+        #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+        #[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize, Schema)]
+        pub struct HeartbeatAck {
+            /// Sequence number of the acknowledged heartbeat.
             pub sequence_number: u32,
         }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+
+            fn health() -> Health {
+                Health {
+                    version: HEALTH_VERSION,
+                    uptime_ms: 12_345,
+                    ethernet_tx_free: 1,
+                    sleep_free: 8,
+                    eth_rx_frames: 100,
+                    eth_tx_frames: 90,
+                    eth_rx_errors: 1,
+                    eth_rx_missed: 2,
+                    net_dropped: 3,
+                    dtls_errors: 4,
+                    link_up: true,
+                }
+            }
+
+            #[test]
+            fn older_hosts_decode_the_heartbeat() {
+                let heartbeat = Heartbeat {
+                    value: 1.,
+                    sequence_number: 7,
+                };
+                let mut buf = [0; 64];
+                let body = postcard::to_slice(
+                    &HeartbeatWithHealth {
+                        heartbeat: heartbeat.clone(),
+                        health: health(),
+                    },
+                    &mut buf,
+                )
+                .unwrap();
+
+                assert_eq!(
+                    postcard::from_bytes::<Heartbeat>(body),
+                    Ok(heartbeat.clone())
+                );
+                assert_eq!(decode(body), Some((heartbeat, Some(health()))));
+            }
+
+            #[test]
+            fn heartbeats_without_health_decode() {
+                let heartbeat = Heartbeat {
+                    value: 1.,
+                    sequence_number: 7,
+                };
+                let mut buf = [0; 64];
+                let body = postcard::to_slice(&heartbeat, &mut buf).unwrap();
+
+                assert_eq!(decode(body), Some((heartbeat, None)));
+                assert_eq!(decode(&body[..2]), None);
+            }
+        }
     }
 
     /// Another topic with some streaming data.
@@ -37,14 +165,46 @@ pub mod topics {
         // This is how you define a topic.
         topic!(TopicSomeData, SomeData, "topic/somedata");
 
-        /// Another unsolicited message.
+        /// Another unsolicited message, streamed when started with `endpoints::stream`.
         #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
         #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Schema)]
         pub struct SomeData {
-            /// With some data.
+            /// Counts up by one for every message, so gaps show lost messages.
             pub data: u64,
         }
     }
+
+    /// The time of the backend, a topic from the backend to devices.
+    pub mod time {
+        use super::super::*;
+        use postcard_rpc::topic;
+
+        topic!(TopicTime, Time, "topic/time");
+
+        /// Wall clock time of the backend, published to devices so their logs line up with it.
+        #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Schema)]
+        pub struct Time {
+            /// Milliseconds since the Unix epoch.
+            pub unix_time_ms: u64,
+        }
+    }
+
+    /// The end of a session, a topic from the backend to devices.
+    pub mod close {
+        use super::super::*;
+        use postcard_rpc::topic;
+
+        topic!(TopicClose, Close, "topic/close");
+
+        /// The backend is closing the session, e.g. as it shuts down.
+        ///
+        /// This stands in for a DTLS `close_notify` alert. The device drops the connection and
+        /// connects again after its backoff, instead of waiting for the session to time out.
+        #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Schema)]
+        pub struct Close {}
+    }
 }
 
 /// Endpoints are the core RPC API.
@@ -74,6 +234,271 @@ pub mod endpoints {
         }
     }
 
+    /// Deferred responses of a device, the responses it sends at a later point in time such as the
+    /// one of `Sleep`. Their statistics, and the queue the firmware and the simulator hold them in.
+    pub mod deferred {
+        use core::sync::atomic::{AtomicU16, AtomicU32, Ordering};
+        use heapless::{binary_heap::Min, BinaryHeap};
+        use postcard_rpc::endpoint;
+
+        use super::super::*;
+        use crate::clock::Clock;
+
+        endpoint!(
+            DeferredStatsEndpoint,
+            GetDeferredStats,
+            DeferredStats,
+            "endpoint/deferred/stats"
+        );
+
+        /// Request for the statistics of the deferred responses.
+        #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+        #[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize, Schema)]
+        pub struct GetDeferredStats {}
+
+        /// Statistics of the deferred responses.
+        #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Schema)]
+        pub struct DeferredStats {
+            /// Responses the device can hold at the same time.
+            pub capacity: u16,
+            /// Responses waiting to be sent.
+            pub queued: u16,
+            /// Most responses waiting at the same time since the device started.
+            pub peak: u16,
+            /// Responses sent.
+            pub completed: u32,
+            /// Calls refused with `FatalError::Busy` as the device was full.
+            pub rejected: u32,
+            /// Responses dropped as the backend cancelled the call.
+            pub cancelled: u32,
+        }
+
+        /// Responses a device holds at the same time, also the depth of the channel of commands
+        /// to the task holding them.
+        pub const DEFERRED_CAPACITY: usize = 8;
+
+        /// Retry hint for calls refused as the commands to the task holding the responses pile
+        /// up, it catches up soon.
+        pub const COMMANDS_FULL_RETRY_MS: u32 = 10;
+
+        /// A serialized response, sent once it is due.
+        pub struct DeferredResponse<C: Clock, F> {
+            pub seq_no: u32,
+            pub due: C::Instant,
+            pub frame: F,
+        }
+
+        impl<C: Clock, F> PartialEq for DeferredResponse<C, F> {
+            fn eq(&self, other: &Self) -> bool {
+                self.due.eq(&other.due)
+            }
+        }
+
+        impl<C: Clock, F> Eq for DeferredResponse<C, F> {}
+
+        impl<C: Clock, F> PartialOrd for DeferredResponse<C, F> {
+            fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+
+        impl<C: Clock, F> Ord for DeferredResponse<C, F> {
+            fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+                self.due.cmp(&other.due)
+            }
+        }
+
+        /// Requests to the task holding the responses, forwarded from the dispatcher.
+        pub enum DeferredCommand<C: Clock, F> {
+            /// Send a response once it is due.
+            Schedule(DeferredResponse<C, F>),
+            /// The backend gave up on the call, drop its response.
+            Cancel(u32),
+        }
+
+        /// At most `N` responses, with the next one due at the top.
+        pub struct Deferred<C: Clock, F, const N: usize> {
+            queue: BinaryHeap<DeferredResponse<C, F>, Min, N>,
+        }
+
+        impl<C: Clock, F, const N: usize> Deferred<C, F, N> {
+            pub const fn new() -> Self {
+                Self {
+                    queue: BinaryHeap::new(),
+                }
+            }
+
+            pub fn len(&self) -> usize {
+                self.queue.len()
+            }
+
+            pub fn is_empty(&self) -> bool {
+                self.queue.is_empty()
+            }
+
+            /// When the next response is due.
+            pub fn next_due(&self) -> Option<C::Instant> {
+                self.queue.peek().map(|next| next.due)
+            }
+
+            /// Queue a response. If there is no room, the milliseconds until the next response is
+            /// due are returned, the hint for `FatalError::Busy` when to retry.
+            pub fn schedule(
+                &mut self,
+                response: DeferredResponse<C, F>,
+                now: C::Instant,
+            ) -> Result<(), u32> {
+                self.queue.push(response).map_err(|_full| {
+                    let next = self.next_due().unwrap_or(now);
+                    let retry_after_ms = C::micros_between(now, next) / 1000;
+                    retry_after_ms.clamp(1, u32::MAX as u64) as u32
+                })
+            }
+
+            /// Take the next response if it is due at `now`.
+            pub fn pop_due(&mut self, now: C::Instant) -> Option<DeferredResponse<C, F>> {
+                if self.next_due()? <= now {
+                    self.queue.pop()
+                } else {
+                    None
+                }
+            }
+
+            /// Drop the response to the call with `seq_no`, returns `false` if there is none.
+            pub fn cancel(&mut self, seq_no: u32) -> bool {
+                if !self.queue.iter().any(|response| response.seq_no == seq_no) {
+                    return false;
+                }
+
+                let mut kept = BinaryHeap::new();
+                while let Some(response) = self.queue.pop() {
+                    if response.seq_no != seq_no {
+                        kept.push(response).ok();
+                    }
+                }
+                self.queue = kept;
+
+                true
+            }
+        }
+
+        impl<C: Clock, F, const N: usize> Default for Deferred<C, F, N> {
+            fn default() -> Self {
+                Self::new()
+            }
+        }
+
+        /// Counts behind `DeferredStats`, updated by the task holding the responses.
+        #[derive(Default)]
+        pub struct DeferredCounters {
+            queued: AtomicU16,
+            peak: AtomicU16,
+            completed: AtomicU32,
+            rejected: AtomicU32,
+            cancelled: AtomicU32,
+        }
+
+        impl DeferredCounters {
+            pub const fn new() -> Self {
+                Self {
+                    queued: AtomicU16::new(0),
+                    peak: AtomicU16::new(0),
+                    completed: AtomicU32::new(0),
+                    rejected: AtomicU32::new(0),
+                    cancelled: AtomicU32::new(0),
+                }
+            }
+
+            pub fn stats(&self) -> DeferredStats {
+                DeferredStats {
+                    capacity: DEFERRED_CAPACITY as u16,
+                    queued: self.queued.load(Ordering::Relaxed),
+                    peak: self.peak.load(Ordering::Relaxed),
+                    completed: self.completed.load(Ordering::Relaxed),
+                    rejected: self.rejected.load(Ordering::Relaxed),
+                    cancelled: self.cancelled.load(Ordering::Relaxed),
+                }
+            }
+
+            /// A response was sent.
+            pub fn completed(&self) {
+                self.completed.fetch_add(1, Ordering::Relaxed);
+            }
+
+            /// A call was refused with `FatalError::Busy`.
+            pub fn rejected(&self) {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+            }
+
+            /// A response was dropped as its call was cancelled.
+            pub fn cancelled(&self) {
+                self.cancelled.fetch_add(1, Ordering::Relaxed);
+            }
+
+            /// The number of responses waiting changed.
+            pub fn queued(&self, len: usize) {
+                self.queued.store(len as u16, Ordering::Relaxed);
+                self.peak.fetch_max(len as u16, Ordering::Relaxed);
+            }
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+            use crate::clock::tests::Micros;
+
+            type Response = DeferredResponse<Micros, ()>;
+
+            fn response(seq_no: u32, due: u64) -> Response {
+                DeferredResponse {
+                    seq_no,
+                    due,
+                    frame: (),
+                }
+            }
+
+            #[test]
+            fn responses_are_sent_in_the_order_they_are_due() {
+                let mut deferred = Deferred::<Micros, (), 3>::new();
+                deferred.schedule(response(1, 3_000), 0).unwrap();
+                deferred.schedule(response(2, 1_000), 0).unwrap();
+                deferred.schedule(response(3, 2_000), 0).unwrap();
+
+                assert_eq!(deferred.next_due(), Some(1_000));
+                assert!(deferred.pop_due(999).is_none());
+
+                let sent: heapless::Vec<u32, 3> =
+                    core::iter::from_fn(|| deferred.pop_due(5_000).map(|r| r.seq_no)).collect();
+                assert_eq!(sent, [2, 3, 1]);
+                assert!(deferred.is_empty());
+            }
+
+            #[test]
+            fn a_full_queue_refuses_with_the_time_until_the_next_response() {
+                let mut deferred = Deferred::<Micros, (), 2>::new();
+                deferred.schedule(response(1, 30_000), 10_000).unwrap();
+                deferred.schedule(response(2, 50_000), 10_000).unwrap();
+
+                assert_eq!(deferred.schedule(response(3, 20_000), 10_000), Err(20));
+                // Never a hint of zero, even when the next response is overdue.
+                assert_eq!(deferred.schedule(response(3, 20_000), 40_000), Err(1));
+            }
+
+            #[test]
+            fn cancelled_responses_are_dropped() {
+                let mut deferred = Deferred::<Micros, (), 3>::new();
+                deferred.schedule(response(1, 1_000), 0).unwrap();
+                deferred.schedule(response(2, 2_000), 0).unwrap();
+
+                assert!(deferred.cancel(1));
+                assert!(!deferred.cancel(1));
+                assert_eq!(deferred.len(), 1);
+                assert_eq!(deferred.pop_due(5_000).map(|r| r.seq_no), Some(2));
+            }
+        }
+    }
+
     /// A command that is expected to answer instantly, Ping/Pong to measure round trip time.
     pub mod pingpong {
         use postcard_rpc::endpoint;
@@ -93,6 +518,921 @@ pub mod endpoints {
         #[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize, Schema)]
         pub struct Pong {}
     }
+
+    /// Firmware updates over the RPC link.
+    ///
+    /// An update is started with `StartUpdate`, after which the image is sent in chunks with
+    /// `WriteChunk` and activated with `FinishUpdate`. Progress responses carry the offset the
+    /// device expects next, so a lost chunk or a dropped connection resumes where the device is.
+    pub mod firmware_update {
+        use postcard_rpc::endpoint;
+
+        use super::super::*;
+
+        // These are the definitions of the endpoints.
+        endpoint!(
+            FirmwareVersionEndpoint,
+            GetFirmwareVersion,
+            FirmwareVersion,
+            "endpoint/firmware/version"
+        );
+        endpoint!(
+            StartUpdateEndpoint,
+            StartUpdate,
+            UpdateResponse,
+            "endpoint/firmware/start"
+        );
+        endpoint!(
+            WriteChunkEndpoint,
+            WriteChunk,
+            UpdateResponse,
+            "endpoint/firmware/chunk"
+        );
+        endpoint!(
+            FinishUpdateEndpoint,
+            FinishUpdate,
+            UpdateResponse,
+            "endpoint/firmware/finish"
+        );
+
+        /// Maximum number of image bytes in one `WriteChunk`.
+        pub const CHUNK_SIZE: usize = 64;
+
+        /// Request for the version of the running firmware.
+        #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+        #[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize, Schema)]
+        pub struct GetFirmwareVersion {}
+
+        /// Version of a firmware image.
+        #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Schema)]
+        pub struct FirmwareVersion {
+            pub major: u16,
+            pub minor: u16,
+            pub patch: u16,
+        }
+
+        /// Start (or resume) writing an image into the update slot.
+        ///
+        /// If the device already has a partial image with the same size and CRC it keeps it and
+        /// answers with the offset to continue from.
+        #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+        #[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize, Schema)]
+        pub struct StartUpdate {
+            /// Version of the new image.
+            pub version: FirmwareVersion,
+            /// Size of the full image in bytes.
+            pub size: u32,
+            /// CRC-32 of the full image.
+            pub crc: u32,
+        }
+
+        /// A piece of the image.
+        #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Schema)]
+        pub struct WriteChunk {
+            /// Offset of `data` in the image.
+            pub offset: u32,
+            /// CRC-32 of `data`.
+            pub crc: u32,
+            pub data: heapless::Vec<u8, CHUNK_SIZE>,
+        }
+
+        /// Verify the written image and keep it in the update slot.
+        ///
+        /// A device that can install the image restarts into it after answering `Done`, the others
+        /// answer `Staged` and keep running their firmware.
+        #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+        #[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize, Schema)]
+        pub struct FinishUpdate {}
+
+        /// Response to all update requests.
+        #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Schema)]
+        pub enum UpdateResponse {
+            /// The image is written up to this offset, continue from there.
+            Progress(u32),
+            /// The image is verified and activated, the device restarts into it.
+            Done,
+            /// The request was rejected.
+            Error(UpdateError),
+            /// The image is verified and kept in the update slot, but the device has no way to
+            /// install it and keeps running its firmware. Starting the same update again answers
+            /// `Staged` right away.
+            Staged,
+        }
+
+        /// Reasons for rejecting an update request.
+        #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Schema)]
+        pub enum UpdateError {
+            /// There is no update in progress, send `StartUpdate` first.
+            NotStarted,
+            /// The image does not fit in the update slot.
+            TooLarge,
+            /// The chunk did not match its CRC.
+            ChunkCrc,
+            /// The written image did not match its CRC, the update has to start over.
+            ImageCrc,
+            /// Erasing or writing the flash failed.
+            Flash,
+            /// A chunk was shorter than `CHUNK_SIZE` without ending the image.
+            ShortChunk,
+        }
+
+        /// Progress of the image being written, shared by the firmware and the simulator. Where
+        /// the image is stored is up to them.
+        #[derive(Debug, Copy, Clone, PartialEq)]
+        pub struct UpdateProgress {
+            /// The image being written.
+            pub image: StartUpdate,
+            /// Offset of the next chunk to write.
+            pub next_offset: u32,
+        }
+
+        impl UpdateProgress {
+            /// Start writing `image`, once the storage for it is erased.
+            pub fn new(image: StartUpdate) -> Self {
+                Self {
+                    image,
+                    next_offset: 0,
+                }
+            }
+
+            /// If a `StartUpdate` for `image` can continue this update.
+            pub fn resumes(&self, image: &StartUpdate) -> bool {
+                self.image.size == image.size && self.image.crc == image.crc
+            }
+
+            /// Check that `chunk` is the next one to write. Otherwise the response to send instead
+            /// of writing it is returned.
+            pub fn check(&self, chunk: &WriteChunk) -> Result<(), UpdateResponse> {
+                if crate::crc::crc32(&chunk.data) != chunk.crc {
+                    return Err(UpdateResponse::Error(UpdateError::ChunkCrc));
+                }
+
+                let end = chunk.offset as u64 + chunk.data.len() as u64;
+
+                // A duplicate or out of order chunk, tell the backend where we are.
+                if chunk.offset != self.next_offset || end > self.image.size as u64 {
+                    return Err(UpdateResponse::Progress(self.next_offset));
+                }
+
+                // Only the last chunk may be short, the others have to keep the writes aligned.
+                if chunk.data.len() < CHUNK_SIZE && end != self.image.size as u64 {
+                    return Err(UpdateResponse::Error(UpdateError::ShortChunk));
+                }
+
+                Ok(())
+            }
+
+            /// Record that a checked chunk of `len` bytes was written.
+            pub fn written(&mut self, len: usize) -> UpdateResponse {
+                self.next_offset += len as u32;
+                UpdateResponse::Progress(self.next_offset)
+            }
+
+            /// If the full image is written.
+            pub fn is_complete(&self) -> bool {
+                self.next_offset == self.image.size
+            }
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+
+            fn chunk(offset: u32, len: usize) -> WriteChunk {
+                let data = heapless::Vec::from_slice(&[0xa5; CHUNK_SIZE][..len]).unwrap();
+                WriteChunk {
+                    offset,
+                    crc: crate::crc::crc32(&data),
+                    data,
+                }
+            }
+
+            #[test]
+            fn chunks_are_written_in_order() {
+                let mut progress = UpdateProgress::new(StartUpdate {
+                    version: FirmwareVersion {
+                        major: 1,
+                        minor: 0,
+                        patch: 0,
+                    },
+                    size: CHUNK_SIZE as u32 + 10,
+                    crc: 0,
+                });
+
+                assert_eq!(
+                    progress.check(&chunk(0, 10)),
+                    Err(UpdateResponse::Error(UpdateError::ShortChunk))
+                );
+                assert_eq!(
+                    progress.check(&chunk(CHUNK_SIZE as u32, 10)),
+                    Err(UpdateResponse::Progress(0))
+                );
+
+                let mut corrupted = chunk(0, CHUNK_SIZE);
+                corrupted.crc ^= 1;
+                assert_eq!(
+                    progress.check(&corrupted),
+                    Err(UpdateResponse::Error(UpdateError::ChunkCrc))
+                );
+
+                assert_eq!(progress.check(&chunk(0, CHUNK_SIZE)), Ok(()));
+                assert_eq!(
+                    progress.written(CHUNK_SIZE),
+                    UpdateResponse::Progress(CHUNK_SIZE as u32)
+                );
+                assert!(!progress.is_complete());
+
+                // The last chunk may be short, but not run past the image.
+                assert_eq!(
+                    progress.check(&chunk(CHUNK_SIZE as u32, 11)),
+                    Err(UpdateResponse::Progress(CHUNK_SIZE as u32))
+                );
+                assert_eq!(progress.check(&chunk(CHUNK_SIZE as u32, 10)), Ok(()));
+                progress.written(10);
+                assert!(progress.is_complete());
+            }
+        }
+    }
+
+    /// Information about a device and the firmware it runs.
+    pub mod device_info {
+        use postcard_rpc::endpoint;
+
+        use super::super::*;
+        use super::firmware_update::FirmwareVersion;
+
+        // This is the definition of an endpoint.
+        endpoint!(
+            DeviceInfoEndpoint,
+            GetDeviceInfo,
+            DeviceInfo,
+            "endpoint/device/info"
+        );
+
+        /// Request for the device information.
+        #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+        #[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize, Schema)]
+        pub struct GetDeviceInfo {}
+
+        /// Device information response.
+        #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+        #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Schema)]
+        pub struct DeviceInfo {
+            /// Unique ID of the MCU.
+            pub uid: [u8; 12],
+            /// MAC address of the Ethernet interface.
+            pub mac: [u8; 6],
+            /// Hostname the device uses for DHCP.
+            pub hostname: heapless::String<32>,
+            /// Version of the running firmware.
+            pub firmware_version: FirmwareVersion,
+            /// Git commit the firmware was built from, with `-dirty` if there were local changes.
+            pub git_hash: heapless::String<16>,
+            /// `PROTOCOL_VERSION` of the firmware.
+            pub protocol_version: u16,
+            /// Time since the device started, in milliseconds.
+            pub uptime_ms: u64,
+            /// Why the device last started.
+            pub reset_reason: ResetReason,
+        }
+
+        /// Cause of the last reset of the MCU.
+        #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Schema)]
+        pub enum ResetReason {
+            PowerOn,
+            BrownOut,
+            /// The reset pin, e.g. from a debugger.
+            Pin,
+            /// The firmware restarted itself, e.g. after an update.
+            Software,
+            IndependentWatchdog,
+            WindowWatchdog,
+            LowPower,
+            Unknown,
+        }
+    }
+
+    /// Provisioning of the persistent device configuration.
+    ///
+    /// A new configuration is sent with `SetConfig`, which only checks and stages it. It is
+    /// written to flash with `CommitConfig`, after which the device restarts to use it.
+    pub mod config {
+        use postcard_rpc::endpoint;
+
+        use super::super::*;
+
+        // These are the definitions of the endpoints.
+        endpoint!(
+            GetConfigEndpoint,
+            GetConfig,
+            DeviceConfig,
+            "endpoint/config/get"
+        );
+        endpoint!(
+            SetConfigEndpoint,
+            SetConfig,
+            ConfigResponse,
+            "endpoint/config/set"
+        );
+        endpoint!(
+            CommitConfigEndpoint,
+            CommitConfig,
+            ConfigResponse,
+            "endpoint/config/commit"
+        );
+
+        /// Maximum length of the PSK identity.
+        pub const MAX_IDENTITY_LEN: usize = 32;
+        /// Maximum length of the PSK.
+        pub const MAX_KEY_LEN: usize = 48;
+
+        /// Request for the configuration the device is running with.
+        #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+        #[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize, Schema)]
+        pub struct GetConfig {}
+
+        /// Configuration kept in flash by the device.
+        #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+        #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Schema)]
+        pub struct DeviceConfig {
+            /// Identity of the PSK used for the DTLS handshake.
+            pub psk_identity: heapless::Vec<u8, MAX_IDENTITY_LEN>,
+            /// The PSK, always empty in responses to `GetConfig`.
+            pub psk_key: heapless::Vec<u8, MAX_KEY_LEN>,
+            /// Fixed address of the backend, the backend is discovered if `None`.
+            pub backend: Option<Backend>,
+            /// Backend to use when discovery finds none, the device keeps looking if `None`.
+            pub fallback_backend: Option<Backend>,
+            /// Time between heartbeats, in seconds.
+            pub heartbeat_interval_secs: u32,
+        }
+
+        impl DeviceConfig {
+            /// Check that the device can run with the configuration.
+            pub fn validate(&self) -> Result<(), ConfigError> {
+                if self.psk_identity.is_empty() || self.psk_key.is_empty() {
+                    return Err(ConfigError::MissingPsk);
+                }
+
+                if self.heartbeat_interval_secs == 0 {
+                    return Err(ConfigError::InvalidHeartbeatInterval);
+                }
+
+                Ok(())
+            }
+        }
+
+        /// Address of the backend.
+        #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Schema)]
+        pub struct Backend {
+            pub ip: [u8; 4],
+            pub port: u16,
+        }
+
+        /// Stage a new configuration, it is not used until it has been committed.
+        #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+        #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Schema)]
+        pub struct SetConfig {
+            pub config: DeviceConfig,
+        }
+
+        /// Write the staged configuration to flash, the device restarts after answering.
+        #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+        #[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize, Schema)]
+        pub struct CommitConfig {}
+
+        /// Response to `SetConfig` and `CommitConfig`.
+        #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Schema)]
+        pub enum ConfigResponse {
+            /// The configuration is staged, commit it to use it.
+            Staged,
+            /// The configuration is written and verified.
+            Committed,
+            /// The request was rejected.
+            Error(ConfigError),
+        }
+
+        /// Reasons for rejecting a configuration request.
+        #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Schema)]
+        pub enum ConfigError {
+            /// The PSK identity or the key is empty.
+            MissingPsk,
+            /// The heartbeat interval is zero.
+            InvalidHeartbeatInterval,
+            /// There is no staged configuration, send `SetConfig` first.
+            NothingStaged,
+            /// Writing the flash failed, or what was read back did not match its CRC.
+            Flash,
+        }
+    }
+
+    /// Control of the topics a device streams to the backend.
+    pub mod stream {
+        use postcard_rpc::{endpoint, Endpoint, Key};
+
+        use super::super::*;
+        use crate::clock::Clock;
+
+        endpoint!(
+            StartStreamEndpoint,
+            StartStream,
+            StreamResponse,
+            "endpoint/stream/start"
+        );
+        endpoint!(
+            StopStreamEndpoint,
+            StopStream,
+            StreamResponse,
+            "endpoint/stream/stop"
+        );
+        endpoint!(
+            SetStreamRateEndpoint,
+            SetStreamRate,
+            StreamResponse,
+            "endpoint/stream/rate"
+        );
+
+        /// Rate of a stream until it is set, in Hz.
+        pub const DEFAULT_STREAM_RATE_HZ: u32 = 10;
+        /// Highest rate a stream can be set to, in Hz.
+        pub const MAX_STREAM_RATE_HZ: u32 = 1000;
+
+        /// The topics a device streams on request.
+        #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, Schema)]
+        pub enum StreamTopic {
+            /// `topics::some_data::TopicSomeData`.
+            SomeData,
+        }
+
+        /// Start publishing a topic, at the rate it was last set to.
+        #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+        #[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize, Schema)]
+        pub struct StartStream {
+            pub topic: StreamTopic,
+        }
+
+        /// Stop publishing a topic.
+        #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+        #[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize, Schema)]
+        pub struct StopStream {
+            pub topic: StreamTopic,
+        }
+
+        /// Set the rate of a topic, whether it is running or not. Rates are kept in RAM, a
+        /// device restarts with all streams stopped at the default rate.
+        #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+        #[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize, Schema)]
+        pub struct SetStreamRate {
+            pub topic: StreamTopic,
+            /// Messages per second, from 1 to `MAX_STREAM_RATE_HZ`.
+            pub hz: u32,
+        }
+
+        /// Response to all stream requests.
+        #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Schema)]
+        pub enum StreamResponse {
+            /// The state of the topic after the request.
+            Status(StreamStatus),
+            /// The request was rejected.
+            Error(StreamError),
+        }
+
+        /// State of a streamed topic.
+        #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Schema)]
+        pub struct StreamStatus {
+            pub running: bool,
+            pub hz: u32,
+        }
+
+        /// Reasons for rejecting a stream request.
+        #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Schema)]
+        pub enum StreamError {
+            /// The rate is zero or above `MAX_STREAM_RATE_HZ`.
+            InvalidRate,
+        }
+
+        /// Stream requests, forwarded from the dispatcher to the task publishing the streams.
+        #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+        #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+        pub enum StreamCommand {
+            Start(StreamTopic),
+            Stop(StreamTopic),
+            SetRate(StreamTopic, u32),
+        }
+
+        /// State of one streamed topic.
+        ///
+        /// A stream that falls behind, e.g. as the Ethernet queue is full, skips the messages it
+        /// missed instead of sending them in a burst.
+        pub struct Stream<C: Clock> {
+            running: bool,
+            hz: u32,
+            next: C::Instant,
+        }
+
+        impl<C: Clock> Stream<C> {
+            /// A stopped stream at the default rate.
+            pub fn new(now: C::Instant) -> Self {
+                Self {
+                    running: false,
+                    hz: DEFAULT_STREAM_RATE_HZ,
+                    next: now,
+                }
+            }
+
+            pub fn status(&self) -> StreamStatus {
+                StreamStatus {
+                    running: self.running,
+                    hz: self.hz,
+                }
+            }
+
+            /// When the next message is due, `None` while the stream is stopped.
+            pub fn next_due(&self) -> Option<C::Instant> {
+                self.running.then_some(self.next)
+            }
+
+            /// Apply a request to the stream, returns the key and the response to answer it with.
+            pub fn handle(
+                &mut self,
+                command: StreamCommand,
+                now: C::Instant,
+            ) -> (Key, StreamResponse) {
+                match command {
+                    StreamCommand::Start(_) => {
+                        if !self.running {
+                            self.running = true;
+                            self.next = C::after(now, self.period_micros());
+                        }
+                        (
+                            StartStreamEndpoint::RESP_KEY,
+                            StreamResponse::Status(self.status()),
+                        )
+                    }
+                    StreamCommand::Stop(_) => {
+                        self.running = false;
+                        (
+                            StopStreamEndpoint::RESP_KEY,
+                            StreamResponse::Status(self.status()),
+                        )
+                    }
+                    StreamCommand::SetRate(_, hz) => (
+                        SetStreamRateEndpoint::RESP_KEY,
+                        if hz == 0 || hz > MAX_STREAM_RATE_HZ {
+                            StreamResponse::Error(StreamError::InvalidRate)
+                        } else {
+                            self.hz = hz;
+                            self.next = C::after(now, self.period_micros());
+                            StreamResponse::Status(self.status())
+                        },
+                    ),
+                }
+            }
+
+            /// A message was published at `now`, schedule the next one.
+            pub fn published(&mut self, now: C::Instant) {
+                self.next = C::after(self.next, self.period_micros());
+                if self.next < now {
+                    self.next = C::after(now, self.period_micros());
+                }
+            }
+
+            fn period_micros(&self) -> u64 {
+                1_000_000 / self.hz as u64
+            }
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+            use crate::clock::tests::Micros;
+
+            #[test]
+            fn streams_run_at_their_rate_once_started() {
+                let mut stream = Stream::<Micros>::new(0);
+                assert_eq!(stream.next_due(), None);
+
+                let (_, response) =
+                    stream.handle(StreamCommand::SetRate(StreamTopic::SomeData, 0), 0);
+                assert_eq!(response, StreamResponse::Error(StreamError::InvalidRate));
+
+                let (key, response) =
+                    stream.handle(StreamCommand::SetRate(StreamTopic::SomeData, 100), 0);
+                assert_eq!(key, SetStreamRateEndpoint::RESP_KEY);
+                assert_eq!(
+                    response,
+                    StreamResponse::Status(StreamStatus {
+                        running: false,
+                        hz: 100
+                    })
+                );
+                assert_eq!(stream.next_due(), None);
+
+                stream.handle(StreamCommand::Start(StreamTopic::SomeData), 1_000);
+                assert_eq!(stream.next_due(), Some(11_000));
+                stream.published(11_000);
+                assert_eq!(stream.next_due(), Some(21_000));
+
+                stream.handle(StreamCommand::Stop(StreamTopic::SomeData), 25_000);
+                assert_eq!(stream.next_due(), None);
+            }
+
+            #[test]
+            fn a_stream_that_fell_behind_skips_what_it_missed() {
+                let mut stream = Stream::<Micros>::new(0);
+                stream.handle(StreamCommand::Start(StreamTopic::SomeData), 0);
+                assert_eq!(stream.next_due(), Some(100_000));
+
+                // Published half a second late, the four messages in between are not sent.
+                stream.published(600_000);
+                assert_eq!(stream.next_due(), Some(700_000));
+            }
+        }
+    }
+}
+
+/// Time on a device, for the parts of the firmware that the simulator shares.
+pub mod clock {
+    /// Arithmetic on the time of the timer a device runs on. The current time is passed in to the
+    /// code using it.
+    pub trait Clock {
+        /// A point in time.
+        type Instant: Copy + Ord;
+
+        /// `instant` plus `micros` microseconds.
+        fn after(instant: Self::Instant, micros: u64) -> Self::Instant;
+
+        /// Microseconds from `earlier` to `later`, zero if `later` is not later.
+        fn micros_between(earlier: Self::Instant, later: Self::Instant) -> u64;
+    }
+
+    #[cfg(test)]
+    pub(crate) mod tests {
+        use super::*;
+
+        /// Microseconds since an arbitrary start.
+        pub struct Micros;
+
+        impl Clock for Micros {
+            type Instant = u64;
+
+            fn after(instant: u64, micros: u64) -> u64 {
+                instant + micros
+            }
+
+            fn micros_between(earlier: u64, later: u64) -> u64 {
+                later.saturating_sub(earlier)
+            }
+        }
+    }
+}
+
+/// The connection of a device to the backend, the parts the simulator shares with the firmware.
+pub mod connection {
+    /// Largest message a device sends or receives, header included.
+    pub const MAX_MESSAGE_SIZE: usize = 512;
+
+    /// Heartbeats in a row the backend can leave unacknowledged before the device considers the
+    /// session dead.
+    pub const MISSED_ACKS: u32 = 3;
+
+    /// Bounds of the backoff between connection attempts, in milliseconds.
+    pub const MIN_BACKOFF_MS: u32 = 500;
+    pub const MAX_BACKOFF_MS: u32 = 30_000;
+
+    /// Exponential backoff with jitter between connection attempts, so devices don't reconnect in
+    /// lockstep after the backend restarts.
+    pub struct Backoff {
+        next_ms: u32,
+    }
+
+    impl Backoff {
+        pub const fn new() -> Self {
+            Self {
+                next_ms: MIN_BACKOFF_MS,
+            }
+        }
+
+        /// Connected, the next failure starts over at the shortest backoff.
+        pub fn reset(&mut self) {
+            self.next_ms = MIN_BACKOFF_MS;
+        }
+
+        /// Milliseconds to wait before the next attempt, from a `random` number.
+        pub fn next_ms(&mut self, random: u32) -> u32 {
+            let max = self.next_ms;
+            self.next_ms = (self.next_ms * 2).min(MAX_BACKOFF_MS);
+
+            // Somewhere between half and all of the backoff.
+            max / 2 + random % (max / 2 + 1)
+        }
+    }
+
+    impl Default for Backoff {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn backoff_doubles_up_to_the_maximum_with_jitter() {
+            let mut backoff = Backoff::new();
+            let mut max = MIN_BACKOFF_MS;
+
+            for random in [0, u32::MAX, 1, 7, 42, 12_345, 999_999, 3] {
+                let ms = backoff.next_ms(random);
+                assert!(
+                    (max / 2..=max).contains(&ms),
+                    "{ms} ms with a maximum of {max}"
+                );
+                max = (max * 2).min(MAX_BACKOFF_MS);
+            }
+            assert_eq!(max, MAX_BACKOFF_MS);
+            assert_eq!(backoff.next_ms(MAX_BACKOFF_MS / 2), MAX_BACKOFF_MS);
+
+            backoff.reset();
+            assert_eq!(backoff.next_ms(0), MIN_BACKOFF_MS / 2);
+        }
+    }
+}
+
+/// Checksums shared by the device and the backend.
+pub mod crc {
+    /// CRC-32 (IEEE 802.3) of `data`.
+    pub const fn crc32(data: &[u8]) -> u32 {
+        Crc32::new().update(data).finish()
+    }
+
+    /// Incremental CRC-32 (IEEE 802.3) calculation.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub struct Crc32(u32);
+
+    impl Crc32 {
+        pub const fn new() -> Self {
+            Self(0xffff_ffff)
+        }
+
+        /// Add more data to the checksum.
+        #[must_use]
+        pub const fn update(self, data: &[u8]) -> Self {
+            let mut crc = self.0;
+            let mut i = 0;
+
+            while i < data.len() {
+                crc ^= data[i] as u32;
+
+                let mut bit = 0;
+                while bit < 8 {
+                    crc = if crc & 1 != 0 {
+                        (crc >> 1) ^ 0xedb8_8320
+                    } else {
+                        crc >> 1
+                    };
+                    bit += 1;
+                }
+
+                i += 1;
+            }
+
+            Self(crc)
+        }
+
+        /// The checksum of all data so far.
+        pub const fn finish(self) -> u32 {
+            !self.0
+        }
+    }
+
+    impl Default for Crc32 {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+}
+
+/// Per-device pre-shared keys, derived from the unique ID of the MCU.
+///
+/// The PSK identity of a device is `rpc-` followed by its UID in hex, the same as its DHCP
+/// hostname. The key is derived from a master secret and the identity with Ascon-XOF, so the
+/// backend can find the key of any device from the master secret alone.
+pub mod psk {
+    use ascon_hash::{AsconXof, ExtendableOutput, Update, XofReader};
+
+    /// Length of the unique ID of the STM32F407.
+    pub const UID_LEN: usize = 12;
+    /// Prefix of every derived identity.
+    pub const IDENTITY_PREFIX: &[u8] = b"rpc-";
+    /// Length of a derived identity.
+    pub const IDENTITY_LEN: usize = IDENTITY_PREFIX.len() + 2 * UID_LEN;
+    /// Length of a derived key.
+    pub const KEY_LEN: usize = 32;
+
+    /// Separates key derivation from other uses of Ascon-XOF with the same input.
+    const KEY_CONTEXT: &[u8] = b"rpc-udp-test psk v1";
+
+    /// The PSK identity of the device with `uid`.
+    pub fn identity(uid: &[u8; UID_LEN]) -> [u8; IDENTITY_LEN] {
+        const HEX: &[u8; 16] = b"0123456789ABCDEF";
+
+        let mut identity = [0; IDENTITY_LEN];
+        identity[..IDENTITY_PREFIX.len()].copy_from_slice(IDENTITY_PREFIX);
+
+        for (i, byte) in uid.iter().enumerate() {
+            let at = IDENTITY_PREFIX.len() + 2 * i;
+            identity[at] = HEX[(byte >> 4) as usize];
+            identity[at + 1] = HEX[(byte & 0xf) as usize];
+        }
+
+        identity
+    }
+
+    /// If `identity` has the form of a derived identity.
+    pub fn is_derived(identity: &[u8]) -> bool {
+        identity.len() == IDENTITY_LEN
+            && identity.starts_with(IDENTITY_PREFIX)
+            && identity[IDENTITY_PREFIX.len()..]
+                .iter()
+                .all(|c| matches!(c, b'0'..=b'9' | b'A'..=b'F'))
+    }
+
+    /// The key of the device with `identity`.
+    pub fn derive_key(master_secret: &[u8], identity: &[u8]) -> [u8; KEY_LEN] {
+        let mut xof = AsconXof::default();
+        xof.update(KEY_CONTEXT);
+        // The length keeps the boundary between the secret and the identity unambiguous.
+        xof.update(&(master_secret.len() as u32).to_le_bytes());
+        xof.update(master_secret);
+        xof.update(identity);
+
+        let mut key = [0; KEY_LEN];
+        xof.finalize_xof().read(&mut key);
+        key
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const UID: [u8; UID_LEN] = [
+            0x00, 0x1f, 0x00, 0x2a, 0x31, 0x33, 0x51, 0x0a, 0x33, 0x35, 0x38, 0x37,
+        ];
+
+        #[test]
+        fn identity_is_the_uid_in_hex() {
+            assert_eq!(&identity(&UID), b"rpc-001F002A3133510A33353837");
+        }
+
+        #[test]
+        fn only_derived_identities_are_recognized() {
+            assert!(is_derived(&identity(&UID)));
+            assert!(is_derived(&identity(&[0xff; UID_LEN])));
+
+            assert!(!is_derived(b"hello world"));
+            assert!(!is_derived(b"rpc-001f002a3133510a33353837"));
+            assert!(!is_derived(b"rpc-001F002A3133510A3335383"));
+            assert!(!is_derived(b"dev-001F002A3133510A33353837"));
+        }
+
+        #[test]
+        fn key_depends_on_the_secret_and_the_identity() {
+            let key = derive_key(b"master", &identity(&UID));
+
+            assert_eq!(key, derive_key(b"master", &identity(&UID)));
+            assert_ne!(key, derive_key(b"master2", &identity(&UID)));
+            assert_ne!(key, derive_key(b"master", &identity(&[0; UID_LEN])));
+            // Moving bytes between the secret and the identity gives another key.
+            assert_ne!(derive_key(b"ab", b"c"), derive_key(b"a", b"bc"),);
+        }
+
+        #[test]
+        fn keys_do_not_change() {
+            // Devices in the field have keys derived with this, it must never change.
+            assert_eq!(
+                derive_key(b"master", &identity(&UID)),
+                [
+                    0x62, 0xa3, 0x93, 0x7a, 0xe0, 0x59, 0x67, 0x16, 0xee, 0x69, 0x84, 0xe5, 0xc3,
+                    0x1a, 0x72, 0x54, 0x6e, 0x22, 0xe6, 0xa3, 0xa2, 0xdb, 0x3e, 0xea, 0xeb, 0xae,
+                    0xc1, 0x30, 0x6c, 0xe4, 0xe6, 0x9f,
+                ]
+            );
+        }
+    }
 }
 
 /// When something is not possible to understand that comes over the wire the device can answer
@@ -116,5 +1456,357 @@ pub mod wire_error {
         NotEnoughSenders,
         /// Ser(/de) error, malformed packet.
         WireFailure,
+        /// The device has no room for the call right now, retry in about this many milliseconds.
+        Busy(u32),
+        /// The request, or the response to it, is larger than the device can handle.
+        MessageTooLarge,
+    }
+}
+
+/// Cancellation of calls the backend stopped waiting for, e.g. as they timed out.
+pub mod cancel {
+    use postcard_rpc::topic;
+
+    use super::*;
+
+    topic!(TopicCancel, Cancel, "topic/cancel");
+
+    /// The backend gave up on the call with `seq_no`. The device drops the work it has queued for
+    /// the call and sends no response, calls that are already done or can't be stopped are not
+    /// affected.
+    #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Schema)]
+    pub struct Cancel {
+        pub seq_no: u32,
+    }
+}
+
+/// Fragmentation of frames too large for a single DTLS record.
+///
+/// Frames up to `MAX_RECORD_SIZE` are sent as they are. Larger frames are sent as a series of
+/// [`Fragment`]s in order, each with the sequence number of the frame it is part of, and put back
+/// together by the receiver. Records are not retransmitted, a lost fragment loses the frame the
+/// same as a lost record does.
+pub mod fragment {
+    use postcard_rpc::{
+        headered::{extract_header_from_bytes, to_slice_keyed},
+        topic, Topic,
+    };
+
+    use super::*;
+
+    topic!(TopicFragment, Fragment, "topic/fragment");
+
+    /// Largest record sent, kept well below the MTU so a record fits the UDP buffers of the
+    /// firmware a few times over.
+    pub const MAX_RECORD_SIZE: usize = 256;
+
+    /// Bytes of a frame carried by one fragment, leaving room for the header and the other fields
+    /// of the fragment in the record.
+    pub const FRAGMENT_DATA_SIZE: usize = MAX_RECORD_SIZE - 32;
+
+    /// Largest frame that can be fragmented.
+    pub const MAX_FRAGMENTED_SIZE: usize = u16::MAX as usize;
+
+    /// Part of a frame larger than `MAX_RECORD_SIZE`.
+    #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Schema)]
+    pub struct Fragment {
+        /// Size of the whole frame.
+        pub total_len: u16,
+        /// Where in the frame `data` goes.
+        pub offset: u16,
+        pub data: heapless::Vec<u8, FRAGMENT_DATA_SIZE>,
+    }
+
+    /// Splits a frame in the records to send.
+    pub struct Fragmenter<'a> {
+        frame: &'a [u8],
+        seq_no: u32,
+        offset: usize,
+    }
+
+    impl<'a> Fragmenter<'a> {
+        pub fn new(frame: &'a [u8]) -> Self {
+            let seq_no = extract_header_from_bytes(frame)
+                .map(|(hdr, _body)| hdr.seq_no)
+                .unwrap_or(0);
+
+            Self {
+                frame,
+                seq_no,
+                offset: 0,
+            }
+        }
+
+        /// The next record to send, `None` once all of the frame has been. A frame larger than
+        /// `MAX_FRAGMENTED_SIZE` gives no records.
+        pub fn next_record<'b>(&mut self, buf: &'b mut [u8; MAX_RECORD_SIZE]) -> Option<&'b [u8]> {
+            let len = self.frame.len();
+            if self.offset >= len || len > MAX_FRAGMENTED_SIZE {
+                return None;
+            }
+
+            if len <= MAX_RECORD_SIZE {
+                self.offset = len;
+                buf[..len].copy_from_slice(self.frame);
+                return Some(&buf[..len]);
+            }
+
+            let end = (self.offset + FRAGMENT_DATA_SIZE).min(len);
+            let fragment = Fragment {
+                total_len: len as u16,
+                offset: self.offset as u16,
+                data: heapless::Vec::from_slice(&self.frame[self.offset..end]).ok()?,
+            };
+            self.offset = end;
+
+            to_slice_keyed(self.seq_no, TopicFragment::TOPIC_KEY, &fragment, buf)
+                .ok()
+                .map(|used| &*used)
+        }
+    }
+
+    /// Why a received record did not give a frame.
+    #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum ReassemblyError {
+        /// The frame with `seq_no` is larger than the storage of the reassembler, it is dropped.
+        TooLarge { seq_no: u32 },
+        /// A fragment of the frame with `seq_no` was lost or came out of order, the frame is
+        /// dropped.
+        Missing { seq_no: u32 },
+        /// The record could not be decoded.
+        Malformed,
+    }
+
+    /// Puts fragmented frames back together, in storage of the caller that sets the largest
+    /// frame received.
+    pub struct Reassembler<'a> {
+        storage: &'a mut [u8],
+        /// Sequence number and size of the frame being put together.
+        frame: Option<(u32, usize)>,
+        received: usize,
+    }
+
+    impl<'a> Reassembler<'a> {
+        pub fn new(storage: &'a mut [u8]) -> Self {
+            Self {
+                storage,
+                frame: None,
+                received: 0,
+            }
+        }
+
+        /// Take a received record, returns a frame once there is a whole one. That is the record
+        /// itself if it was not a fragment.
+        pub fn push<'b>(
+            &'b mut self,
+            record: &'b [u8],
+        ) -> Result<Option<&'b [u8]>, ReassemblyError> {
+            let (hdr, body) =
+                extract_header_from_bytes(record).map_err(|_| ReassemblyError::Malformed)?;
+            if hdr.key != TopicFragment::TOPIC_KEY {
+                return Ok(Some(record));
+            }
+
+            let (fragment, _rest) = postcard::take_from_bytes::<Fragment>(body)
+                .map_err(|_| ReassemblyError::Malformed)?;
+            let total_len = fragment.total_len as usize;
+            let offset = fragment.offset as usize;
+
+            let mut abandoned = None;
+            if offset == 0 {
+                // A frame that is too large doesn't need the storage, so whatever is being put
+                // together is kept. If it lost a fragment, the next fragment tells.
+                if total_len > self.storage.len() {
+                    return Err(ReassemblyError::TooLarge { seq_no: hdr.seq_no });
+                }
+
+                // A new frame, whatever was being put together is lost.
+                abandoned = self.frame.replace((hdr.seq_no, total_len));
+                self.received = 0;
+            }
+
+            let end = offset + fragment.data.len();
+            if self.frame != Some((hdr.seq_no, total_len)) || offset != self.received {
+                // Without a frame being put together this is the rest of one that was already
+                // reported, as too large or as missing a fragment.
+                return match self.frame.take() {
+                    Some((seq_no, _)) => Err(ReassemblyError::Missing { seq_no }),
+                    None => Ok(None),
+                };
+            }
+            if end > total_len {
+                self.frame = None;
+                return Err(ReassemblyError::Malformed);
+            }
+
+            self.storage[offset..end].copy_from_slice(&fragment.data);
+            self.received = end;
+
+            if let Some((seq_no, _)) = abandoned {
+                Err(ReassemblyError::Missing { seq_no })
+            } else if end == total_len {
+                self.frame = None;
+                Ok(Some(&self.storage[..total_len]))
+            } else {
+                Ok(None)
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use postcard_rpc::Key;
+
+        const KEY: Key = Key::for_path::<[u8]>("test");
+
+        /// A frame with a body of `len` counting bytes.
+        fn frame(seq_no: u32, len: usize, buf: &mut [u8]) -> &[u8] {
+            let hdr_len = to_slice_keyed(seq_no, KEY, &(), buf).unwrap().len();
+            for (i, b) in buf[hdr_len..len].iter_mut().enumerate() {
+                *b = i as u8;
+            }
+            &buf[..len]
+        }
+
+        #[test]
+        fn small_frames_are_sent_as_they_are() {
+            let mut buf = [0; 64];
+            let frame = frame(1, 64, &mut buf);
+
+            let mut record = [0; MAX_RECORD_SIZE];
+            let mut fragmenter = Fragmenter::new(frame);
+            assert_eq!(fragmenter.next_record(&mut record), Some(frame));
+            assert_eq!(fragmenter.next_record(&mut record), None);
+
+            let mut storage = [0; 128];
+            let mut reassembler = Reassembler::new(&mut storage);
+            assert_eq!(reassembler.push(frame), Ok(Some(frame)));
+        }
+
+        #[test]
+        fn large_frames_are_put_back_together() {
+            let mut buf = [0; 1000];
+            let frame = frame(7, 1000, &mut buf);
+
+            let mut storage = [0; 1024];
+            let mut reassembler = Reassembler::new(&mut storage);
+            let mut fragmenter = Fragmenter::new(frame);
+            let mut record = [0; MAX_RECORD_SIZE];
+            let mut records = 0;
+            let mut reassembled = None;
+
+            while let Some(record) = fragmenter.next_record(&mut record) {
+                assert!(reassembled.is_none());
+                records += 1;
+                reassembled = reassembler.push(record).unwrap().map(|f| f.to_vec());
+            }
+
+            assert_eq!(records, 1000_usize.div_ceil(FRAGMENT_DATA_SIZE));
+            assert_eq!(reassembled.as_deref(), Some(frame));
+        }
+
+        #[test]
+        fn lost_fragments_and_too_large_frames_are_reported() {
+            let mut buf = [0; 1000];
+            let frame = frame(7, 1000, &mut buf);
+            let mut record = [0; MAX_RECORD_SIZE];
+
+            let mut storage = [0; 512];
+            let mut reassembler = Reassembler::new(&mut storage);
+            let mut fragmenter = Fragmenter::new(frame);
+            let first = fragmenter.next_record(&mut record).unwrap();
+            assert_eq!(
+                reassembler.push(first),
+                Err(ReassemblyError::TooLarge { seq_no: 7 })
+            );
+            // The rest of it is dropped quietly.
+            let second = fragmenter.next_record(&mut record).unwrap();
+            assert_eq!(reassembler.push(second), Ok(None));
+
+            let mut storage = [0; 1024];
+            let mut reassembler = Reassembler::new(&mut storage);
+            let mut fragmenter = Fragmenter::new(frame);
+            let first = fragmenter.next_record(&mut record).unwrap().to_vec();
+            fragmenter.next_record(&mut record).unwrap();
+            let third = fragmenter.next_record(&mut record).unwrap();
+            assert_eq!(reassembler.push(&first), Ok(None));
+            assert_eq!(
+                reassembler.push(third),
+                Err(ReassemblyError::Missing { seq_no: 7 })
+            );
+        }
+
+        #[test]
+        fn frames_cut_short_by_a_new_one_are_reported() {
+            let mut first_buf = [0; 1000];
+            let first = frame(7, 1000, &mut first_buf);
+            let mut second_buf = [0; 600];
+            let second = frame(8, 600, &mut second_buf);
+            let mut record = [0; MAX_RECORD_SIZE];
+
+            let mut storage = [0; 1024];
+            let mut reassembler = Reassembler::new(&mut storage);
+
+            let mut fragmenter = Fragmenter::new(first);
+            let start = fragmenter.next_record(&mut record).unwrap();
+            assert_eq!(reassembler.push(start), Ok(None));
+
+            // The second frame starts before the first is done, the first is reported and the
+            // second is still put together.
+            let mut fragmenter = Fragmenter::new(second);
+            let start = fragmenter.next_record(&mut record).unwrap();
+            assert_eq!(
+                reassembler.push(start),
+                Err(ReassemblyError::Missing { seq_no: 7 })
+            );
+
+            let mut reassembled = None;
+            while let Some(record) = fragmenter.next_record(&mut record) {
+                reassembled = reassembler.push(record).unwrap().map(|f| f.to_vec());
+            }
+            assert_eq!(reassembled.as_deref(), Some(second));
+        }
+    }
+}
+
+/// Discovery of the backend on the local network.
+///
+/// The backend can broadcast an `Announcement` to `DISCOVERY_PORT`, devices that can't resolve the
+/// backend by name connect to the address the announcement came from.
+pub mod discovery {
+    use super::*;
+
+    /// UDP port devices listen on for announcements.
+    pub const DISCOVERY_PORT: u16 = 8322;
+
+    /// Tells announcements apart from other traffic on the port.
+    pub const MAGIC: [u8; 4] = *b"RPCI";
+
+    /// Sent by the backend, the source IP of the datagram is the address of the backend.
+    #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Schema)]
+    pub struct Announcement {
+        /// Always `MAGIC`.
+        pub magic: [u8; 4],
+        /// The port the backend accepts DTLS connections on.
+        pub port: u16,
+    }
+
+    impl Announcement {
+        /// An announcement of a backend listening on `port`.
+        pub fn new(port: u16) -> Self {
+            Self { magic: MAGIC, port }
+        }
+
+        /// Parse an announcement, `None` if the datagram is something else.
+        pub fn parse(datagram: &[u8]) -> Option<Self> {
+            postcard::from_bytes::<Self>(datagram)
+                .ok()
+                .filter(|announcement| announcement.magic == MAGIC)
+        }
     }
 }